@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_tcp_vpn::codec;
+
+// decoded frames must be encoded back to the same bytes
+fuzz_target!(|data: &[u8]| {
+    if let Ok((frame, len)) = codec::decode_frame(data) {
+        let mut buf = vec![];
+        codec::encode_frame(&frame, &mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..len]);
+    }
+});
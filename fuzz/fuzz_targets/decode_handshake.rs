@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_tcp_vpn::codec;
+
+// decoded messages must be encoded back to the same bytes
+fuzz_target!(|data: &[u8]| {
+    if let Ok((msg, len)) = codec::decode_handshake(data) {
+        let mut buf = vec![];
+        codec::encode_handshake(&msg, &mut buf).unwrap();
+        // reject text is decoded lossily
+        if !matches!(msg, codec::HandshakeMsg::Reject { .. }) {
+            assert_eq!(&buf[..], &data[..len]);
+        }
+    }
+});
@@ -0,0 +1,376 @@
+// Contains the traffic accounting of the server (--accounting FILE):
+// bytes received from and sent to each client identity today, this
+// month (UTC days and months) and since accounting started. Usage is
+// kept in a JSON state file written periodically and when the server
+// exits, so it survives restarts. The file is replaced by a temporary
+// file of the same directory, so that a crash never leaves it
+// truncated:
+//      {"alice": {"day": "2026-10-19", "today": {"rx": 1200, "tx": 5400},
+//                 "month": "2026-10", "this_month": {...}, "total": {...}}}
+// Clients exceeding the daily or monthly quota of the client database
+// are disconnected, and cannot connect again until the period is over.
+
+use crate::acl::ClientConfig;
+use crate::session::Traffic;
+
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{self, UnlinkatFlags};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// min time between two writes of the state file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // years start in March so that leap days are last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// (year, month, day) of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// Current UTC day, as days since 1970-01-01
+pub fn today() -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / 86400) as i64
+}
+
+// "YYYY-MM-DD"
+pub fn format_day(day: i64) -> String {
+    let (year, month, day) = civil_from_days(day);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn parse_day(s: &str) -> Result<i64, String> {
+    let err = || format!("{}: expected YYYY-MM-DD", s);
+    let mut parts = s.splitn(3, '-');
+    let mut next = || parts.next().and_then(|part| part.parse::<u32>().ok());
+    let (Some(year), Some(month), Some(day)) = (next(), next(), next()) else {
+        return Err(err());
+    };
+    let days = days_from_civil(year as i64, month, day);
+    // rejects days past the end of the month
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year as i64, month, day) {
+        return Err(err());
+    }
+    Ok(days)
+}
+
+// "YYYY-MM" of the month containing day
+pub fn format_month(day: i64) -> String {
+    let (year, month, _) = civil_from_days(day);
+    format!("{:04}-{:02}", year, month)
+}
+
+// Bytes received from the client (rx) and sent to it (tx)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bytes {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+impl Bytes {
+    pub fn sum(&self) -> u64 {
+        self.rx + self.tx
+    }
+
+    fn add(&mut self, rx: u64, tx: u64) {
+        self.rx += rx;
+        self.tx += tx;
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "rx": self.rx, "tx": self.tx })
+    }
+
+    fn from_json(value: &Value) -> Option<Bytes> {
+        Some(Bytes {
+            rx: value.get("rx")?.as_u64()?,
+            tx: value.get("tx")?.as_u64()?,
+        })
+    }
+}
+
+// Traffic of a client identity
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    // day counted by today, as days since 1970-01-01
+    pub day: i64,
+    pub today: Bytes,
+    // any day of the month counted by this_month
+    pub month_day: i64,
+    pub this_month: Bytes,
+    pub total: Bytes,
+}
+
+impl Usage {
+    // counters of the periods over by day are reset
+    pub fn roll(&mut self, day: i64) {
+        if day != self.day {
+            self.day = day;
+            self.today = Bytes::default();
+        }
+        if format_month(day) != format_month(self.month_day) {
+            self.month_day = day;
+            self.this_month = Bytes::default();
+        }
+    }
+
+    pub fn add(&mut self, day: i64, rx: u64, tx: u64) {
+        self.roll(day);
+        self.today.add(rx, tx);
+        self.this_month.add(rx, tx);
+        self.total.add(rx, tx);
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "day": format_day(self.day),
+            "today": self.today.to_json(),
+            "month": format_month(self.month_day),
+            "this_month": self.this_month.to_json(),
+            "total": self.total.to_json(),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Usage, String> {
+        let date = |name: &str| match value.get(name).and_then(Value::as_str) {
+            Some(date) => Ok(date),
+            None => Err(format!("missing {}", name)),
+        };
+        let bytes = |name: &str| match value.get(name).and_then(Bytes::from_json) {
+            Some(bytes) => Ok(bytes),
+            None => Err(format!("missing or invalid {}", name)),
+        };
+        Ok(Usage {
+            day: parse_day(date("day")?)?,
+            today: bytes("today")?,
+            // first day of the month
+            month_day: parse_day(&format!("{}-01", date("month")?))?,
+            this_month: bytes("this_month")?,
+            total: bytes("total")?,
+        })
+    }
+}
+
+// Bytes a client can exchange, received and sent together
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+impl Quota {
+    pub fn of(config: &ClientConfig) -> Quota {
+        Quota {
+            daily: config.quota_day,
+            monthly: config.quota_month,
+        }
+    }
+
+    // "daily" or "monthly" if usage (of the current periods) reached
+    // the quota
+    pub fn exceeded(&self, usage: &Usage) -> Option<&'static str> {
+        if self.daily.is_some_and(|max| usage.today.sum() >= max) {
+            Some("daily")
+        } else if self
+            .monthly
+            .is_some_and(|max| usage.this_month.sum() >= max)
+        {
+            Some("monthly")
+        } else {
+            None
+        }
+    }
+}
+
+struct Ledger {
+    // by client identity
+    usage: BTreeMap<String, Usage>,
+    // changed since last saved
+    dirty: bool,
+    saved: Instant,
+}
+
+pub struct Accounting {
+    // directory of the state file, kept open: the file can be
+    // replaced once the server entered its chroot
+    dir: File,
+    // of the state file in dir
+    name: OsString,
+    path: PathBuf,
+    ledger: Mutex<Ledger>,
+}
+
+// Usage by identity of the content of a state file
+pub fn parse(content: &str) -> Result<BTreeMap<String, Usage>, String> {
+    let mut usage = BTreeMap::new();
+    if content.trim().is_empty() {
+        return Ok(usage);
+    }
+    let value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let Value::Object(clients) = value else {
+        return Err("expected an object".to_string());
+    };
+    for (identity, value) in clients.iter() {
+        let client = Usage::from_json(value).map_err(|e| format!("{}: {}", identity, e))?;
+        usage.insert(identity.clone(), client);
+    }
+    Ok(usage)
+}
+
+impl Accounting {
+    // Read the usage saved in path, created if missing
+    pub fn open(path: &Path) -> Result<Accounting, String> {
+        let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => return Err(format!("{}: not a file", path.display())),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = File::open(dir).map_err(err)?;
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(err(e)),
+        };
+        let usage = parse(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let accounting = Accounting {
+            dir,
+            name,
+            path: path.to_path_buf(),
+            ledger: Mutex::new(Ledger {
+                usage,
+                // written now to report a read-only directory early
+                dirty: true,
+                saved: Instant::now(),
+            }),
+        };
+        accounting.save()?;
+        Ok(accounting)
+    }
+
+    // Add the traffic of a session since the last call, return the
+    // usage of its client
+    pub fn record(&self, identity: &str, traffic: &Traffic) -> Usage {
+        let mut ledger = self.ledger.lock().unwrap();
+        // under the lock: sessions of the same identity may be
+        // recorded concurrently
+        let (rx, tx) = traffic.take_unaccounted();
+        let day = today();
+        if rx == 0 && tx == 0 {
+            // idle clients are not listed
+            let mut usage = ledger.usage.get(identity).copied().unwrap_or_default();
+            usage.roll(day);
+            return usage;
+        }
+        let usage = ledger.usage.entry(identity.to_string()).or_default();
+        usage.add(day, rx, tx);
+        let usage = *usage;
+        ledger.dirty = true;
+        usage
+    }
+
+    // usage of a client, for the current day and month
+    pub fn usage_of(&self, identity: &str) -> Usage {
+        let ledger = self.ledger.lock().unwrap();
+        let mut usage = ledger.usage.get(identity).copied().unwrap_or_default();
+        usage.roll(today());
+        usage
+    }
+
+    // usage of every client, for the current day and month
+    pub fn usage(&self) -> Vec<(String, Usage)> {
+        let ledger = self.ledger.lock().unwrap();
+        let day = today();
+        ledger
+            .usage
+            .iter()
+            .map(|(identity, usage)| {
+                let mut usage = *usage;
+                usage.roll(day);
+                (identity.clone(), usage)
+            })
+            .collect()
+    }
+
+    // Write the state file if the usage changed
+    pub fn save(&self) -> Result<(), String> {
+        let mut ledger = self.ledger.lock().unwrap();
+        if !ledger.dirty {
+            return Ok(());
+        }
+        let clients: Map<String, Value> = ledger
+            .usage
+            .iter()
+            .map(|(identity, usage)| (identity.clone(), usage.to_json()))
+            .collect();
+        let content = Value::Object(clients).to_string() + "\n";
+        self.replace(content.as_bytes())
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        ledger.dirty = false;
+        ledger.saved = Instant::now();
+        Ok(())
+    }
+
+    // write content to a temporary file renamed over the state file
+    fn replace(&self, content: &[u8]) -> std::io::Result<()> {
+        let dir = self.dir.as_raw_fd();
+        let mut tmp = OsString::from(".");
+        tmp.push(&self.name);
+        tmp.push(".tmp");
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC;
+        let mode = Mode::from_bits_truncate(0o644);
+        let fd = fcntl::openat(Some(dir), tmp.as_os_str(), flags, mode)?;
+        // safety: fd has just been opened and is owned by nobody else
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        if let Err(err) = file.write_all(content).and_then(|()| file.sync_data()) {
+            let _ = unistd::unlinkat(Some(dir), tmp.as_os_str(), UnlinkatFlags::NoRemoveDir);
+            return Err(err);
+        }
+        fcntl::renameat(Some(dir), tmp.as_os_str(), Some(dir), self.name.as_os_str())?;
+        // rename is durable once the directory is synced
+        self.dir.sync_all()
+    }
+
+    // save if not done for a while
+    pub fn save_if_due(&self) -> Result<(), String> {
+        if self.ledger.lock().unwrap().saved.elapsed() < SAVE_INTERVAL {
+            return Ok(());
+        }
+        self.save()
+    }
+}
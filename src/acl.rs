@@ -0,0 +1,253 @@
+// Contains code for the server-side client database: a directory
+// containing one file for each client identity, similar to OpenVPN
+// client-config-dir. File name is the identity, content is made of
+// "key value" lines, empty lines and lines starting with '#' are ignored:
+//      ifaddr 172.19.88.2      # required tunnel address
+//      subnet 10.1.0.0/24      # subnet behind client, can be repeated
+//      peer 172.19.88.3        # client reachable both ways (rules policy), can be repeated
+//      rate 1000000            # max bytes per second sent by client
+//      enabled false           # default true
+//      totp JBSWY3DPEHPK3PXP   # base32 secret, requires one-time passwords
+//      filter allow tcp to 10.1.0.5 port 22    # packet filter rule, can be repeated
+//      quota-day 1000000000    # max bytes received and sent per UTC day (--accounting)
+//      quota-month 20000000000 # same per UTC month
+
+use crate::filter::{Filter, Rule};
+use crate::totp::{self, TotpVerifier};
+
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+const MAX_IDENTITY_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subnet {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    fn mask(&self) -> u32 {
+        if self.prefix == 0 {
+            0
+        } else {
+            (!0) << (32 - self.prefix)
+        }
+    }
+
+    pub fn contains(&self, addr: &Ipv4Addr) -> bool {
+        let mask = self.mask();
+        u32::from(self.addr) & mask == u32::from(*addr) & mask
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    // accept "a.b.c.d/n" or "a.b.c.d" (as /32)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix),
+            None => (s, "32"),
+        };
+        let addr = Ipv4Addr::from_str(addr).map_err(|e| format!("{}: {}", s, e))?;
+        let prefix = match u8::from_str(prefix) {
+            Ok(prefix) if prefix <= 32 => prefix,
+            _ => return Err(format!("{}: bad prefix length", s)),
+        };
+        Ok(Subnet { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Settings of a single client
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    pub identity: String,
+    pub ifaddr: Ipv4Addr,
+    pub subnets: Vec<Subnet>,
+    // other clients it can exchange packets with when
+    // client-to-client policy is "rules"
+    pub peers: Vec<Subnet>,
+    // bytes per second
+    pub rate: Option<u64>,
+    pub enabled: bool,
+    // one-time password secret, if required
+    pub totp: Option<Vec<u8>>,
+    // packets the client can exchange
+    pub filter: Filter,
+    // max bytes received and sent per day and per month
+    pub quota_day: Option<u64>,
+    pub quota_month: Option<u64>,
+}
+
+impl ClientConfig {
+    // can the client send packets with the given source address?
+    pub fn allowed_source(&self, addr: &Ipv4Addr) -> bool {
+        *addr == self.ifaddr || self.subnets.iter().any(|net| net.contains(addr))
+    }
+}
+
+// Settings of a connected client, shared by the threads serving it
+// and replaced when the client database is reloaded
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<ClientConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: ClientConfig) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    // current settings, not locked while used
+    pub fn get(&self) -> Arc<ClientConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: ClientConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("bad boolean: {}", value)),
+    }
+}
+
+// (line number, key, value) of "key value" lines, comments removed
+pub fn key_values(content: &str) -> Vec<(usize, &str, &str)> {
+    let mut lines = vec![];
+    for (idx, line) in content.lines().enumerate() {
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once(char::is_whitespace) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        };
+        lines.push((idx + 1, key, value));
+    }
+    lines
+}
+
+fn parse_client_config(identity: &str, content: &str) -> Result<ClientConfig, String> {
+    let mut ifaddr = None;
+    let mut config = ClientConfig {
+        identity: identity.to_string(),
+        ifaddr: Ipv4Addr::UNSPECIFIED,
+        subnets: vec![],
+        peers: vec![],
+        rate: None,
+        enabled: true,
+        totp: None,
+        filter: Filter::default(),
+        quota_day: None,
+        quota_month: None,
+    };
+    for (line, key, value) in key_values(content) {
+        let ans = match key {
+            "ifaddr" => Ipv4Addr::from_str(value)
+                .map(|addr| ifaddr = Some(addr))
+                .map_err(|e| e.to_string()),
+            "subnet" => Subnet::from_str(value).map(|net| config.subnets.push(net)),
+            "peer" => Subnet::from_str(value).map(|net| config.peers.push(net)),
+            "rate" => match u64::from_str(value) {
+                Ok(0) => Err("rate must be at least 1".to_string()),
+                Ok(rate) => {
+                    config.rate = Some(rate);
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            "enabled" => parse_bool(value).map(|flag| config.enabled = flag),
+            "totp" => totp::decode_secret(value).map(|secret| config.totp = Some(secret)),
+            "filter" => Rule::from_str(value).map(|rule| config.filter.rules.push(rule)),
+            "quota-day" => u64::from_str(value)
+                .map(|max| config.quota_day = Some(max))
+                .map_err(|e| e.to_string()),
+            "quota-month" => u64::from_str(value)
+                .map(|max| config.quota_month = Some(max))
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("unknown key: {}", key)),
+        };
+        if let Err(err) = ans {
+            return Err(format!("line {}: {}", line, err));
+        }
+    }
+    match ifaddr {
+        Some(addr) => config.ifaddr = addr,
+        None => return Err("missing ifaddr".to_string()),
+    }
+    Ok(config)
+}
+
+// identity is used as file name: forbid path separators and hidden files
+pub fn valid_identity(identity: &str) -> bool {
+    !identity.is_empty()
+        && identity.len() <= MAX_IDENTITY_LEN
+        && !identity.starts_with('.')
+        && identity
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c))
+}
+
+pub struct ClientDb {
+    dir: PathBuf,
+    totp: TotpVerifier,
+}
+
+impl ClientDb {
+    pub fn new(dir: PathBuf) -> ClientDb {
+        ClientDb {
+            dir,
+            totp: TotpVerifier::default(),
+        }
+    }
+
+    // check one-time password of a client requiring it
+    pub fn verify_totp(&self, config: &ClientConfig, code: u32) -> bool {
+        match &config.totp {
+            Some(secret) => self.totp.verify(&config.identity, secret, code),
+            None => false,
+        }
+    }
+
+    // Files are read on every lookup so changes are applied
+    // to new connections without restarting the server.
+    // Return Ok(None) if identity is unknown
+    pub fn lookup(
+        &self,
+        identity: &str,
+    ) -> std::result::Result<Option<ClientConfig>, Box<dyn std::error::Error>> {
+        if !valid_identity(identity) {
+            return Ok(None);
+        }
+        let path = self.dir.join(identity);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match parse_client_config(identity, &content) {
+            Ok(config) => Ok(Some(config)),
+            Err(err) => {
+                let msg = format!("Invalid client file {}: {}", path.display(), err);
+                Err(msg.into())
+            }
+        }
+    }
+}
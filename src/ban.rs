@@ -0,0 +1,155 @@
+// Contains the list of source addresses temporarily banned by the
+// server after repeated handshake failures (bad magic, failed
+// authentication, bad address...). Each new ban of the same source
+// lasts twice as long as the previous one, up to MAX_BAN. Connections
+// from a banned source are closed as soon as they are accepted.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// failures older than this are forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+// longest ban, also the time after which previous bans are forgotten
+const MAX_BAN: Duration = Duration::from_secs(86400);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BanPolicy {
+    // handshake failures within FAILURE_WINDOW causing a ban
+    pub failures: u32,
+    // duration of the first ban
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct Record {
+    // recent failures
+    failures: VecDeque<Instant>,
+    // bans so far, for escalation
+    bans: u32,
+    // end of current or last ban
+    until: Option<Instant>,
+}
+
+impl Record {
+    // nothing worth remembering?
+    fn is_stale(&self, now: Instant) -> bool {
+        let recent = |time: &Instant| now.saturating_duration_since(*time) < FAILURE_WINDOW;
+        match self.until {
+            Some(until) => now > until + MAX_BAN,
+            None => !self.failures.iter().any(recent),
+        }
+    }
+}
+
+// Banned source, as listed to the administrator
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub source: IpAddr,
+    // time left
+    pub remaining: Duration,
+    // bans of this source so far, this one included
+    pub count: u32,
+}
+
+pub struct Bans {
+    policy: Mutex<Option<BanPolicy>>,
+    sources: Mutex<HashMap<IpAddr, Record>>,
+}
+
+impl Bans {
+    // policy None disables banning
+    pub fn new(policy: Option<BanPolicy>) -> Bans {
+        Bans {
+            policy: Mutex::new(policy),
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Current bans are kept, and expire as planned, if the new policy
+    // is None
+    pub fn set_policy(&self, policy: Option<BanPolicy>) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    // Err with the reason if source is currently banned
+    pub fn check(&self, source: IpAddr) -> Result<(), String> {
+        let sources = self.sources.lock().unwrap();
+        let until = sources.get(&source).and_then(|record| record.until);
+        match until {
+            Some(until) if until > Instant::now() => Err(format!(
+                "banned for {}s",
+                until.duration_since(Instant::now()).as_secs() + 1
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Record a handshake failure of source, return the duration of the
+    // ban if source has just been banned
+    pub fn failure(&self, source: IpAddr) -> Option<Duration> {
+        let policy = (*self.policy.lock().unwrap())?;
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        sources.retain(|_, record| !record.is_stale(now));
+        let record = sources.entry(source).or_default();
+        while let Some(time) = record.failures.front() {
+            if now.duration_since(*time) < FAILURE_WINDOW {
+                break;
+            }
+            record.failures.pop_front();
+        }
+        record.failures.push_back(now);
+        if record.failures.len() < policy.failures as usize {
+            return None;
+        }
+        // doubled at each ban
+        let duration = policy
+            .duration
+            .saturating_mul(1 << record.bans.min(16))
+            .min(MAX_BAN);
+        record.failures.clear();
+        record.bans += 1;
+        record.until = Some(now + duration);
+        Some(duration)
+    }
+
+    // sources currently banned
+    pub fn list(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap();
+        let mut bans: Vec<Ban> = sources
+            .iter()
+            .filter_map(|(source, record)| {
+                let until = record.until.filter(|until| *until > now)?;
+                Some(Ban {
+                    source: *source,
+                    remaining: until - now,
+                    count: record.bans,
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.source);
+        bans
+    }
+
+    // Forget bans and failures of source (all sources if None),
+    // return the number of bans lifted
+    pub fn clear(&self, source: Option<IpAddr>) -> usize {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        let banned = |record: &Record| record.until.is_some_and(|until| until > now);
+        match source {
+            Some(source) => match sources.remove(&source) {
+                Some(record) if banned(&record) => 1,
+                _ => 0,
+            },
+            None => {
+                let count = sources.values().filter(|record| banned(record)).count();
+                sources.clear();
+                count
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Contains code for X.509 certificate authentication: each endpoint
+// can present a certificate issued by the CA trusted by its peer and
+// prove it owns the private key signing a challenge chosen by the
+// peer. Client certificates must allow client authentication (extended
+// key usage), server certificates server authentication, so that one
+// cannot be used in place of the other. Certificate subject common
+// name is the client identity.
+//
+// Supported keys: ECDSA P-256 and Ed25519 (PKCS#8 PEM)
+
+use ring::rand::SystemRandom;
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use x509_parser::oid_registry::{OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_SIG_ED25519};
+use x509_parser::pem::Pem;
+use x509_parser::prelude::*;
+
+pub const CHALLENGE_LEN: usize = 32;
+// prepended to the challenge before signing it, so that signatures
+// cannot be reused in other protocols nor by the other endpoint
+const CLIENT_CONTEXT: &[u8] = b"rust-tcp-vpn client authentication\0";
+const SERVER_CONTEXT: &[u8] = b"rust-tcp-vpn server authentication\0";
+// how often the revocation list is read again
+const CRL_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+// Endpoint authenticated by a certificate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+fn signed_message(challenge: &[u8], role: Role) -> Vec<u8> {
+    let mut msg = match role {
+        Role::Client => CLIENT_CONTEXT.to_vec(),
+        Role::Server => SERVER_CONTEXT.to_vec(),
+    };
+    msg.extend_from_slice(challenge);
+    msg
+}
+
+// return DER content of every PEM block with the given label
+fn read_pem(
+    path: &Path,
+    label: &str,
+) -> std::result::Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let content = std::fs::read(path)?;
+    let mut blocks = vec![];
+    for pem in Pem::iter_from_buffer(&content) {
+        let pem = pem.map_err(|e| format!("{}: {}", path.display(), e))?;
+        if pem.label == label {
+            blocks.push(pem.contents);
+        }
+    }
+    if blocks.is_empty() {
+        let msg = format!("{}: no {} found", path.display(), label);
+        return Err(msg.into());
+    }
+    Ok(blocks)
+}
+
+pub fn new_challenge() -> std::result::Result<[u8; CHALLENGE_LEN], Box<dyn std::error::Error>> {
+    use ring::rand::SecureRandom;
+    let mut challenge = [0; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| "Cannot generate random challenge")?;
+    Ok(challenge)
+}
+
+enum SigningKey {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+// Certificate and private key of this endpoint
+pub struct Credentials {
+    pub cert: Vec<u8>,
+    key: SigningKey,
+}
+
+impl Credentials {
+    pub fn load(
+        cert: &Path,
+        key: &Path,
+    ) -> std::result::Result<Credentials, Box<dyn std::error::Error>> {
+        let cert = read_pem(cert, "CERTIFICATE")?.swap_remove(0);
+        let pkcs8 = read_pem(key, "PRIVATE KEY")?.swap_remove(0);
+        let key = if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8) {
+            SigningKey::Ed25519(pair)
+        } else {
+            let rng = SystemRandom::new();
+            match EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, &pkcs8, &rng)
+            {
+                Ok(pair) => SigningKey::Ecdsa(pair),
+                Err(err) => {
+                    let msg = format!("{}: unsupported private key: {}", key.display(), err);
+                    return Err(msg.into());
+                }
+            }
+        };
+        // double check key belongs to certificate
+        let (_, parsed) = X509Certificate::from_der(&cert)?;
+        let public = match &key {
+            SigningKey::Ecdsa(pair) => pair.public_key().as_ref(),
+            SigningKey::Ed25519(pair) => pair.public_key().as_ref(),
+        };
+        if parsed.public_key().subject_public_key.data.as_ref() != public {
+            return Err("Private key does not match certificate".into());
+        }
+        Ok(Credentials { cert, key })
+    }
+
+    // sign challenge received from the peer, role is ours
+    pub fn sign(
+        &self,
+        challenge: &[u8],
+        role: Role,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let msg = signed_message(challenge, role);
+        let sig = match &self.key {
+            SigningKey::Ecdsa(pair) => pair
+                .sign(&SystemRandom::new(), &msg)
+                .map_err(|_| "Cannot sign challenge")?
+                .as_ref()
+                .to_vec(),
+            SigningKey::Ed25519(pair) => pair.sign(&msg).as_ref().to_vec(),
+        };
+        Ok(sig)
+    }
+}
+
+struct Crl {
+    path: PathBuf,
+    // serial numbers of revoked certificates
+    revoked: Vec<Vec<u8>>,
+    loaded: Instant,
+}
+
+// Verification of the certificates of the peers
+pub struct CertVerifier {
+    ca: Vec<u8>,
+    crl: Option<Mutex<Crl>>,
+}
+
+impl CertVerifier {
+    pub fn load(
+        ca: &Path,
+        crl: Option<&Path>,
+    ) -> std::result::Result<CertVerifier, Box<dyn std::error::Error>> {
+        let path = ca;
+        let ca = read_pem(ca, "CERTIFICATE")?.swap_remove(0);
+        let (_, parsed) = X509Certificate::from_der(&ca)?;
+        let is_ca =
+            matches!(parsed.basic_constraints(), Ok(Some(constraints)) if constraints.value.ca);
+        if !is_ca {
+            let msg = format!(
+                "{}: not a CA certificate (basicConstraints)",
+                path.display()
+            );
+            return Err(msg.into());
+        }
+        if let Ok(Some(usage)) = parsed.key_usage() {
+            if !usage.value.key_cert_sign() {
+                let msg = format!(
+                    "{}: key usage does not allow signing certificates",
+                    path.display()
+                );
+                return Err(msg.into());
+            }
+        }
+        let mut verifier = CertVerifier { ca, crl: None };
+        if let Some(path) = crl {
+            let revoked = verifier.load_crl(path)?;
+            verifier.crl = Some(Mutex::new(Crl {
+                path: path.to_path_buf(),
+                revoked,
+                loaded: Instant::now(),
+            }));
+        }
+        Ok(verifier)
+    }
+
+    // parse revocation list (PEM or DER) and check it has been issued by CA
+    fn load_crl(
+        &self,
+        path: &Path,
+    ) -> std::result::Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let content = std::fs::read(path)?;
+        let der = match Pem::read(Cursor::new(&content)) {
+            Ok((pem, _)) => pem.contents,
+            Err(_) => content,
+        };
+        let (_, crl) = parse_x509_crl(&der).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let (_, ca) = X509Certificate::from_der(&self.ca)?;
+        crl.verify_signature(ca.public_key())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(crl
+            .iter_revoked_certificates()
+            .map(|revoked| revoked.raw_serial().to_vec())
+            .collect())
+    }
+
+    fn is_revoked(&self, serial: &[u8]) -> bool {
+        let crl = match &self.crl {
+            Some(crl) => crl,
+            None => return false,
+        };
+        let mut crl = crl.lock().unwrap();
+        if crl.loaded.elapsed() >= CRL_RELOAD_INTERVAL {
+            self.refresh(&mut crl);
+        }
+        crl.revoked.iter().any(|revoked| revoked == serial)
+    }
+
+    fn refresh(&self, crl: &mut Crl) {
+        // on failure keep using previous list
+        match self.load_crl(&crl.path) {
+            Ok(revoked) => crl.revoked = revoked,
+            Err(err) => eprintln!("Cannot reload revocation list: {}", err),
+        }
+        crl.loaded = Instant::now();
+    }
+
+    // read the revocation list (if any) now instead of waiting
+    // for the periodic reload
+    pub fn reload_crl(&self) {
+        if let Some(crl) = &self.crl {
+            self.refresh(&mut crl.lock().unwrap());
+        }
+    }
+
+    // Check certificate (DER) has been issued by the CA for role, is
+    // currently valid and not revoked, return subject common name
+    pub fn verify(&self, cert: &[u8], role: Role) -> std::result::Result<String, String> {
+        let (_, cert) = X509Certificate::from_der(cert).map_err(|e| e.to_string())?;
+        let (_, ca) = X509Certificate::from_der(&self.ca).map_err(|e| e.to_string())?;
+        if cert.issuer() != ca.subject() {
+            return Err("unknown issuer".to_string());
+        }
+        cert.verify_signature(Some(ca.public_key()))
+            .map_err(|_| "bad certificate signature".to_string())?;
+        if !cert.validity().is_valid() {
+            return Err("certificate expired or not yet valid".to_string());
+        }
+        match cert.key_usage() {
+            Ok(Some(usage)) if !usage.value.digital_signature() => {
+                return Err("key usage does not allow digital signature".to_string());
+            }
+            Ok(_) => {}
+            Err(err) => return Err(err.to_string()),
+        }
+        let allowed = match cert.extended_key_usage() {
+            Ok(Some(usage)) => match role {
+                Role::Client => usage.value.client_auth,
+                Role::Server => usage.value.server_auth,
+            },
+            Ok(None) => false,
+            Err(err) => return Err(err.to_string()),
+        };
+        if !allowed {
+            let text = match role {
+                Role::Client => "extended key usage does not allow client authentication",
+                Role::Server => "extended key usage does not allow server authentication",
+            };
+            return Err(text.to_string());
+        }
+        if self.is_revoked(cert.raw_serial()) {
+            return Err("certificate revoked".to_string());
+        }
+        let cn = match cert.subject().iter_common_name().next() {
+            Some(cn) => cn
+                .as_str()
+                .map(|cn| cn.to_string())
+                .map_err(|_| "bad subject common name".to_string()),
+            None => Err("missing subject common name".to_string()),
+        };
+        cn
+    }
+
+    // check signature of the challenge made with certificate key by
+    // the peer acting as role
+    pub fn verify_signature(cert: &[u8], challenge: &[u8], sig: &[u8], role: Role) -> bool {
+        let cert = match X509Certificate::from_der(cert) {
+            Ok((_, cert)) => cert,
+            Err(_) => return false,
+        };
+        let spki = cert.public_key();
+        let alg: &dyn signature::VerificationAlgorithm =
+            if spki.algorithm.algorithm == OID_SIG_ED25519 {
+                &signature::ED25519
+            } else if spki.algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY
+                && spki
+                    .algorithm
+                    .parameters
+                    .as_ref()
+                    .and_then(|p| p.as_oid().ok())
+                    == Some(OID_EC_P256)
+            {
+                &signature::ECDSA_P256_SHA256_ASN1
+            } else {
+                return false;
+            };
+        let key = signature::UnparsedPublicKey::new(alg, &spki.subject_public_key.data);
+        key.verify(&signed_message(challenge, role), sig).is_ok()
+    }
+}
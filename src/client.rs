@@ -1,21 +1,152 @@
+use crate::control::{self, ControlSocket};
+use crate::dns;
 use crate::flows;
-use crate::handshake;
+use crate::handshake::{self, ClientSettings};
+use crate::info;
+use crate::privileges::Privileges;
+use crate::session::{Resume, Session, SessionInfo, Ticket, Traffic};
+use crate::transport::Peer;
 use crate::tunif;
 
-use std::net::{IpAddr, TcpStream};
+use serde_json::{json, Value};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::PathBuf;
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// delay between reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Commands of the control socket specific to the client
+struct ClientControl {
+    identity: Option<String>,
+    ifaddr: Ipv4Addr,
+    remote: SocketAddr,
+    // start and traffic of the current session
+    session: Mutex<(Instant, Arc<Traffic>)>,
+}
+
+impl ClientControl {
+    fn set_session(&self, session: &Session) {
+        *self.session.lock().unwrap() = (session.started, session.traffic.clone());
+    }
+}
+
+impl control::Handler for ClientControl {
+    fn handle(&self, cmd: &str, _request: &Value) -> Result<Value, String> {
+        match cmd {
+            "sessions" => {
+                let (started, traffic) = &*self.session.lock().unwrap();
+                let session = SessionInfo {
+                    identity: self.identity.clone(),
+                    ifaddr: self.ifaddr,
+                    remote: Peer::Tcp(self.remote),
+                    uptime: started.elapsed(),
+                    traffic: traffic.get(),
+                    metadata: None,
+                };
+                Ok(json!({ "sessions": [control::session_json(&session)] }))
+            }
+            "kick" | "bans" | "unban" | "usage" => Err(format!("{}: server only command", cmd)),
+            _ => Err(format!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+// wait for delay, return true if a signal was received meanwhile
+fn wait_or_signal(
+    sigfile: &mut std::fs::File,
+    delay: Duration,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    use nix::poll::{PollFd, PollFlags, PollTimeout};
+    use std::os::fd::AsFd;
+
+    let mut fds = [PollFd::new(sigfile.as_fd(), PollFlags::POLLIN)];
+    let timeout = PollTimeout::try_from(delay).unwrap_or(PollTimeout::MAX);
+    if nix::poll::poll(&mut fds, timeout)? > 0 {
+        crate::signals::consume_sigpipe(sigfile);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// Try to resume session until deadline, return None if the
+// deadline expires or a signal is received
+fn reconnect(
+    remote: std::net::SocketAddr,
+    settings: &ClientSettings,
+    ticket: &mut Ticket,
+    session: &mut Session,
+    sigfile: &mut std::fs::File,
+    deadline: Instant,
+) -> std::result::Result<Option<TcpStream>, Box<dyn std::error::Error>> {
+    while Instant::now() < deadline {
+        if wait_or_signal(sigfile, RECONNECT_DELAY)? {
+            return Ok(None);
+        }
+        let mut stream = match TcpStream::connect(remote) {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Cannot reconnect to: {} cause {}", remote, err);
+                continue;
+            }
+        };
+        let resume = Resume {
+            ticket: *ticket,
+            received: session.received,
+        };
+        let info = match handshake::handler_client_handshake(&mut stream, settings, Some(&resume)) {
+            Ok(None) => {
+                return Err("Failed client handshake due to protocol error".into());
+            }
+            Ok(Some(info)) => info,
+            Err(err) if flows::is_connection_error(err.as_ref()) => {
+                eprintln!("Failed client handshake: {}", err);
+                continue;
+            }
+            Err(err) => {
+                let msg = format!("Failed client handshake: {}", err);
+                return Err(msg.into());
+            }
+        };
+        match info.resumed {
+            Some(received) => flows::resume_session(&mut stream, session, received)?,
+            None => {
+                info!("Server did not resume session, starting a new one");
+                *session = Session::new(info.ticket.is_some());
+            }
+        }
+        match info.ticket {
+            Some(value) => *ticket = value,
+            None => {
+                // server does not allow further resumptions
+                *session = Session::new(false);
+            }
+        }
+        return Ok(Some(stream));
+    }
+    Ok(None)
+}
 
 pub fn execute_client(
     ifname: String,
-    ifaddr: IpAddr,
-    netmask: u8,
+    settings: ClientSettings,
     remote: std::net::SocketAddr,
+    dns_apply: dns::DnsApply,
+    resume_grace: Duration,
+    control: Option<PathBuf>,
+    privileges: Privileges,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let mut iffile = tunif::initialize_tun_interface(&ifname, ifaddr, netmask);
+    let mut iffile = tunif::initialize_tun_interface(&ifname, settings.ifaddr, settings.netmask);
+    if let Err(err) = privileges.drop() {
+        eprintln!("ERROR: cannot drop privileges: {}", err);
+        process::exit(1)
+    }
     // try to connect to remote server
     let mut stream = match TcpStream::connect(remote) {
         Ok(stream) => {
-            println!("Connection established!");
+            info!("Connection established!");
             stream
         }
         Err(err) => {
@@ -25,12 +156,11 @@ pub fn execute_client(
     };
 
     // start handshake as client
-    // if true ok
-    let _h = match handshake::handler_client_handshake(&mut stream, &ifaddr, netmask) {
-        Ok(false) => {
+    let info = match handshake::handler_client_handshake(&mut stream, &settings, None) {
+        Ok(None) => {
             return Err("Failed client handshake due to protocol error".into());
         }
-        Ok(h) => h,
+        Ok(Some(info)) => info,
         Err(err) => {
             let msg = format!("Failed client handshake: {}", err);
             return Err(msg.into());
@@ -38,8 +168,67 @@ pub fn execute_client(
     };
     // bring interface up
     tunif::set_interface_up(&iffile, &ifname);
+    // apply DNS settings pushed by server
+    let dns_guard = match dns::apply(&dns_apply, &info.dns) {
+        Ok(guard) => guard,
+        Err(err) => {
+            tunif::set_interface_down(&iffile, &ifname);
+            let msg = format!("Cannot apply DNS settings: {}", err);
+            return Err(msg.into());
+        }
+    };
     let mut sigfile = crate::signals::spawn_sig_handler();
-    let ans = flows::handle_flow(&mut stream, &mut iffile, &mut sigfile);
+    // resumption is possible only if server issued a ticket
+    let mut ticket = info.ticket;
+    let mut session = Session::new(ticket.is_some() && !resume_grace.is_zero());
+    let status = Arc::new(ClientControl {
+        identity: settings.identity.clone(),
+        ifaddr: match settings.ifaddr {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        },
+        remote,
+        session: Mutex::new((session.started, session.traffic.clone())),
+    });
+    // socket file removed when the client exits
+    let _control = match control {
+        Some(path) => match ControlSocket::spawn(&path, status.clone()) {
+            Ok(control) => Some(control),
+            Err(err) => {
+                eprintln!("Cannot create control socket {}: {}", path.display(), err);
+                None
+            }
+        },
+        None => None,
+    };
+    let ans = loop {
+        // session may have been replaced when reconnecting
+        status.set_session(&session);
+        let ans = flows::handle_flow(&mut stream, &mut iffile, &mut sigfile, &mut session);
+        let err = match ans {
+            Err(err) if flows::is_connection_error(err.as_ref()) => err,
+            ans => break ans,
+        };
+        let current = match ticket.as_mut() {
+            Some(current) if !resume_grace.is_zero() => current,
+            _ => break Err(err),
+        };
+        eprintln!("Connection lost: {}, trying to resume session", err);
+        let deadline = Instant::now() + resume_grace;
+        match reconnect(
+            remote,
+            &settings,
+            current,
+            &mut session,
+            &mut sigfile,
+            deadline,
+        ) {
+            Ok(Some(new_stream)) => stream = new_stream,
+            Ok(None) => break Err(err),
+            Err(err) => break Err(err),
+        }
+    };
+    dns::restore(dns_guard);
     tunif::set_interface_down(&iffile, &ifname);
     match ans {
         Err(e) => Err(e),
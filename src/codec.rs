@@ -0,0 +1,354 @@
+// Contains encoding and decoding of every message exchanged between
+// endpoints: handshake packets and data flow frames. Functions here
+// work on byte buffers only, I/O is done elsewhere.
+//
+// All integers are big endian.
+//
+// HANDSHAKE PACKETS: MAGIC, packet id (u32), content
+//      client hello:   1, ifaddr (u32), netmask (u32), extensions
+//      server hello:   2, ifaddr (u32), extensions
+//      client confirm: 3, status (u32), extensions
+//      reject:         4, reason (u32), text length (u32), UTF-8 text
+// Extensions are carried as a block:
+//      block: total length (u32) followed by extensions
+//      extension: kind (u16), length (u16), value
+//
+// FLOW FRAMES:
+//      data: 1, packet length (u32), counter (u64), packet
+//      exit: 2, reason (u32): 0 normal exit, 1 duplicate session
+//      ack:  3, counter (u64)
+
+use std::io::Read;
+use std::net::Ipv4Addr;
+
+pub const MAGIC: u32 = 0x12345678;
+
+// max size of a network packet carried by data frames
+pub const MAX_PACKET_LEN: usize = 4096;
+pub const MAX_EXTENSIONS_LEN: usize = 16384;
+pub const MAX_REJECT_TEXT_LEN: usize = 1024;
+
+const CLIENT_HELLO_ID: u32 = 1;
+const SERVER_HELLO_ID: u32 = 2;
+const CLIENT_CONFIRM_ID: u32 = 3;
+const REJECT_ID: u32 = 4;
+
+// exit reasons
+pub const EXIT_NORMAL: u32 = 0;
+// replaced by a new session of the same client
+pub const EXIT_DUPLICATE: u32 = 1;
+// disconnected by the administrator of the server
+pub const EXIT_KICKED: u32 = 2;
+// traffic quota of the client exceeded
+pub const EXIT_QUOTA: u32 = 3;
+
+const DATA_TYPE: u32 = 1;
+const EXIT_TYPE: u32 = 2;
+const ACK_TYPE: u32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum CodecError {
+    // buffer is too short, at least this many bytes are needed
+    Incomplete(usize),
+    // handshake packet not starting with MAGIC
+    BadMagic(u32),
+    // unknown frame type or handshake packet id
+    UnknownType(u32),
+    // length field exceeds limit
+    TooLong { len: usize, max: usize },
+    // content is not valid
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CodecError::Incomplete(len) => write!(f, "incomplete message, {} bytes needed", len),
+            CodecError::BadMagic(magic) => write!(f, "bad magic {:#010x}", magic),
+            CodecError::UnknownType(id) => write!(f, "unknown message type {:#010x}", id),
+            CodecError::TooLong { len, max } => write!(f, "length {} exceeds limit {}", len, max),
+            CodecError::Malformed(what) => write!(f, "malformed {}", what),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Extension {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HandshakeMsg {
+    ClientHello {
+        ifaddr: Ipv4Addr,
+        netmask: u32,
+        extensions: Vec<Extension>,
+    },
+    ServerHello {
+        ifaddr: Ipv4Addr,
+        extensions: Vec<Extension>,
+    },
+    ClientConfirm {
+        status: u32,
+        extensions: Vec<Extension>,
+    },
+    Reject {
+        reason: u32,
+        text: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame<'a> {
+    Data { counter: u64, packet: &'a [u8] },
+    Exit { reason: u32 },
+    Ack { counter: u64 },
+}
+
+// Keep track of decoding position
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Cursor<'a> {
+        Cursor { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.buf.len() < self.pos + len {
+            return Err(CodecError::Incomplete(self.pos + len));
+        }
+        let ans = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ans)
+    }
+
+    fn u16(&mut self) -> Result<u16, CodecError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    // read length field and check it against limit
+    fn len(&mut self, max: usize) -> Result<usize, CodecError> {
+        let len = self.u32()? as usize;
+        if len > max {
+            return Err(CodecError::TooLong { len, max });
+        }
+        Ok(len)
+    }
+}
+
+fn encode_extensions(extensions: &[Extension], out: &mut Vec<u8>) -> Result<(), CodecError> {
+    let mut len = 0;
+    for ext in extensions.iter() {
+        if ext.value.len() > u16::MAX as usize {
+            return Err(CodecError::TooLong {
+                len: ext.value.len(),
+                max: u16::MAX as usize,
+            });
+        }
+        len += 4 + ext.value.len();
+    }
+    if len > MAX_EXTENSIONS_LEN {
+        return Err(CodecError::TooLong {
+            len,
+            max: MAX_EXTENSIONS_LEN,
+        });
+    }
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    for ext in extensions.iter() {
+        out.extend_from_slice(&ext.kind.to_be_bytes());
+        out.extend_from_slice(&(ext.value.len() as u16).to_be_bytes());
+        out.extend_from_slice(&ext.value);
+    }
+    Ok(())
+}
+
+fn decode_extensions(cursor: &mut Cursor) -> Result<Vec<Extension>, CodecError> {
+    let len = cursor.len(MAX_EXTENSIONS_LEN)?;
+    let mut block = Cursor::new(cursor.bytes(len)?);
+    let mut extensions = vec![];
+    while block.pos < block.buf.len() {
+        // incomplete here means the block is truncated
+        let truncated = |_| CodecError::Malformed("extension");
+        let kind = block.u16().map_err(truncated)?;
+        let ext_len = block.u16().map_err(truncated)? as usize;
+        let value = block.bytes(ext_len).map_err(truncated)?.to_vec();
+        extensions.push(Extension { kind, value });
+    }
+    Ok(extensions)
+}
+
+pub fn encode_handshake(msg: &HandshakeMsg, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    out.extend_from_slice(&MAGIC.to_be_bytes());
+    match msg {
+        HandshakeMsg::ClientHello {
+            ifaddr,
+            netmask,
+            extensions,
+        } => {
+            out.extend_from_slice(&CLIENT_HELLO_ID.to_be_bytes());
+            out.extend_from_slice(&ifaddr.octets());
+            out.extend_from_slice(&netmask.to_be_bytes());
+            encode_extensions(extensions, out)
+        }
+        HandshakeMsg::ServerHello { ifaddr, extensions } => {
+            out.extend_from_slice(&SERVER_HELLO_ID.to_be_bytes());
+            out.extend_from_slice(&ifaddr.octets());
+            encode_extensions(extensions, out)
+        }
+        HandshakeMsg::ClientConfirm { status, extensions } => {
+            out.extend_from_slice(&CLIENT_CONFIRM_ID.to_be_bytes());
+            out.extend_from_slice(&status.to_be_bytes());
+            encode_extensions(extensions, out)
+        }
+        HandshakeMsg::Reject { reason, text } => {
+            // truncate text on a char boundary
+            let mut end = text.len().min(MAX_REJECT_TEXT_LEN);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            out.extend_from_slice(&REJECT_ID.to_be_bytes());
+            out.extend_from_slice(&reason.to_be_bytes());
+            out.extend_from_slice(&(end as u32).to_be_bytes());
+            out.extend_from_slice(&text.as_bytes()[..end]);
+            Ok(())
+        }
+    }
+}
+
+// Return decoded message and number of bytes used
+pub fn decode_handshake(buf: &[u8]) -> Result<(HandshakeMsg, usize), CodecError> {
+    let mut cursor = Cursor::new(buf);
+    let magic = cursor.u32()?;
+    if magic != MAGIC {
+        return Err(CodecError::BadMagic(magic));
+    }
+    let msg = match cursor.u32()? {
+        CLIENT_HELLO_ID => {
+            let ifaddr = Ipv4Addr::from(cursor.u32()?);
+            let netmask = cursor.u32()?;
+            let extensions = decode_extensions(&mut cursor)?;
+            HandshakeMsg::ClientHello {
+                ifaddr,
+                netmask,
+                extensions,
+            }
+        }
+        SERVER_HELLO_ID => {
+            let ifaddr = Ipv4Addr::from(cursor.u32()?);
+            let extensions = decode_extensions(&mut cursor)?;
+            HandshakeMsg::ServerHello { ifaddr, extensions }
+        }
+        CLIENT_CONFIRM_ID => {
+            let status = cursor.u32()?;
+            let extensions = decode_extensions(&mut cursor)?;
+            HandshakeMsg::ClientConfirm { status, extensions }
+        }
+        REJECT_ID => {
+            let reason = cursor.u32()?;
+            let len = cursor.len(MAX_REJECT_TEXT_LEN)?;
+            let text = String::from_utf8_lossy(cursor.bytes(len)?).into_owned();
+            HandshakeMsg::Reject { reason, text }
+        }
+        id => return Err(CodecError::UnknownType(id)),
+    };
+    Ok((msg, cursor.pos))
+}
+
+pub fn encode_frame(frame: &Frame, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    match frame {
+        Frame::Data { counter, packet } => {
+            if packet.len() > MAX_PACKET_LEN {
+                return Err(CodecError::TooLong {
+                    len: packet.len(),
+                    max: MAX_PACKET_LEN,
+                });
+            }
+            out.extend_from_slice(&DATA_TYPE.to_be_bytes());
+            out.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            out.extend_from_slice(&counter.to_be_bytes());
+            out.extend_from_slice(packet);
+        }
+        Frame::Exit { reason } => {
+            out.extend_from_slice(&EXIT_TYPE.to_be_bytes());
+            out.extend_from_slice(&reason.to_be_bytes());
+        }
+        Frame::Ack { counter } => {
+            out.extend_from_slice(&ACK_TYPE.to_be_bytes());
+            out.extend_from_slice(&counter.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+// Return decoded frame and number of bytes used
+pub fn decode_frame(buf: &[u8]) -> Result<(Frame<'_>, usize), CodecError> {
+    let mut cursor = Cursor::new(buf);
+    let frame = match cursor.u32()? {
+        DATA_TYPE => {
+            let len = cursor.len(MAX_PACKET_LEN)?;
+            let counter = cursor.u64()?;
+            let packet = cursor.bytes(len)?;
+            Frame::Data { counter, packet }
+        }
+        EXIT_TYPE => Frame::Exit {
+            reason: cursor.u32()?,
+        },
+        ACK_TYPE => Frame::Ack {
+            counter: cursor.u64()?,
+        },
+        id => return Err(CodecError::UnknownType(id)),
+    };
+    Ok((frame, cursor.pos))
+}
+
+// Read from stream until buf contains a whole message according
+// to decode, bytes are read only when decode asks for them
+fn fill_message(
+    stream: &mut impl Read,
+    buf: &mut Vec<u8>,
+    decode: impl Fn(&[u8]) -> Result<usize, CodecError>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    buf.clear();
+    loop {
+        match decode(buf) {
+            Ok(_) => return Ok(()),
+            Err(CodecError::Incomplete(needed)) => {
+                let len = buf.len();
+                buf.resize(needed, 0);
+                stream.read_exact(&mut buf[len..])?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+pub fn read_handshake(
+    stream: &mut impl Read,
+) -> std::result::Result<HandshakeMsg, Box<dyn std::error::Error>> {
+    let mut buf = vec![];
+    fill_message(stream, &mut buf, |buf| Ok(decode_handshake(buf)?.1))?;
+    Ok(decode_handshake(&buf)?.0)
+}
+
+// buf is used to store the frame and is reused across calls
+pub fn read_frame<'a>(
+    stream: &mut impl Read,
+    buf: &'a mut Vec<u8>,
+) -> std::result::Result<Frame<'a>, Box<dyn std::error::Error>> {
+    fill_message(stream, buf, |buf| Ok(decode_frame(buf)?.1))?;
+    Ok(decode_frame(buf)?.0)
+}
@@ -0,0 +1,177 @@
+// Contains the server settings that can change while it is running,
+// read from the --config file at startup and again when SIGHUP is
+// received. Same syntax as the client database files:
+//      log-level debug         # error, info or debug
+//      max-sessions 100        # 0 for no limit
+//      max-handshakes 32
+//      max-source-handshakes 4
+//      max-conn-rate 20        # 0 for no limit
+//      ban-after 5             # 0 disables bans
+//      ban-time 60
+//      push-dns 10.8.0.1       # can be repeated
+//      push-domain vpn.example # can be repeated
+// Settings missing from the file keep their command line value.
+// Changes apply to new connections, sessions are not interrupted.
+// Settings used once at startup are also read from the file, a change
+// of them is reported on reload and requires a restart:
+//      listen 0.0.0.0:7000     # can be repeated
+//      control /run/vpn.ctl
+//      metrics 9100
+//      user nobody
+//      group nogroup
+//      chroot /var/empty
+
+use crate::acl;
+use crate::ban::BanPolicy;
+use crate::dns::DnsConfig;
+use crate::limits::Limits;
+use crate::logging::Level;
+use crate::parsing;
+use crate::privileges::Privileges;
+use crate::transport::Endpoint;
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Runtime {
+    pub log_level: Level,
+    pub limits: Limits,
+    // settings pushed to clients
+    pub dns: DnsConfig,
+    // not changed while running
+    pub startup: Startup,
+}
+
+// Settings used once at startup
+#[derive(Clone, Debug, PartialEq)]
+pub struct Startup {
+    // sockets passed by systemd are used as well
+    pub listen: Vec<Endpoint>,
+    pub control: Option<PathBuf>,
+    pub metrics: Option<SocketAddr>,
+    pub privileges: Privileges,
+}
+
+// Settings of new that differ from the ones of old but cannot change
+// while running
+pub fn restart_needed(old: &Runtime, new: &Runtime) -> Vec<&'static str> {
+    let (old, new) = (&old.startup, &new.startup);
+    let changes = [
+        ("listen", old.listen != new.listen),
+        ("control", old.control != new.control),
+        ("metrics", old.metrics != new.metrics),
+        ("user", old.privileges.user != new.privileges.user),
+        ("group", old.privileges.group != new.privileges.group),
+        ("chroot", old.privileges.chroot != new.privileges.chroot),
+    ];
+    changes
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    u32::from_str(value).map_err(|e| format!("{}: {}", value, e))
+}
+
+fn parse_positive(value: &str) -> Result<u32, String> {
+    match parse_number(value)? {
+        0 => Err("must be at least 1".to_string()),
+        n => Ok(n),
+    }
+}
+
+// 0 means no limit
+fn parse_limit(value: &str) -> Result<Option<u32>, String> {
+    parse_number(value).map(|n| if n == 0 { None } else { Some(n) })
+}
+
+// Settings of base overridden by the ones of content
+pub fn parse(content: &str, base: &Runtime) -> Result<Runtime, String> {
+    let mut config = base.clone();
+    let mut ban_after = base.limits.ban.map(|ban| ban.failures);
+    let mut ban_time = base.limits.ban.map(|ban| ban.duration);
+    // lists of the file replace the ones of the command line
+    let mut nameservers = None;
+    let mut search = None;
+    let mut listen = None;
+    for (line, key, value) in acl::key_values(content) {
+        let ans =
+            match key {
+                "log-level" => Level::from_str(value).map(|level| config.log_level = level),
+                "max-sessions" => parse_limit(value).map(|max| config.limits.max_sessions = max),
+                "max-handshakes" => {
+                    parse_positive(value).map(|max| config.limits.max_handshakes = Some(max))
+                }
+                "max-source-handshakes" => {
+                    parse_positive(value).map(|max| config.limits.max_source_handshakes = Some(max))
+                }
+                "max-conn-rate" => parse_limit(value).map(|rate| config.limits.rate = rate),
+                "ban-after" => parse_limit(value).map(|failures| ban_after = failures),
+                "ban-time" => parse_positive(value)
+                    .map(|secs| ban_time = Some(Duration::from_secs(secs as u64))),
+                "push-dns" => IpAddr::from_str(value)
+                    .map(|addr| nameservers.get_or_insert(vec![]).push(addr))
+                    .map_err(|e| format!("{}: {}", value, e)),
+                "push-domain" if value.is_empty() => Err("missing domain".to_string()),
+                "push-domain" => {
+                    search.get_or_insert(vec![]).push(value.to_string());
+                    Ok(())
+                }
+                "listen" => Endpoint::from_str(value)
+                    .map(|endpoint| listen.get_or_insert(vec![]).push(endpoint)),
+                "control" if value.is_empty() => Err("missing path".to_string()),
+                "control" => {
+                    config.startup.control = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "metrics" => parsing::parse_metrics_addr(value)
+                    .map(|addr| config.startup.metrics = Some(addr)),
+                "user" | "group" | "chroot" if value.is_empty() => Err("missing value".to_string()),
+                "user" => {
+                    config.startup.privileges.user = Some(value.to_string());
+                    Ok(())
+                }
+                "group" => {
+                    config.startup.privileges.group = Some(value.to_string());
+                    Ok(())
+                }
+                "chroot" => {
+                    config.startup.privileges.chroot = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                _ => Err(format!("unknown key: {}", key)),
+            };
+        if let Err(err) = ans {
+            return Err(format!("line {}: {}", line, err));
+        }
+    }
+    config.limits.ban = ban_after.map(|failures| BanPolicy {
+        failures,
+        // same default as --ban-time
+        duration: ban_time.unwrap_or(Duration::from_secs(60)),
+    });
+    config.dns = DnsConfig {
+        nameservers: nameservers.unwrap_or(config.dns.nameservers),
+        search: search.unwrap_or(config.dns.search),
+    };
+    if let Some(listen) = listen {
+        config.startup.listen = listen;
+    }
+    // like --group
+    let privileges = &config.startup.privileges;
+    if privileges.group.is_some() && privileges.user.is_none() {
+        return Err("group requires user".to_string());
+    }
+    Ok(config)
+}
+
+pub fn load(path: &Path, base: &Runtime) -> Result<Runtime, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&content, base).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,200 @@
+// Contains the control socket of a running client or server: a Unix
+// domain socket (readable by its owner only) accepting line-delimited
+// JSON requests like {"cmd": "sessions"}. Each request gets one line
+// in response: {"ok": true, ...} or {"ok": false, "error": "..."}.
+// Commands:
+//      sessions                        connected sessions and their counters
+//      kick {ifaddr, reason}           (server) ask a client to exit
+//      bans                            (server) banned source addresses
+//      unban {source}                  (server) lift the ban of source, all if missing
+//      usage {identity}                (server) traffic of a client identity, all if missing
+//      log-level {level}               current log level, changed if given
+// The "ctl" subcommand sends a request and prints the response.
+
+use crate::logging::{self, Level};
+use crate::parsing::{CtlCommand, CtlOpts};
+use crate::session::SessionInfo;
+use crate::transport;
+
+use nix::sys::stat::{self, Mode};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+// longest request accepted
+const MAX_REQUEST_LEN: u64 = 4096;
+
+// Commands specific to the client or the server
+pub trait Handler: Send + Sync {
+    // answer to request cmd, Err with a message if it failed
+    fn handle(&self, cmd: &str, request: &Value) -> Result<Value, String>;
+}
+
+pub fn session_json(session: &SessionInfo) -> Value {
+    let (rx_packets, rx_bytes, tx_packets, tx_bytes) = session.traffic;
+    let metadata = session.metadata.clone().unwrap_or_default();
+    json!({
+        "identity": session.identity,
+        "ifaddr": session.ifaddr.to_string(),
+        "remote": session.remote.to_string(),
+        "uptime": session.uptime.as_secs(),
+        "rx_packets": rx_packets,
+        "rx_bytes": rx_bytes,
+        "tx_packets": tx_packets,
+        "tx_bytes": tx_bytes,
+        "hostname": metadata.hostname,
+        "os": metadata.os,
+        "version": metadata.version,
+        "label": metadata.label,
+    })
+}
+
+// string field of a request, None if missing
+pub fn field<'a>(request: &'a Value, name: &str) -> Result<Option<&'a str>, String> {
+    match request.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(format!("{} must be a string", name)),
+    }
+}
+
+// Response to a single request line
+pub fn respond(handler: &dyn Handler, line: &str) -> Value {
+    let ans = match serde_json::from_str::<Value>(line) {
+        Ok(request) => match request.get("cmd").and_then(Value::as_str) {
+            Some("log-level") => log_level(&request),
+            Some(cmd) => handler.handle(cmd, &request),
+            None => Err("missing cmd".to_string()),
+        },
+        Err(err) => Err(format!("invalid request: {}", err)),
+    };
+    match ans {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert("ok".to_string(), Value::Bool(true));
+            Value::Object(fields)
+        }
+        Ok(_) => json!({ "ok": true }),
+        Err(err) => json!({ "ok": false, "error": err }),
+    }
+}
+
+fn log_level(request: &Value) -> Result<Value, String> {
+    if let Some(level) = field(request, "level")? {
+        let level = Level::from_str(level)?;
+        if level != logging::level() {
+            println!("Log level set to {}", level);
+        }
+        logging::set_level(level);
+    }
+    Ok(json!({ "level": logging::level().to_string() }))
+}
+
+fn serve_connection(handler: &dyn Handler, stream: UnixStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(std::io::Read::take(&stream, MAX_REQUEST_LEN));
+    let mut writer = &stream;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        // limit applies to each line
+        reader.get_mut().set_limit(MAX_REQUEST_LEN);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = respond(handler, line.trim());
+        writeln!(writer, "{}", response)?;
+    }
+}
+
+// Socket file is removed when dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    // Accept connections on path in background, each one is served
+    // by its own thread
+    pub fn spawn(path: &Path, handler: Arc<dyn Handler>) -> std::io::Result<ControlSocket> {
+        transport::remove_stale_socket(&path.to_path_buf())?;
+        // created with mode 0600: never accessible to other users, not
+        // even between bind and chmod
+        let umask = stat::umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(path);
+        stat::umask(umask);
+        let listener = listener?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("Failed to accept on control socket: {}", err);
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = serve_connection(handler.as_ref(), stream) {
+                        eprintln!("Control connection: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Send the command of the "ctl" subcommand, print the response
+pub fn execute_ctl(opts: CtlOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let request = match opts.command {
+        CtlCommand::Sessions => json!({ "cmd": "sessions" }),
+        CtlCommand::Kick { ifaddr, reason } => {
+            json!({ "cmd": "kick", "ifaddr": ifaddr.to_string(), "reason": reason })
+        }
+        CtlCommand::LogLevel { level } => {
+            json!({ "cmd": "log-level", "level": level.map(|l| l.to_string()) })
+        }
+        CtlCommand::Bans => json!({ "cmd": "bans" }),
+        CtlCommand::Unban { source } => {
+            json!({ "cmd": "unban", "source": source.map(|s| s.to_string()) })
+        }
+        CtlCommand::Usage { identity } => json!({ "cmd": "usage", "identity": identity }),
+    };
+    let mut response = send_request(&opts.socket, &request)?;
+    if response.get("ok") != Some(&Value::Bool(true)) {
+        let err = response.get("error").and_then(Value::as_str);
+        return Err(err.unwrap_or("unknown error").into());
+    }
+    if let Some(fields) = response.as_object_mut() {
+        fields.remove("ok");
+        if !fields.is_empty() {
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+    }
+    Ok(())
+}
+
+// Send a request to the control socket at path, return the response
+pub fn send_request(path: &Path, request: &Value) -> Result<Value, Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("cannot connect to {}: {}", path.display(), e))?;
+    writeln!(&stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err("connection closed without response".into());
+    }
+    Ok(serde_json::from_str(&line)?)
+}
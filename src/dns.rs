@@ -0,0 +1,141 @@
+// Contains code for handling DNS settings pushed by the server
+// to the client during the handshake
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::Command;
+
+// DNS settings advertised by the server
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DnsConfig {
+    pub nameservers: Vec<IpAddr>,
+    pub search: Vec<String>,
+}
+
+impl DnsConfig {
+    pub fn is_empty(&self) -> bool {
+        self.nameservers.is_empty() && self.search.is_empty()
+    }
+}
+
+// How the client should apply received DNS settings
+pub enum DnsApply {
+    // only print received settings
+    Ignore,
+    // overwrite a resolv.conf-style file, previous content
+    // is restored when the tunnel goes down
+    File(PathBuf),
+    // invoke executable with "up" or "down" as first argument,
+    // settings are passed as environment variables
+    Hook(PathBuf),
+}
+
+// Keep track of what must be undone when the tunnel goes down
+pub struct DnsGuard {
+    // Some(content) if file existed, None if it has to be removed
+    file: Option<(PathBuf, Option<Vec<u8>>)>,
+    hook: Option<(PathBuf, DnsConfig)>,
+}
+
+const HOOK_ENV_SERVERS: &str = "VPN_DNS_SERVERS";
+const HOOK_ENV_SEARCH: &str = "VPN_DNS_SEARCH";
+
+fn run_hook(
+    hook: &PathBuf,
+    action: &str,
+    dns: &DnsConfig,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let servers: Vec<String> = dns.nameservers.iter().map(|a| a.to_string()).collect();
+    let status = Command::new(hook)
+        .arg(action)
+        .env(HOOK_ENV_SERVERS, servers.join(" "))
+        .env(HOOK_ENV_SEARCH, dns.search.join(" "))
+        .status()?;
+    if !status.success() {
+        let msg = format!("DNS hook {} {} failed: {}", hook.display(), action, status);
+        return Err(msg.into());
+    }
+    Ok(())
+}
+
+fn write_resolv_conf(
+    path: &PathBuf,
+    dns: &DnsConfig,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut file = std::fs::File::create(path)?;
+    writeln!(file, "# generated by rust-tcp-vpn")?;
+    for addr in dns.nameservers.iter() {
+        writeln!(file, "nameserver {}", addr)?;
+    }
+    if !dns.search.is_empty() {
+        writeln!(file, "search {}", dns.search.join(" "))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+// Apply DNS settings received from the server, return guard
+// to be used to restore previous settings
+pub fn apply(
+    how: &DnsApply,
+    dns: &DnsConfig,
+) -> std::result::Result<DnsGuard, Box<dyn std::error::Error>> {
+    let mut guard = DnsGuard {
+        file: None,
+        hook: None,
+    };
+    if dns.is_empty() {
+        return Ok(guard);
+    }
+    match how {
+        DnsApply::Ignore => {
+            println!(
+                "Server pushed DNS settings (ignored): nameservers {:?} search {:?}",
+                dns.nameservers, dns.search
+            );
+        }
+        DnsApply::File(path) => {
+            // backup previous content
+            let previous = match std::fs::read(path) {
+                Ok(content) => Some(content),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            guard.file = Some((path.clone(), previous));
+            if let Err(err) = write_resolv_conf(path, dns) {
+                // do not leave a partially written file behind
+                restore(guard);
+                return Err(err);
+            }
+            println!("DNS settings written to {}", path.display());
+        }
+        DnsApply::Hook(hook) => {
+            run_hook(hook, "up", dns)?;
+            guard.hook = Some((hook.clone(), dns.clone()));
+        }
+    }
+    Ok(guard)
+}
+
+// Restore DNS settings found before apply() was invoked
+pub fn restore(guard: DnsGuard) {
+    if let Some((path, previous)) = guard.file {
+        let ans = match previous {
+            Some(content) => std::fs::write(&path, content),
+            // may not have been created
+            None => match std::fs::remove_file(&path) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                ans => ans,
+            },
+        };
+        if let Err(err) = ans {
+            eprintln!("Cannot restore DNS file {}: {}", path.display(), err);
+        }
+    }
+    if let Some((hook, dns)) = guard.hook {
+        if let Err(err) = run_hook(&hook, "down", &dns) {
+            eprintln!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Contains the packet filter the server applies to the traffic of a
+// client, configured with "filter" lines of the client database:
+//      filter ACTION [PROTO] [from SUBNET] [to SUBNET] [port PORTS]
+// ACTION is allow or deny, PROTO is tcp, udp, icmp or a protocol
+// number, PORTS a port or a range (tcp and udp only). Rules describe
+// packets sent by the client (to is their destination, port their
+// destination port), packets sent to the client match them with
+// addresses and ports swapped, so replies follow the same rules.
+// The first matching rule applies, packets matching no rule are
+// dropped: a client without rules is not filtered.
+
+use crate::acl::Subnet;
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    // packet sent by the client
+    FromClient,
+    // packet sent to the client
+    ToClient,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ports {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for Ports {
+    type Err = String;
+
+    // accept "n" or "n-m"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        match (u16::from_str(first), u16::from_str(last)) {
+            (Ok(first), Ok(last)) if first <= last => Ok(Ports { first, last }),
+            _ => Err(format!("{}: bad port range", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    // any protocol if None
+    pub protocol: Option<u8>,
+    // source of the packets sent by the client
+    pub from: Option<Subnet>,
+    // destination of the packets sent by the client
+    pub to: Option<Subnet>,
+    // destination port of the packets sent by the client
+    pub ports: Option<Ports>,
+}
+
+fn parse_protocol(s: &str) -> Option<u8> {
+    match s {
+        "icmp" => Some(ICMP),
+        "tcp" => Some(TCP),
+        "udp" => Some(UDP),
+        _ => u8::from_str(s).ok(),
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().peekable();
+        let action = match words.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            _ => return Err("rule must start with allow or deny".to_string()),
+        };
+        let mut rule = Rule {
+            action,
+            protocol: None,
+            from: None,
+            to: None,
+            ports: None,
+        };
+        // protocol is optional
+        if let Some(word) = words.peek() {
+            if !["from", "to", "port"].contains(word) {
+                rule.protocol = match parse_protocol(word) {
+                    Some(protocol) => Some(protocol),
+                    None => return Err(format!("{}: unknown protocol", word)),
+                };
+                words.next();
+            }
+        }
+        while let Some(word) = words.next() {
+            let value = match words.next() {
+                Some(value) => value,
+                None => return Err(format!("missing value after {}", word)),
+            };
+            match word {
+                "from" => rule.from = Some(Subnet::from_str(value)?),
+                "to" => rule.to = Some(Subnet::from_str(value)?),
+                "port" => rule.ports = Some(Ports::from_str(value)?),
+                _ => return Err(format!("unexpected {}", word)),
+            }
+        }
+        if rule.ports.is_some() && rule.protocol != Some(TCP) && rule.protocol != Some(UDP) {
+            return Err("port requires tcp or udp".to_string());
+        }
+        Ok(rule)
+    }
+}
+
+// Fields of an IPv4 packet matched by rules
+struct Header {
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    // tcp and udp, not for fragments after the first one
+    ports: Option<(u16, u16)>,
+}
+
+impl Header {
+    fn parse(pkt: &[u8]) -> Option<Header> {
+        if pkt.len() < 20 || pkt[0] >> 4 != 4 {
+            return None;
+        }
+        let ihl = (pkt[0] & 0x0f) as usize * 4;
+        let protocol = pkt[9];
+        let offset = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff;
+        let ports = match protocol {
+            TCP | UDP if offset == 0 && pkt.len() >= ihl + 4 => Some((
+                u16::from_be_bytes([pkt[ihl], pkt[ihl + 1]]),
+                u16::from_be_bytes([pkt[ihl + 2], pkt[ihl + 3]]),
+            )),
+            _ => None,
+        };
+        Some(Header {
+            protocol,
+            src: Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[12..16]).unwrap()),
+            dst: Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[16..20]).unwrap()),
+            ports,
+        })
+    }
+
+    // same packet as if it was sent by the client
+    fn swap(self) -> Header {
+        Header {
+            protocol: self.protocol,
+            src: self.dst,
+            dst: self.src,
+            ports: self.ports.map(|(src, dst)| (dst, src)),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, header: &Header) -> bool {
+        let in_subnet = |net: &Option<Subnet>, addr| net.is_none_or(|net| net.contains(addr));
+        let in_ports = match (self.ports, header.ports) {
+            (None, _) => true,
+            (Some(ports), Some((_, dst))) => ports.first <= dst && dst <= ports.last,
+            (Some(_), None) => false,
+        };
+        self.protocol.is_none_or(|p| p == header.protocol)
+            && in_subnet(&self.from, &header.src)
+            && in_subnet(&self.to, &header.dst)
+            && in_ports
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub rules: Vec<Rule>,
+}
+
+impl Filter {
+    // is the packet allowed? Packets that are not IPv4 are dropped
+    // if there are rules
+    pub fn allows(&self, pkt: &[u8], direction: Direction) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        let header = match (Header::parse(pkt), direction) {
+            (Some(header), Direction::FromClient) => header,
+            (Some(header), Direction::ToClient) => header.swap(),
+            (None, _) => return false,
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&header))
+            .is_some_and(|rule| rule.action == Action::Allow)
+    }
+}
@@ -1,5 +1,28 @@
+use crate::acl::{ClientConfig, SharedConfig};
+use crate::codec::{self, Frame};
+use crate::filter::Direction;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::session::Session;
+use crate::transport::Stream;
+use crate::{debug, info};
+
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::sync::Mutex;
+
+// Sent to the thread writing frames to a client of the server
+pub enum Outgoing {
+    // packet read from the virtual interface
+    Packet(Vec<u8>),
+    // counter of last data packet received
+    Ack(u64),
+    // send exit packet with the given reason and close connection
+    Exit(u32),
+    // connection is over: stop without sending anything
+    Close,
+}
 
 enum Status {
     // continue
@@ -8,24 +31,88 @@ enum Status {
     ExitOk,
 }
 
+fn write_frame(
+    stream: &mut impl std::io::Write,
+    frame: &Frame,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut buf = Vec::with_capacity(16);
+    codec::encode_frame(frame, &mut buf)?;
+    stream.write_all(&buf)?;
+    metrics::frame_sent(frame);
+    Ok(())
+}
+
 fn send_exit_pkt(
     stream: &mut impl std::io::Write,
     exit_reason: u32,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    // build packet
-    // exit packet: type 2
-    stream.write_all(&2_u32.to_be_bytes())?;
     // exit reason, only 0 in currently valid
-    stream.write_all(&exit_reason.to_be_bytes())?;
+    write_frame(
+        stream,
+        &Frame::Exit {
+            reason: exit_reason,
+        },
+    )?;
     // send packet
     stream.flush()?;
     Ok(())
 }
 
+fn send_ack_pkt(
+    stream: &mut impl std::io::Write,
+    counter: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    // counter of last data packet received
+    write_frame(stream, &Frame::Ack { counter })?;
+    // send packet
+    stream.flush()?;
+    Ok(())
+}
+
+fn write_data_pkt(
+    stream: &mut impl std::io::Write,
+    counter: u64,
+    pkt: &[u8],
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    write_frame(
+        stream,
+        &Frame::Data {
+            counter,
+            packet: pkt,
+        },
+    )
+}
+
+// Send again data packets not received by the remote endpoint
+// before the connection was lost, remote_received is the counter
+// of the last data packet it received
+pub fn resume_session(
+    stream: &mut impl std::io::Write,
+    session: &mut Session,
+    remote_received: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    session.on_ack(remote_received);
+    let mut ostream = BufWriter::with_capacity(64 + 4096, stream);
+    let mut count = 0;
+    for (counter, pkt) in session.pending() {
+        write_data_pkt(&mut ostream, *counter, pkt)?;
+        count += 1;
+    }
+    ostream.flush()?;
+    info!("Session resumed, {} packets retransmitted", count);
+    Ok(())
+}
+
+// Is the error caused by the TCP connection? If so the session
+// can be resumed with a new connection
+pub fn is_connection_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<std::io::Error>().is_some()
+}
+
 fn handle_local2remote_pkt(
     iffile: &mut std::fs::File,
     stream: &mut impl std::io::Write,
-    counter: &mut u64,
+    session: &mut Session,
     buffer: &mut [u8],
 ) -> Result<Status, Box<dyn std::error::Error>> {
     // packet is always fully read (if possible):
@@ -36,57 +123,80 @@ fn handle_local2remote_pkt(
             // should never happens!
             panic!("UNEXPECTED EMPTY PACKET from Virtual interface!");
         }
-        Ok(sz) => {
-            // new packet
-            *counter += 1;
-            sz
-        }
+        Ok(sz) => sz,
         Err(err) => {
             // should never happens!
             eprintln!("Error reading from virtual interface: {}", err);
             std::process::exit(1)
         }
     };
-    // build packet
-    // data packet: type 1
-    stream.write_all(&1_u32.to_be_bytes())?;
-    // pkt length
-    stream.write_all(&(sz as u32).to_be_bytes())?;
-    // counter
-    stream.write_all(&counter.to_be_bytes())?;
-    // network packet
-    stream.write_all(&buffer[..sz])?;
+    // new packet
+    let counter = session.on_send(&buffer[..sz]);
+    metrics::packet_sent(sz);
+    write_data_pkt(stream, counter, &buffer[..sz])?;
     // send packet
     stream.flush()?;
     // Everything Ok, continue
     Ok(Status::Continue)
 }
 
+// Check restrictions configured for the remote client (if any):
+// source address must be the client one or in subnets behind it,
+// the packet filter must allow the packet and rate limit must not
+// be exceeded
+fn accept_remote_pkt(
+    pkt: &[u8],
+    client: Option<&ClientConfig>,
+    limiter: &mut Option<RateLimiter>,
+) -> bool {
+    let client = match client {
+        Some(client) => client,
+        None => return true,
+    };
+    // only IPv4 is supported
+    if pkt.len() < 20 || pkt[0] >> 4 != 4 {
+        return false;
+    }
+    let src = Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[12..16]).unwrap());
+    let dropped = if !client.allowed_source(&src) {
+        "source address not allowed"
+    } else if !client.filter.allows(pkt, Direction::FromClient) {
+        "denied by filter"
+    } else if limiter.as_mut().is_some_and(|l| !l.allow(pkt.len() as u64)) {
+        "rate limit exceeded"
+    } else {
+        return true;
+    };
+    debug!("Packet from {} dropped: {}", client.identity, dropped);
+    false
+}
+
 fn handle_remote2local_pkt(
-    iffile: &mut std::fs::File,
-    stream: &mut impl std::io::BufRead,
-    buffer: &mut [u8],
+    iffile: &mut impl std::io::Write,
+    frame: Frame,
+    session: &mut Session,
+    client: Option<&ClientConfig>,
+    limiter: &mut Option<RateLimiter>,
 ) -> std::result::Result<Status, Box<dyn std::error::Error>> {
-    // read packet type
-    let mut pkt_type: [u8; 4] = [0; 4];
-    stream.read_exact(&mut pkt_type)?;
-    let pkt_type = u32::from_be_bytes(pkt_type);
-    match pkt_type {
-        1 => {
-            let mut pkt_len: [u8; 4] = [0; 4];
-            stream.read_exact(&mut pkt_len)?;
-            let pkt_len: u32 = u32::from_be_bytes(pkt_len);
-            //println!("pkt_len = {}", pkt_len);
-            let mut counter: [u8; 8] = [0; 8];
-            stream.read_exact(&mut counter)?;
-            let _counter = u64::from_be_bytes(counter);
-            // counter is unused now
-            stream.read_exact(&mut buffer[0..(pkt_len as usize)])?;
+    metrics::frame_received(&frame);
+    match frame {
+        Frame::Data { counter, packet } => {
+            if !session.on_receive(counter) {
+                // already received before connection was lost
+                return Ok(Status::Continue);
+            }
+            session.traffic.on_receive(packet.len());
+            metrics::packet_received(packet.len());
+            if !accept_remote_pkt(packet, client, limiter) {
+                // silently drop packet
+                return Ok(Status::Continue);
+            }
             // https://doc.rust-lang.org/std/fs/struct.File.html#method.write_all_at-1
-            match iffile.write_all(&buffer[0..(pkt_len as usize)]) {
+            match iffile.write_all(packet) {
                 // Everything Ok, continue
                 Ok(()) => Ok(Status::Continue),
                 Err(e) => {
+                    metrics::tun_write_failed();
                     let msg = format!("Failed to write pkt to virtual interface due to: {}", e);
                     Err(msg.into())
                 }
@@ -94,21 +204,21 @@ fn handle_remote2local_pkt(
             // it does not seem possible to flush virtual interface fd
             //iffile.flush().unwrap();
         }
-        2 => {
-            let mut exit_reason: [u8; 4] = [0; 4];
-            stream.read_exact(&mut exit_reason)?;
-            let exit_reason: u32 = u32::from_be_bytes(exit_reason);
-            if exit_reason != 0 {
-                let msg = format!("Unknown exit reason code {} in VPN protocol", exit_reason);
+        Frame::Exit { reason } => match reason {
+            // terminate VPN protocol
+            codec::EXIT_NORMAL => Ok(Status::ExitOk),
+            // not a connection error: do not try to resume
+            codec::EXIT_DUPLICATE => Err("Session replaced by a new one of the same client".into()),
+            codec::EXIT_KICKED => Err("Session closed by server administrator".into()),
+            codec::EXIT_QUOTA => Err("Traffic quota exceeded".into()),
+            _ => {
+                let msg = format!("Unknown exit reason code {} in VPN protocol", reason);
                 Err(msg.into())
-            } else {
-                // terminate VPN protocol
-                Ok(Status::ExitOk)
             }
-        }
-        _ => {
-            let msg = format!("Unknown packet type: {} (only 1 valid)", pkt_type);
-            Err(msg.into())
+        },
+        Frame::Ack { counter } => {
+            session.on_ack(counter);
+            Ok(Status::Continue)
         }
     }
 }
@@ -122,18 +232,22 @@ fn handle_remote2local_pkt(
 // it exits because of local signal
 //
 // Return Err in case of other errors
+//
+// session keeps packet counters, it survives the connection
+// if the session is resumed
 pub fn handle_flow(
     stream: &mut TcpStream,
     iffile: &mut std::fs::File,
     sigfile: &mut std::fs::File,
+    session: &mut Session,
 ) -> std::result::Result<bool, Box<dyn std::error::Error>> {
     // buffer
-    let mut buffer: [u8; 4096] = [0; 4096];
+    let mut buffer: [u8; codec::MAX_PACKET_LEN] = [0; codec::MAX_PACKET_LEN];
+    // frames received from remote endpoint
+    let mut frame_buf = Vec::with_capacity(64 + codec::MAX_PACKET_LEN);
     // split both socket ends
     let mut ostream = BufWriter::with_capacity(64 + 4096, stream.try_clone()?);
     let mut istream = BufReader::with_capacity(64 + 4096, stream.try_clone()?);
-    // count how many packets are sent?
-    let mut counter: u64 = 0;
 
     loop {
         use nix::poll::PollFd;
@@ -165,8 +279,7 @@ pub fn handle_flow(
             // consume pending signal data
             crate::signals::consume_sigpipe(sigfile);
             // send exit packet
-            let exit_reason = 0_u32; // normal exit
-            if let Err(err) = send_exit_pkt(&mut ostream, exit_reason) {
+            if let Err(err) = send_exit_pkt(&mut ostream, codec::EXIT_NORMAL) {
                 eprintln!(
                     "Anomalous error occurred while sending exit packet: {}",
                     err
@@ -197,10 +310,12 @@ pub fn handle_flow(
         // check interface
         if tcp_flag {
             loop {
-                if let Status::ExitOk = handle_remote2local_pkt(iffile, &mut istream, &mut buffer)?
+                let frame = codec::read_frame(&mut istream, &mut frame_buf)?;
+                if let Status::ExitOk =
+                    handle_remote2local_pkt(iffile, frame, session, None, &mut None)?
                 {
                     // remote endpoint exited
-                    println!("Remote exit!");
+                    info!("Remote exit!");
                     return Ok(true);
                 }
                 // https://doc.rust-lang.org/std/io/struct.BufReader.html#method.buffer
@@ -208,9 +323,120 @@ pub fn handle_flow(
                     break;
                 }
             }
+            if session.ack_needed() {
+                send_ack_pkt(&mut ostream, session.received)?;
+                session.on_ack_sent();
+            }
         }
         if if_flag {
-            handle_local2remote_pkt(iffile, &mut ostream, &mut counter, &mut buffer)?;
+            handle_local2remote_pkt(iffile, &mut ostream, session, &mut buffer)?;
+        }
+    }
+}
+
+// Server side: write frames queued for a client until Exit or Close
+// is received, or until the connection fails. The socket is shut
+// down on return so that the reader of the connection stops too.
+// Packets denied by the filter of the client (if any) are dropped.
+//
+// Return the exit reason if the client has been asked to exit
+pub fn client_writer(
+    stream: &Stream,
+    rx: Receiver<Outgoing>,
+    session: &Mutex<Session>,
+    client: Option<&SharedConfig>,
+) -> Option<u32> {
+    let denied =
+        |pkt: &[u8]| client.is_some_and(|c| !c.get().filter.allows(pkt, Direction::ToClient));
+    let mut ostream = BufWriter::with_capacity(64 + 4096, stream);
+    let mut next = rx.recv().ok();
+    let mut exit = None;
+    while let Some(msg) = next {
+        let ans = match msg {
+            Outgoing::Packet(pkt) if denied(&pkt) => {
+                debug!("Packet to client dropped: denied by filter");
+                Ok(())
+            }
+            Outgoing::Packet(pkt) => {
+                let counter = session.lock().unwrap().on_send(&pkt);
+                metrics::packet_sent(pkt.len());
+                write_data_pkt(&mut ostream, counter, &pkt)
+            }
+            Outgoing::Ack(counter) => write_frame(&mut ostream, &Frame::Ack { counter }),
+            Outgoing::Exit(reason) => {
+                if let Err(err) = send_exit_pkt(&mut ostream, reason) {
+                    eprintln!(
+                        "Anomalous error occurred while sending exit packet: {}",
+                        err
+                    );
+                }
+                exit = Some(reason);
+                break;
+            }
+            Outgoing::Close => return None,
+        };
+        if ans.is_err() {
+            break;
+        }
+        // flush only when nothing else is queued
+        next = match rx.try_recv() {
+            Ok(msg) => Some(msg),
+            Err(TryRecvError::Empty) => {
+                if ostream.flush().is_err() {
+                    break;
+                }
+                rx.recv().ok()
+            }
+            Err(TryRecvError::Disconnected) => None,
+        };
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    exit
+}
+
+// Server side: read frames sent by a client and write its packets to
+// output (one packet per write, like the virtual interface), acks are
+// queued to the writer of the connection through tx.
+//
+// Return Ok if the client sent an exit packet, Err otherwise
+//
+// client contains restrictions to apply to packets received from
+// the client, if any, they can change while the client is connected
+pub fn client_reader(
+    stream: &Stream,
+    output: &mut impl std::io::Write,
+    client: Option<&SharedConfig>,
+    session: &Mutex<Session>,
+    tx: &SyncSender<Outgoing>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut istream = BufReader::with_capacity(64 + 4096, stream);
+    let mut frame_buf = Vec::with_capacity(64 + codec::MAX_PACKET_LEN);
+    // traffic allowed from remote client
+    let mut rate = client.and_then(|c| c.get().rate);
+    let mut limiter = rate.map(RateLimiter::new);
+    loop {
+        // session is not locked while waiting for the client
+        let frame = codec::read_frame(&mut istream, &mut frame_buf)?;
+        let config = client.map(SharedConfig::get);
+        // rate changed by a reload
+        let new_rate = config.as_ref().and_then(|c| c.rate);
+        if new_rate != rate {
+            rate = new_rate;
+            limiter = rate.map(RateLimiter::new);
+        }
+        let mut session = session.lock().unwrap();
+        if let Status::ExitOk =
+            handle_remote2local_pkt(output, frame, &mut session, config.as_deref(), &mut limiter)?
+        {
+            return Ok(());
+        }
+        if session.ack_needed() {
+            session.on_ack_sent();
+            let received = session.received;
+            // writer needs the session: release it before queuing
+            drop(session);
+            // writer has stopped if send fails, next read fails too
+            let _ = tx.send(Outgoing::Ack(received));
         }
     }
 }
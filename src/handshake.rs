@@ -1,190 +1,992 @@
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use crate::acl::{ClientConfig, ClientDb};
+use crate::certauth::{self, CertVerifier, Credentials, Role, CHALLENGE_LEN};
+use crate::codec::{self, CodecError, Extension, HandshakeMsg};
+use crate::dns::DnsConfig;
+use crate::metadata::ClientMetadata;
+use crate::metrics;
+use crate::session::{Conflict, Resume, Sessions, Ticket, TICKET_LEN};
+use crate::totp;
 
-const MAGIC: u32 = 0x12345678;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::RwLock;
+
+// Optional data is carried at the end of some handshake packets
+// as a block of extensions (see codec), unknown extension kinds
+// are ignored
+
+// DNS resolver address: 4 (IPv4) or 16 (IPv6) bytes
+const EXT_DNS_SERVER: u16 = 1;
+// DNS search domain: UTF-8 string
+const EXT_DNS_SEARCH: u16 = 2;
+// client identity: UTF-8 string
+const EXT_IDENTITY: u16 = 3;
+// resumption ticket issued by the server: TICKET_LEN bytes
+const EXT_TICKET: u16 = 4;
+// client resumption request: ticket followed by the
+// counter of last data packet received (u64)
+const EXT_RESUME: u16 = 5;
+// server accepted resumption: counter of last data
+// packet received (u64)
+const EXT_RESUMED: u16 = 6;
+// certificate of the sender: DER bytes
+const EXT_CERTIFICATE: u16 = 7;
+// random bytes to be signed by the receiver: CHALLENGE_LEN bytes
+const EXT_CHALLENGE: u16 = 8;
+// signature of the challenge of the receiver made with the sender
+// certificate key
+const EXT_SIGNATURE: u16 = 9;
+// client description: versioned, see metadata
+const EXT_METADATA: u16 = 10;
+// server requires a one-time password: empty
+const EXT_TOTP_CHALLENGE: u16 = 11;
+// one-time password typed by the user: u32
+const EXT_TOTP_CODE: u16 = 12;
+
+fn dns_to_extensions(dns: &DnsConfig) -> Vec<Extension> {
+    let mut extensions = vec![];
+    for addr in dns.nameservers.iter() {
+        let value = match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        extensions.push(Extension {
+            kind: EXT_DNS_SERVER,
+            value,
+        });
+    }
+    for domain in dns.search.iter() {
+        extensions.push(Extension {
+            kind: EXT_DNS_SEARCH,
+            value: domain.as_bytes().to_vec(),
+        });
+    }
+    extensions
+}
+
+// return None if some DNS extension is malformed
+fn dns_from_extensions(extensions: &[Extension]) -> Option<DnsConfig> {
+    let mut dns = DnsConfig::default();
+    for ext in extensions.iter() {
+        match ext.kind {
+            EXT_DNS_SERVER => {
+                let addr = if let Ok(octets) = <[u8; 4]>::try_from(&ext.value[..]) {
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                } else if let Ok(octets) = <[u8; 16]>::try_from(&ext.value[..]) {
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                } else {
+                    eprintln!(
+                        "HANDSHAKE error, bad DNS server length: {}",
+                        ext.value.len()
+                    );
+                    return None;
+                };
+                dns.nameservers.push(addr);
+            }
+            EXT_DNS_SEARCH => match String::from_utf8(ext.value.clone()) {
+                Ok(domain) => dns.search.push(domain),
+                Err(_) => {
+                    eprintln!("HANDSHAKE error, DNS search domain is not UTF-8");
+                    return None;
+                }
+            },
+            _ => {}
+        }
+    }
+    Some(dns)
+}
+
+// Any endpoint can abort the handshake sending a reject packet
+// in place of the expected one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    Unknown = 0,
+    BadMagic = 1,
+    UnexpectedPacket = 2,
+    NetmaskMismatch = 3,
+    BadAddress = 4,
+    MalformedPacket = 5,
+    UnknownIdentity = 6,
+    AccessDenied = 7,
+    AuthFailed = 8,
+    DuplicateSession = 9,
+}
+
+impl RejectReason {
+    pub fn from_code(code: u32) -> RejectReason {
+        match code {
+            1 => RejectReason::BadMagic,
+            2 => RejectReason::UnexpectedPacket,
+            3 => RejectReason::NetmaskMismatch,
+            4 => RejectReason::BadAddress,
+            5 => RejectReason::MalformedPacket,
+            6 => RejectReason::UnknownIdentity,
+            7 => RejectReason::AccessDenied,
+            8 => RejectReason::AuthFailed,
+            9 => RejectReason::DuplicateSession,
+            _ => RejectReason::Unknown,
+        }
+    }
+
+    // rejects caused by a forged or wrong client, counted toward
+    // bans, rather than by a misconfigured one
+    fn is_failure(self) -> bool {
+        matches!(
+            self,
+            RejectReason::BadMagic
+                | RejectReason::MalformedPacket
+                | RejectReason::AuthFailed
+                | RejectReason::UnknownIdentity
+                | RejectReason::BadAddress
+        )
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            RejectReason::Unknown => "unknown reason",
+            RejectReason::BadMagic => "bad magic",
+            RejectReason::UnexpectedPacket => "unexpected packet",
+            RejectReason::NetmaskMismatch => "netmask mismatch",
+            RejectReason::BadAddress => "bad address",
+            RejectReason::MalformedPacket => "malformed packet",
+            RejectReason::UnknownIdentity => "unknown identity",
+            RejectReason::AccessDenied => "access denied",
+            RejectReason::AuthFailed => "authentication failed",
+            RejectReason::DuplicateSession => "duplicate session",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+fn format_reject(reason: RejectReason, text: &str) -> String {
+    if text.is_empty() {
+        reason.to_string()
+    } else {
+        format!("{} ({})", reason, text)
+    }
+}
+
+// Why a handshake step failed
+#[derive(Debug)]
+pub enum Failure {
+    // peer is refused: a reject packet must be sent to it
+    Reject(RejectReason, String),
+    // peer conflicts with a connected session: a reject packet must
+    // be sent to it, but it is not at fault
+    Conflict(RejectReason, String),
+    // peer refused us sending a reject packet
+    Rejected(RejectReason, String),
+    // peer gave up without a reason
+    Aborted(String),
+    // local or I/O error
+    Error(Box<dyn std::error::Error>),
+}
+
+impl Failure {
+    fn reject(reason: RejectReason, text: &str) -> Failure {
+        Failure::Reject(reason, text.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for Failure {
+    fn from(err: Box<dyn std::error::Error>) -> Failure {
+        Failure::Error(err)
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Reject(reason, text) | Failure::Conflict(reason, text) => {
+                write!(f, "{}", format_reject(*reason, text))
+            }
+            Failure::Rejected(reason, text) => {
+                write!(f, "peer rejected: {}", format_reject(*reason, text))
+            }
+            Failure::Aborted(text) => write!(f, "{}", text),
+            Failure::Error(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+fn rejected(reason: u32, text: String) -> Failure {
+    Failure::Rejected(RejectReason::from_code(reason), text)
+}
+
+fn ipv4_only(addr: &IpAddr) -> Ipv4Addr {
+    match addr {
+        IpAddr::V4(addr) => *addr,
+        _ => {
+            eprintln!("Cannot accept IPv6");
+            std::process::exit(1)
+        }
+    }
+}
+
+// classic netmask
+fn netmask_bits(netmask: u8) -> u32 {
+    (!0) ^ ((1 << (32 - netmask)) - 1)
+}
+
+// Server properties and policies used during the handshake
+pub struct ServerSettings {
+    pub ifaddr: IpAddr,
+    pub netmask: u8,
+    // settings pushed to clients, can change while running
+    pub dns: RwLock<DnsConfig>,
+    // client database, if any
+    pub clients: Option<ClientDb>,
+    // required if clients must authenticate with certificates
+    pub verifier: Option<CertVerifier>,
+    // certificate and key presented to clients asking for them
+    pub credentials: Option<Credentials>,
+}
+
+// Client properties used during the handshake
+pub struct ClientSettings {
+    pub ifaddr: IpAddr,
+    pub netmask: u8,
+    pub identity: Option<String>,
+    // certificate and key, if any
+    pub credentials: Option<Credentials>,
+    // required if the server must authenticate with a certificate
+    pub verifier: Option<CertVerifier>,
+    // reported to the server, if any
+    pub metadata: Option<ClientMetadata>,
+}
+
+// Properties of the client obtained by the server during the handshake
+pub struct ClientInfo {
+    pub ifaddr: Ipv4Addr,
+    pub identity: Option<String>,
+    // settings found in client database, if any
+    pub config: Option<ClientConfig>,
+    // verified certificate, if any
+    pub cert: Option<Vec<u8>>,
+    // reported by the client, if any
+    pub metadata: Option<ClientMetadata>,
+    // Some if parked session has been resumed: counter of
+    // last data packet received by client
+    pub resumed: Option<u64>,
+}
+
+// Properties of the server obtained by the client during the handshake
+pub struct ServerInfo {
+    pub ifaddr: Ipv4Addr,
+    // certificate subject common name, if verified
+    pub identity: Option<String>,
+    pub dns: DnsConfig,
+    // ticket to be used to resume the session
+    pub ticket: Option<Ticket>,
+    // Some if session has been resumed: counter of last
+    // data packet received by server
+    pub resumed: Option<u64>,
+}
 
 // INITIAL HANDSHAKE:
-//      1. client send packet containing (ifaddr,netmask)
-//      2. server check received packet from client
-//      3. server sends its ifaddr
-//      4. client double check server if properties and send OK to server
+//      1. client send packet containing (ifaddr,netmask), identity,
+//         certificate, metadata and, if configured, a challenge for the
+//         server certificate
+//      2. server check received packet from client and, if configured,
+//         client certificate and client database
+//      3. server sends its ifaddr, pushed settings (DNS), resumption
+//         ticket, its certificate and challenge signature (if asked),
+//         challenge for client certificate authentication and, if
+//         configured for the client, one-time password challenge
+//      4. client double check server if properties and certificate,
+//         prompts user for the one-time password (if asked) and send OK
+//         to server along with challenge signature and one-time password
+// Steps 2, 4 and 6 can fail: the failing endpoint sends a reject
+// packet with the cause before closing the connection
 //      5. client can now bring interface UP
 //      6. server receive Ok from client
 //      7. server can now bring interface UP
 //      8. server and client can now exchange packets
-pub fn handler_server_handshake(
-    stream: &mut TcpStream,
-    ifaddr: &IpAddr,
-    netmask: u8,
-) -> std::result::Result<bool, Box<dyn std::error::Error>> {
-    let ifaddr: &Ipv4Addr = match ifaddr {
-        IpAddr::V4(addr) => addr,
-        _ => {
-            eprintln!("Cannot accept IPv6");
-            std::process::exit(1)
-        }
-    };
-    // https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.try_clone
-    let ostream = stream.try_clone()?;
-    // https://doc.rust-lang.org/std/io/struct.BufWriter.html#method.with_capacity
-    let mut ostream = BufWriter::with_capacity(64, ostream);
-    // read stream
-    let istream = stream.try_clone()?;
-    let mut istream = BufReader::with_capacity(64, istream);
-
-    // classic netmask
-    let netmask: u32 = (!0) ^ ((1 << (32 - netmask)) - 1);
-    // local addr
-    let local_addr: u32 = u32::from_be_bytes(ifaddr.octets());
-    // 2. parse first packet
-    {
-        let mut packet1: [u8; 16] = [0; 16];
-        // https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-        istream.read_exact(&mut packet1)?;
-        // check magic
-        // https://doc.rust-lang.org/std/primitive.slice.html#method.split_at
-        let found_magick = u32::from_be_bytes(packet1[..4].try_into().unwrap());
-        if MAGIC != found_magick {
-            eprintln!(
-                "HANDSHAKE error, magic: {} instead of {}",
-                found_magick, MAGIC
-            );
-            return Ok(false);
-        }
-        // check packet id: should be 1
-        let pktid = u32::from_be_bytes(packet1[4..8].try_into().unwrap());
-        if 1 != pktid {
-            eprintln!("HANDSHAKE error, pktid: {} instead of {}", pktid, 1);
-            return Ok(false);
+//
+// Each endpoint is a state machine: every state consumes the message
+// received from the peer and produces the next state along with the
+// message to be sent, no I/O is performed. Server states:
+//      ServerStart -> ServerHelloSent -> ClientInfo
+// Client states:
+//      ClientStart -> ClientHelloSent -> ServerHelloReceived -> ServerInfo
+
+// Server waiting for client hello
+pub struct ServerStart<'a> {
+    settings: &'a ServerSettings,
+    // issued to the client if resumption is enabled
+    ticket: Option<Ticket>,
+    // connected clients and parked sessions
+    sessions: &'a dyn Sessions,
+}
+
+// Server waiting for client confirm
+pub struct ServerHelloSent<'a> {
+    clients: Option<&'a ClientDb>,
+    info: ClientInfo,
+    // to be signed with the key of the client certificate
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+    totp_required: bool,
+}
+
+impl<'a> ServerStart<'a> {
+    pub fn new(
+        settings: &'a ServerSettings,
+        ticket: Option<Ticket>,
+        sessions: &'a dyn Sessions,
+    ) -> ServerStart<'a> {
+        ServerStart {
+            settings,
+            ticket,
+            sessions,
         }
-        // get remote address and netmask
-        let remote_addr = u32::from_be_bytes(packet1[8..12].try_into().unwrap());
-        let remote_netmask = u32::from_be_bytes(packet1[12..16].try_into().unwrap());
+    }
+
+    // 2. check client hello, return server hello
+    pub fn receive_hello(
+        self,
+        msg: HandshakeMsg,
+    ) -> std::result::Result<(ServerHelloSent<'a>, HandshakeMsg), Failure> {
+        let settings = self.settings;
+        let sessions = self.sessions;
+        let clients = settings.clients.as_ref();
+        let ifaddr = ipv4_only(&settings.ifaddr);
+        let netmask = netmask_bits(settings.netmask);
+        let local_addr = u32::from(ifaddr);
+        let (remote_addr, remote_netmask, extensions) = match msg {
+            HandshakeMsg::ClientHello {
+                ifaddr,
+                netmask,
+                extensions,
+            } => (u32::from(ifaddr), netmask, extensions),
+            HandshakeMsg::Reject { reason, text } => return Err(rejected(reason, text)),
+            _ => {
+                let text = "client hello expected";
+                return Err(Failure::reject(RejectReason::UnexpectedPacket, text));
+            }
+        };
         // check netmask
         if netmask != remote_netmask {
-            eprintln!(
-                "HANDSHAKE error, netmask: {} instead of {}",
-                remote_netmask, netmask
+            let text = format!(
+                "server /{}, you /{}",
+                netmask.count_ones(),
+                remote_netmask.count_ones()
             );
-            return Ok(false);
+            return Err(Failure::Reject(RejectReason::NetmaskMismatch, text));
         }
         // check addresse: should not be equals but in the same subnet
         if !((local_addr & netmask == remote_addr & netmask) && (local_addr != remote_addr)) {
-            eprintln!(
-                "HANDSHAKE error, address: local {:#08x} remote {:#08x}",
-                local_addr, remote_addr
+            let text = format!(
+                "server {}/{}, you {}",
+                ifaddr,
+                netmask.count_ones(),
+                Ipv4Addr::from(remote_addr)
             );
-            return Ok(false);
-        }
-    }
-    // 3. send server ifaddr
-    {
-        // packet id: 2
-        ostream.write_all(&2_u32.to_be_bytes())?;
-        // server interface address
-        ostream.write_all(&local_addr.to_be_bytes())?;
-        // send packet
-        ostream.flush()?;
-    }
-    // 5 check client response
-    {
-        let mut packet3: [u8; 8] = [0; 8];
-        // read packet
-        istream.read_exact(&mut packet3)?;
-        let pktid = u32::from_be_bytes(packet3[..4].try_into().unwrap());
-        if 3 != pktid {
-            eprintln!("HANDSHAKE error, pktid: {} instead of {}", pktid, 3);
-            return Ok(false);
-        }
-        let status = u32::from_be_bytes(packet3[4..8].try_into().unwrap());
-        if status != 0 {
-            eprintln!(
-                "HANDSHAKE error, client status: {} instead of {}",
-                status, 0
-            );
-            return Ok(false);
+            return Err(Failure::Reject(RejectReason::BadAddress, text));
+        }
+        // optional data
+        let mut identity = None;
+        let mut resume = None;
+        let mut cert = None;
+        let mut metadata = None;
+        let mut server_challenge = None;
+        for ext in extensions.iter() {
+            match ext.kind {
+                EXT_CERTIFICATE => cert = Some(ext.value.clone()),
+                EXT_CHALLENGE if ext.value.len() == CHALLENGE_LEN => {
+                    server_challenge = Some(ext.value.clone());
+                }
+                // unknown versions are ignored
+                EXT_METADATA => metadata = ClientMetadata::decode(&ext.value),
+                EXT_IDENTITY => match String::from_utf8(ext.value.clone()) {
+                    Ok(name) => identity = Some(name),
+                    Err(_) => {
+                        return Err(Failure::reject(RejectReason::MalformedPacket, "identity"));
+                    }
+                },
+                EXT_RESUME => {
+                    if ext.value.len() != TICKET_LEN + 8 {
+                        return Err(Failure::reject(RejectReason::MalformedPacket, "resume"));
+                    }
+                    resume = Some(Resume {
+                        ticket: ext.value[..TICKET_LEN].try_into().unwrap(),
+                        received: u64::from_be_bytes(ext.value[TICKET_LEN..].try_into().unwrap()),
+                    });
+                }
+                _ => {}
+            }
+        }
+        let remote_addr = Ipv4Addr::from(remote_addr);
+        // check certificate: identity is its subject
+        if let Some(verifier) = &settings.verifier {
+            let cn = match cert
+                .as_ref()
+                .map(|cert| verifier.verify(cert, Role::Client))
+            {
+                Some(Ok(cn)) => cn,
+                Some(Err(text)) => return Err(Failure::Reject(RejectReason::AuthFailed, text)),
+                None => {
+                    let text = "certificate required";
+                    return Err(Failure::reject(RejectReason::AuthFailed, text));
+                }
+            };
+            if let Some(name) = &identity {
+                if *name != cn {
+                    let text = format!("identity {} instead of {}", name, cn);
+                    return Err(Failure::Reject(RejectReason::AuthFailed, text));
+                }
+            }
+            identity = Some(cn);
         }
+        // check client database
+        let config = match clients {
+            None => None,
+            Some(clients) => {
+                let identity = match &identity {
+                    Some(identity) => identity,
+                    None => {
+                        return Err(Failure::reject(RejectReason::UnknownIdentity, "missing"));
+                    }
+                };
+                let config = match clients.lookup(identity)? {
+                    Some(config) => config,
+                    None => return Err(Failure::reject(RejectReason::UnknownIdentity, identity)),
+                };
+                if !config.enabled {
+                    let text = "client disabled";
+                    return Err(Failure::reject(RejectReason::AccessDenied, text));
+                }
+                if config.ifaddr != remote_addr {
+                    let text = format!("{} is assigned {}", identity, config.ifaddr);
+                    return Err(Failure::Reject(RejectReason::BadAddress, text));
+                }
+                Some(config)
+            }
+        };
+        // tunnel address can be used by a single client, identity
+        // too depending on the duplicate session policy
+        match sessions.conflict(remote_addr, identity.as_deref()) {
+            Some(Conflict::Address) => {
+                let text = format!("{} already connected", remote_addr);
+                return Err(Failure::Conflict(RejectReason::BadAddress, text));
+            }
+            Some(Conflict::Identity) => {
+                let name = identity.as_deref().unwrap_or_default();
+                let text = format!("{} already connected", name);
+                return Err(Failure::Conflict(RejectReason::DuplicateSession, text));
+            }
+            None => {}
+        }
+        // parked session can be resumed only by the same client:
+        // (client resume request, counter of last packet received)
+        let parked = match resume {
+            Some(resume) => sessions
+                .parked(&resume.ticket, remote_addr)
+                .map(|received| (resume, received)),
+            None => None,
+        };
+        let resumed = parked.as_ref().map(|(resume, _)| resume.received);
+        // second factor is not asked again when resuming a session
+        let totp_required = match &config {
+            Some(config) => config.totp.is_some() && resumed.is_none(),
+            None => false,
+        };
+        // client must sign the challenge with certificate key
+        let challenge = match settings.verifier {
+            Some(_) => Some(certauth::new_challenge()?),
+            None => None,
+        };
+        // 3. server hello: pushed settings
+        let mut extensions = dns_to_extensions(&settings.dns.read().unwrap());
+        // resumed session keeps its ticket
+        let ticket = match parked {
+            Some((resume, received)) => {
+                extensions.push(Extension {
+                    kind: EXT_RESUMED,
+                    value: received.to_be_bytes().to_vec(),
+                });
+                Some(resume.ticket)
+            }
+            None => self.ticket,
+        };
+        if let Some(ticket) = ticket {
+            extensions.push(Extension {
+                kind: EXT_TICKET,
+                value: ticket.to_vec(),
+            });
+        }
+        if let Some(challenge) = challenge {
+            extensions.push(Extension {
+                kind: EXT_CHALLENGE,
+                value: challenge.to_vec(),
+            });
+        }
+        // prove ownership of the server certificate, if asked
+        if let (Some(challenge), Some(credentials)) = (server_challenge, &settings.credentials) {
+            extensions.push(Extension {
+                kind: EXT_CERTIFICATE,
+                value: credentials.cert.clone(),
+            });
+            extensions.push(Extension {
+                kind: EXT_SIGNATURE,
+                value: credentials.sign(&challenge, Role::Server)?,
+            });
+        }
+        if totp_required {
+            extensions.push(Extension {
+                kind: EXT_TOTP_CHALLENGE,
+                value: vec![],
+            });
+        }
+        let hello = HandshakeMsg::ServerHello { ifaddr, extensions };
+        let state = ServerHelloSent {
+            clients,
+            info: ClientInfo {
+                ifaddr: remote_addr,
+                identity,
+                config,
+                // ignored without verifier
+                cert: settings.verifier.as_ref().and(cert),
+                metadata,
+                resumed,
+            },
+            challenge,
+            totp_required,
+        };
+        Ok((state, hello))
     }
+}
 
-    // SUCCESS
-    Ok(true)
+impl ServerHelloSent<'_> {
+    // 6. check client confirm, return client properties
+    pub fn receive_confirm(self, msg: HandshakeMsg) -> std::result::Result<ClientInfo, Failure> {
+        let extensions = match msg {
+            HandshakeMsg::ClientConfirm { status, extensions } => {
+                if status != 0 {
+                    let text = format!("client status: {} instead of {}", status, 0);
+                    return Err(Failure::Aborted(text));
+                }
+                extensions
+            }
+            HandshakeMsg::Reject { reason, text } => return Err(rejected(reason, text)),
+            _ => {
+                let text = "client confirm expected";
+                return Err(Failure::reject(RejectReason::UnexpectedPacket, text));
+            }
+        };
+        if let (Some(challenge), Some(cert)) = (self.challenge, &self.info.cert) {
+            let signed = extensions.iter().any(|ext| {
+                ext.kind == EXT_SIGNATURE
+                    && CertVerifier::verify_signature(cert, &challenge, &ext.value, Role::Client)
+            });
+            if !signed {
+                return Err(Failure::reject(RejectReason::AuthFailed, "bad signature"));
+            }
+        }
+        if let (true, Some(config), Some(clients)) =
+            (self.totp_required, &self.info.config, self.clients)
+        {
+            let valid = extensions.iter().any(|ext| {
+                ext.kind == EXT_TOTP_CODE
+                    && match <[u8; 4]>::try_from(&ext.value[..]) {
+                        Ok(code) => clients.verify_totp(config, u32::from_be_bytes(code)),
+                        Err(_) => false,
+                    }
+            });
+            if !valid {
+                let text = "bad one-time password";
+                return Err(Failure::reject(RejectReason::AuthFailed, text));
+            }
+        }
+        Ok(self.info)
+    }
 }
 
-pub fn handler_client_handshake(
-    stream: &mut TcpStream,
-    ifaddr: &IpAddr,
-    netmask: u8,
-) -> std::result::Result<bool, Box<dyn std::error::Error>> {
-    let ifaddr: &Ipv4Addr = match ifaddr {
-        IpAddr::V4(addr) => addr,
-        _ => {
-            eprintln!("Cannot accept IPv6");
-            std::process::exit(1)
+// Client about to send its hello
+pub struct ClientStart<'a> {
+    settings: &'a ClientSettings,
+    // session to be resumed, if any
+    resume: Option<&'a Resume>,
+}
+
+// Client waiting for server hello
+pub struct ClientHelloSent<'a> {
+    settings: &'a ClientSettings,
+    // to be signed by the server if its certificate is verified
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+}
+
+// Client about to confirm
+pub struct ServerHelloReceived<'a> {
+    settings: &'a ClientSettings,
+    info: ServerInfo,
+    challenge: Option<Vec<u8>>,
+    totp_required: bool,
+}
+
+impl<'a> ClientStart<'a> {
+    pub fn new(settings: &'a ClientSettings, resume: Option<&'a Resume>) -> ClientStart<'a> {
+        ClientStart { settings, resume }
+    }
+
+    // 1. return client hello
+    pub fn send_hello(self) -> std::result::Result<(ClientHelloSent<'a>, HandshakeMsg), Failure> {
+        let settings = self.settings;
+        // server must sign the challenge with certificate key
+        let challenge = match settings.verifier {
+            Some(_) => Some(certauth::new_challenge()?),
+            None => None,
+        };
+        // optional data
+        let mut extensions = vec![];
+        if let Some(challenge) = challenge {
+            extensions.push(Extension {
+                kind: EXT_CHALLENGE,
+                value: challenge.to_vec(),
+            });
         }
-    };
-    // https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.try_clone
-    let ostream = stream.try_clone()?;
-    // https://doc.rust-lang.org/std/io/struct.BufWriter.html#method.with_capacity
-    let mut ostream = BufWriter::with_capacity(64, ostream);
-    // read stream
-    let istream = stream.try_clone()?;
-    let mut istream = BufReader::with_capacity(64, istream);
-
-    // classic netmask
-    let netmask: u32 = (!0) ^ ((1 << (32 - netmask)) - 1);
-    // local addr
-    let local_addr: u32 = u32::from_be_bytes(ifaddr.octets());
-    // 1. send intial packet: 16 bytes
-    {
-        // insert magic
-        ostream.write_all(&MAGIC.to_be_bytes())?;
-        // packet id: 1
-        ostream.write_all(&1_u32.to_be_bytes())?;
-        // IPv4 address - already in network byte order
-        // https://doc.rust-lang.org/std/net/struct.Ipv4Addr.html#method.octets
-        ostream.write_all(&local_addr.to_be_bytes())?;
-        // netmask
-        ostream.write_all(&netmask.to_be_bytes())?;
-        // send packet
-        ostream.flush()?;
-    }
-    // 3. check server response
-    {
-        let mut packet2: [u8; 8] = [0; 8];
-        // read packet
-        istream.read_exact(&mut packet2)?;
-        // check idx
-        let pktid = u32::from_be_bytes(packet2[..4].try_into().unwrap());
-        if 2 != pktid {
-            eprintln!("HANDSHAKE error, pktid: {} instead of {}", pktid, 2);
-            return Ok(false);
-        }
-        // get remote iterface address
-        let remote_addr = u32::from_be_bytes(packet2[4..8].try_into().unwrap());
+        if let Some(identity) = &settings.identity {
+            extensions.push(Extension {
+                kind: EXT_IDENTITY,
+                value: identity.as_bytes().to_vec(),
+            });
+        }
+        if let Some(credentials) = &settings.credentials {
+            extensions.push(Extension {
+                kind: EXT_CERTIFICATE,
+                value: credentials.cert.clone(),
+            });
+        }
+        if let Some(metadata) = &settings.metadata {
+            extensions.push(Extension {
+                kind: EXT_METADATA,
+                value: metadata.encode(),
+            });
+        }
+        if let Some(resume) = self.resume {
+            let mut value = resume.ticket.to_vec();
+            value.extend_from_slice(&resume.received.to_be_bytes());
+            extensions.push(Extension {
+                kind: EXT_RESUME,
+                value,
+            });
+        }
+        let hello = HandshakeMsg::ClientHello {
+            ifaddr: ipv4_only(&settings.ifaddr),
+            netmask: netmask_bits(settings.netmask),
+            extensions,
+        };
+        Ok((
+            ClientHelloSent {
+                settings,
+                challenge,
+            },
+            hello,
+        ))
+    }
+}
+
+impl<'a> ClientHelloSent<'a> {
+    // 4. check server hello
+    pub fn receive_hello(
+        self,
+        msg: HandshakeMsg,
+    ) -> std::result::Result<ServerHelloReceived<'a>, Failure> {
+        let settings = self.settings;
+        let local_addr = u32::from(ipv4_only(&settings.ifaddr));
+        let netmask = netmask_bits(settings.netmask);
+        let (remote_addr, extensions) = match msg {
+            HandshakeMsg::ServerHello { ifaddr, extensions } => (u32::from(ifaddr), extensions),
+            HandshakeMsg::Reject { reason, text } => return Err(rejected(reason, text)),
+            _ => {
+                let text = "server hello expected";
+                return Err(Failure::reject(RejectReason::UnexpectedPacket, text));
+            }
+        };
+        // check remote iterface address
         if !((local_addr & netmask == remote_addr & netmask) && (local_addr != remote_addr)) {
-            eprintln!(
-                "HANDSHAKE error, address: local {:#08x} remote {:#08x}",
-                local_addr, remote_addr
-            );
-            return Ok(false);
-        } else {
-            // print server address
-            println!(
-                "Server interface address: {}",
-                IpAddr::from(remote_addr.to_be_bytes())
+            let text = format!(
+                "server {}, you {}/{}",
+                Ipv4Addr::from(remote_addr),
+                Ipv4Addr::from(local_addr),
+                netmask.count_ones()
             );
+            return Err(Failure::Reject(RejectReason::BadAddress, text));
+        }
+        // pushed settings
+        let dns = match dns_from_extensions(&extensions) {
+            Some(dns) => dns,
+            None => {
+                return Err(Failure::reject(
+                    RejectReason::MalformedPacket,
+                    "DNS settings",
+                ));
+            }
+        };
+        let mut ticket = None;
+        let mut resumed = None;
+        let mut challenge = None;
+        let mut totp_required = false;
+        let mut cert = None;
+        let mut signature = None;
+        for ext in extensions.iter() {
+            match ext.kind {
+                EXT_CERTIFICATE => cert = Some(ext.value.clone()),
+                EXT_SIGNATURE => signature = Some(ext.value.clone()),
+                EXT_CHALLENGE if ext.value.len() == CHALLENGE_LEN => {
+                    challenge = Some(ext.value.clone());
+                }
+                EXT_TOTP_CHALLENGE => totp_required = true,
+                EXT_TICKET => match Ticket::try_from(&ext.value[..]) {
+                    Ok(value) => ticket = Some(value),
+                    Err(_) => {
+                        return Err(Failure::reject(RejectReason::MalformedPacket, "ticket"));
+                    }
+                },
+                EXT_RESUMED => match <[u8; 8]>::try_from(&ext.value[..]) {
+                    Ok(value) => resumed = Some(u64::from_be_bytes(value)),
+                    Err(_) => {
+                        return Err(Failure::reject(RejectReason::MalformedPacket, "resumed"));
+                    }
+                },
+                _ => {}
+            }
         }
+        // check certificate of the server and its signature of our
+        // challenge
+        let identity = match (&settings.verifier, self.challenge) {
+            (Some(verifier), Some(challenge)) => {
+                let Some(cert) = cert else {
+                    let text = "server certificate required";
+                    return Err(Failure::reject(RejectReason::AuthFailed, text));
+                };
+                let cn = verifier
+                    .verify(&cert, Role::Server)
+                    .map_err(|text| Failure::Reject(RejectReason::AuthFailed, text))?;
+                let signed = signature.is_some_and(|sig| {
+                    CertVerifier::verify_signature(&cert, &challenge, &sig, Role::Server)
+                });
+                if !signed {
+                    let text = "bad server signature";
+                    return Err(Failure::reject(RejectReason::AuthFailed, text));
+                }
+                Some(cn)
+            }
+            _ => None,
+        };
+        Ok(ServerHelloReceived {
+            settings,
+            info: ServerInfo {
+                ifaddr: Ipv4Addr::from(remote_addr),
+                identity,
+                dns,
+                ticket,
+                resumed,
+            },
+            challenge,
+            totp_required,
+        })
     }
-    // 4. send ok to server
-    {
-        // packet id: 3
-        ostream.write_all(&3_u32.to_be_bytes())?;
+}
+
+impl ServerHelloReceived<'_> {
+    pub fn info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    // must the user type a one-time password?
+    pub fn totp_required(&self) -> bool {
+        self.totp_required
+    }
+
+    // 4. return server properties and client confirm, totp_code
+    // is sent only if required by the server
+    pub fn confirm(
+        self,
+        totp_code: Option<u32>,
+    ) -> std::result::Result<(ServerInfo, HandshakeMsg), Failure> {
+        // prove ownership of certificate
+        let mut extensions = vec![];
+        if let (Some(challenge), Some(credentials)) = (self.challenge, &self.settings.credentials) {
+            extensions.push(Extension {
+                kind: EXT_SIGNATURE,
+                value: credentials.sign(&challenge, Role::Client)?,
+            });
+        }
+        // second factor
+        match (self.totp_required, totp_code) {
+            (true, Some(code)) => extensions.push(Extension {
+                kind: EXT_TOTP_CODE,
+                value: code.to_be_bytes().to_vec(),
+            }),
+            (true, None) => return Err(Failure::Error("One-time password required".into())),
+            _ => {}
+        }
         // all zeros is ok!
-        ostream.write_all(&0_u32.to_be_bytes())?;
-        // send packet
-        ostream.flush()?;
+        let confirm = HandshakeMsg::ClientConfirm {
+            status: 0,
+            extensions,
+        };
+        Ok((self.info, confirm))
+    }
+}
+
+fn send_msg(
+    stream: &mut impl Write,
+    msg: &HandshakeMsg,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut buf = vec![];
+    codec::encode_handshake(msg, &mut buf)?;
+    stream.write_all(&buf)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Log failure and notify peer, errors while sending are only
+// logged because the connection is going to be closed anyway
+fn send_reject(stream: &mut impl Write, reason: RejectReason, text: &str) {
+    eprintln!("HANDSHAKE error, {}", format_reject(reason, text));
+    let msg = HandshakeMsg::Reject {
+        reason: reason as u32,
+        text: text.to_string(),
+    };
+    if let Err(err) = send_msg(stream, &msg) {
+        eprintln!("Cannot send reject packet: {}", err);
+    }
+}
+
+// Read next packet: messages that cannot be decoded cause a reject.
+// No more bytes than the packet are read, following data
+// belongs to the flow
+fn read_msg(stream: &mut impl Read) -> std::result::Result<HandshakeMsg, Failure> {
+    let err = match codec::read_handshake(stream) {
+        Ok(msg) => return Ok(msg),
+        Err(err) => err,
+    };
+    match err.downcast_ref::<CodecError>() {
+        Some(CodecError::BadMagic(magic)) => {
+            let text = format!("magic {:#010x} instead of {:#010x}", magic, codec::MAGIC);
+            Err(Failure::Reject(RejectReason::BadMagic, text))
+        }
+        Some(err) => Err(Failure::Reject(
+            RejectReason::MalformedPacket,
+            err.to_string(),
+        )),
+        // I/O errors
+        None => Err(Failure::Error(err)),
+    }
+}
+
+fn run_server(
+    stream: &mut (impl Read + Write),
+    state: ServerStart,
+) -> std::result::Result<ClientInfo, Failure> {
+    let (state, hello) = state.receive_hello(read_msg(stream)?)?;
+    send_msg(stream, &hello)?;
+    state.receive_confirm(read_msg(stream)?)
+}
+
+// How a server handshake ended without a client
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    // authentication or protocol failure, counted toward bans
+    Failed,
+    // session conflict, misconfigured client or client giving up
+    Declined,
+}
+
+// Drive server state machine over the stream, return a refusal in
+// case of protocol error
+pub fn handler_server_handshake(
+    stream: &mut (impl Read + Write),
+    settings: &ServerSettings,
+    ticket: Option<Ticket>,
+    sessions: &dyn Sessions,
+) -> std::result::Result<std::result::Result<ClientInfo, Refusal>, Box<dyn std::error::Error>> {
+    match run_server(stream, ServerStart::new(settings, ticket, sessions)) {
+        Ok(info) => Ok(Ok(info)),
+        Err(Failure::Reject(reason, text)) => {
+            metrics::handshake_failed(&reason.to_string().replace(' ', "_"));
+            send_reject(stream, reason, &text);
+            if reason.is_failure() {
+                Ok(Err(Refusal::Failed))
+            } else {
+                Ok(Err(Refusal::Declined))
+            }
+        }
+        Err(Failure::Conflict(reason, text)) => {
+            metrics::handshake_failed(&reason.to_string().replace(' ', "_"));
+            send_reject(stream, reason, &text);
+            Ok(Err(Refusal::Declined))
+        }
+        Err(Failure::Rejected(reason, text)) => {
+            metrics::handshake_failed("rejected_by_client");
+            eprintln!(
+                "HANDSHAKE error, client rejected: {}",
+                format_reject(reason, &text)
+            );
+            Ok(Err(Refusal::Declined))
+        }
+        Err(Failure::Aborted(text)) => {
+            metrics::handshake_failed("aborted");
+            eprintln!("HANDSHAKE error, {}", text);
+            Ok(Err(Refusal::Declined))
+        }
+        Err(Failure::Error(err)) => Err(err),
     }
+}
 
-    // SUCCESS
-    Ok(true)
+fn run_client(
+    stream: &mut (impl Read + Write),
+    state: ClientStart,
+) -> std::result::Result<ServerInfo, Failure> {
+    let resuming = state.resume.is_some();
+    let (state, hello) = state.send_hello()?;
+    send_msg(stream, &hello)?;
+    let state = state.receive_hello(read_msg(stream)?)?;
+    // print server address
+    println!("Server interface address: {}", state.info().ifaddr);
+    if let Some(identity) = &state.info().identity {
+        println!("Server certificate verified: {}", identity);
+    }
+    // reconnecting clients may run unattended: the user is not
+    // asked for a code, the server asks for one only if the session
+    // could not be resumed
+    let totp_code = match (state.totp_required(), resuming) {
+        (true, false) => Some(totp::prompt_code()?),
+        (true, true) => {
+            let msg = "Session not resumed by the server, one-time password required";
+            return Err(Failure::Error(msg.into()));
+        }
+        (false, _) => None,
+    };
+    let (info, confirm) = state.confirm(totp_code)?;
+    send_msg(stream, &confirm)?;
+    Ok(info)
+}
+
+// Drive client state machine over the stream, return None in
+// case of protocol error
+pub fn handler_client_handshake(
+    stream: &mut (impl Read + Write),
+    settings: &ClientSettings,
+    resume: Option<&Resume>,
+) -> std::result::Result<Option<ServerInfo>, Box<dyn std::error::Error>> {
+    match run_client(stream, ClientStart::new(settings, resume)) {
+        Ok(info) => Ok(Some(info)),
+        Err(Failure::Reject(reason, text)) | Err(Failure::Conflict(reason, text)) => {
+            send_reject(stream, reason, &text);
+            Ok(None)
+        }
+        Err(Failure::Rejected(reason, text)) => {
+            let msg = format!("server rejected: {}", format_reject(reason, &text));
+            Err(msg.into())
+        }
+        Err(Failure::Aborted(text)) => {
+            eprintln!("HANDSHAKE error, {}", text);
+            Ok(None)
+        }
+        Err(Failure::Error(err)) => Err(err),
+    }
 }
@@ -0,0 +1,268 @@
+// Contains the state shared by the connections of the server: the
+// virtual interface and the routing table. Packets read from the
+// interface are routed to the client owning their destination
+// address, packets sent by clients to the interface unless hairpinned
+// to another client.
+//
+// The interface is up while clients are connected or sessions parked,
+// or for the whole server lifetime if asked to: routes to the
+// interface then survive reconnections. Packets for an address with
+// no connected client are dropped and counted.
+
+use crate::accounting::Accounting;
+use crate::acl::{ClientConfig, SharedConfig};
+use crate::codec;
+use crate::flows::Outgoing;
+use crate::handshake::ClientInfo;
+use crate::info;
+use crate::routing::{ClientToClient, Options, Route, Table};
+use crate::session::{Conflict, Parked, Session, SessionInfo, Sessions, Ticket};
+use crate::transport::{Peer, Stream};
+use crate::tunif;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// packets queued for each client, more are dropped
+pub const QUEUE_LEN: usize = 256;
+
+struct State {
+    table: Table,
+    // is the interface up?
+    up: bool,
+}
+
+pub struct Hub {
+    iffile: File,
+    ifname: String,
+    policy: ClientToClient,
+    state: Mutex<State>,
+    // notified when a client disconnects
+    disconnected: Condvar,
+}
+
+impl Hub {
+    pub fn new(
+        iffile: File,
+        ifname: String,
+        options: Options,
+        accounting: Option<Arc<Accounting>>,
+    ) -> Hub {
+        let hub = Hub {
+            iffile,
+            ifname,
+            policy: options.client_to_client,
+            state: Mutex::new(State {
+                table: Table::new(options, accounting),
+                up: false,
+            }),
+            disconnected: Condvar::new(),
+        };
+        hub.update_interface(&mut hub.lock());
+        hub
+    }
+
+    // packets written to the port are sent by client ifaddr
+    pub fn port(&self, ifaddr: Ipv4Addr) -> Port<'_> {
+        Port { hub: self, ifaddr }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn update_interface(&self, state: &mut State) {
+        let used = state.table.interface_needed();
+        if used && !state.up {
+            tunif::set_interface_up(&self.iffile, &self.ifname);
+        } else if !used && state.up {
+            tunif::set_interface_down(&self.iffile, &self.ifname);
+        }
+        state.up = used;
+    }
+
+    // connected clients and parked sessions
+    pub fn clients(&self) -> (usize, usize) {
+        self.lock().table.clients()
+    }
+
+    // remove the session parked by client ifaddr, if any
+    pub fn take_parked(&self, ifaddr: Ipv4Addr) -> Option<Parked> {
+        let mut state = self.lock();
+        let parked = state.table.take_parked(ifaddr);
+        self.update_interface(&mut state);
+        parked
+    }
+
+    // Route packets for the client and the subnets behind it (if any)
+    // to tx, stream is closed if needed to disconnect the client.
+    // config holds the client database settings of the client, if any.
+    // Return the id of the connection, None if the server is exiting
+    // or another client conflicts with it
+    pub fn connect(
+        &self,
+        info: &ClientInfo,
+        config: Option<SharedConfig>,
+        session: &Session,
+        tx: SyncSender<Outgoing>,
+        stream: Stream,
+        remote: Peer,
+    ) -> Option<u64> {
+        let mut route = Route::new(info.identity.clone(), stream, remote, config, tx);
+        route.started = session.started;
+        route.traffic = session.traffic.clone();
+        route.cert = info.cert.clone();
+        route.metadata = info.metadata.clone();
+        let mut state = self.lock();
+        let id = state.table.connect(info.ifaddr, route)?;
+        self.update_interface(&mut state);
+        Some(id)
+    }
+
+    // Connection id of client ifaddr is over, its session is kept
+    // until parked.expires if given. Return false if the connection
+    // had been replaced by a new one.
+    pub fn disconnect(&self, ifaddr: Ipv4Addr, id: u64, parked: Option<Parked>) -> bool {
+        let mut state = self.lock();
+        let current = state.table.disconnect(ifaddr, id, parked);
+        self.update_interface(&mut state);
+        self.disconnected.notify_all();
+        current
+    }
+
+    // keep a session whose resumption failed
+    pub fn park(&self, parked: Parked) {
+        let mut state = self.lock();
+        state.table.park(parked);
+        self.update_interface(&mut state);
+    }
+
+    // connected clients, by tunnel address
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.lock().table.sessions()
+    }
+
+    // client database settings of connected clients
+    pub fn client_configs(&self) -> Vec<(Ipv4Addr, ClientConfig)> {
+        self.lock().table.client_configs()
+    }
+
+    // Apply new client database settings to client ifaddr, return
+    // false if there is no such client or it had no settings
+    pub fn update(&self, ifaddr: Ipv4Addr, config: ClientConfig) -> bool {
+        self.lock().table.update(ifaddr, config)
+    }
+
+    // verified certificates of connected clients
+    pub fn certificates(&self) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        self.lock().table.certificates()
+    }
+
+    // round trip time of each client connection, if known
+    pub fn round_trip_times(&self) -> Vec<(Ipv4Addr, Duration)> {
+        self.lock().table.round_trip_times()
+    }
+
+    // Add the traffic of connected clients to their usage, ask the
+    // ones over quota to exit
+    pub fn account(&self) {
+        self.lock().table.account()
+    }
+
+    // Ask client ifaddr to exit with the given reason, return false
+    // if there is no such client
+    pub fn kick(&self, ifaddr: Ipv4Addr, reason: u32) -> bool {
+        self.lock().table.kick(ifaddr, reason)
+    }
+
+    // drop expired parked sessions, return when the next one expires
+    pub fn expire(&self) -> Option<Instant> {
+        let mut state = self.lock();
+        let next = state.table.expire(Instant::now());
+        self.update_interface(&mut state);
+        next
+    }
+
+    // ask every client to exit and wait until they are disconnected,
+    // at most timeout, then bring the interface down
+    pub fn shutdown(&self, timeout: Duration) {
+        let mut state = self.lock();
+        state.table.close();
+        let (mut state, _) = self
+            .disconnected
+            .wait_timeout_while(state, timeout, |state| state.table.clients().0 > 0)
+            .unwrap();
+        if state.up {
+            tunif::set_interface_down(&self.iffile, &self.ifname);
+            state.up = false;
+        }
+        let (hairpin, unrouted) = state.table.dropped();
+        if self.policy != ClientToClient::Deny || !hairpin.is_empty() {
+            info!("Client to client: {}", hairpin);
+        }
+        if unrouted > 0 {
+            info!("{} packets dropped, no client connected", unrouted);
+        }
+    }
+
+    // Deliver a packet sent by client from: packets for another client
+    // are queued to it if the policy allows, any other packet is
+    // written to the interface
+    fn forward(&self, from: Ipv4Addr, pkt: &[u8]) -> std::io::Result<()> {
+        if self.lock().table.forward(from, pkt) {
+            (&self.iffile).write_all(pkt)?;
+        }
+        Ok(())
+    }
+
+    // Read packets from the virtual interface and queue them to their
+    // client, packets are dropped if there is no such client (counted)
+    // or its queue is full. Never returns.
+    pub fn route_packets(&self) -> ! {
+        let mut buffer: [u8; codec::MAX_PACKET_LEN] = [0; codec::MAX_PACKET_LEN];
+        let mut iffile = &self.iffile;
+        loop {
+            let sz = match iffile.read(&mut buffer) {
+                Ok(sz) => sz,
+                Err(err) => {
+                    // should never happens!
+                    eprintln!("Error reading from virtual interface: {}", err);
+                    std::process::exit(1)
+                }
+            };
+            self.lock().table.deliver(&buffer[..sz]);
+        }
+    }
+}
+
+impl Sessions for Hub {
+    fn conflict(&self, ifaddr: Ipv4Addr, identity: Option<&str>) -> Option<Conflict> {
+        self.lock().table.conflict(ifaddr, identity)
+    }
+
+    fn parked(&self, ticket: &Ticket, ifaddr: Ipv4Addr) -> Option<u64> {
+        self.lock().table.parked(ticket, ifaddr)
+    }
+}
+
+// Where a client connection writes the packets it receives
+pub struct Port<'a> {
+    hub: &'a Hub,
+    ifaddr: Ipv4Addr,
+}
+
+impl Write for Port<'_> {
+    // buf is a whole packet
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hub.forward(self.ifaddr, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,9 +1,30 @@
+pub mod accounting;
+pub mod acl;
+pub mod ban;
+pub mod certauth;
 pub mod client;
+pub mod codec;
+pub mod config;
+pub mod control;
+pub mod dns;
+pub mod filter;
 pub mod flows;
 pub mod handshake;
+pub mod hub;
+pub mod limits;
+pub mod logging;
+pub mod metadata;
+pub mod metrics;
 pub mod parsing;
+pub mod privileges;
+pub mod ratelimit;
+pub mod routing;
 pub mod server;
+pub mod session;
 pub mod signals;
+pub mod systemd;
+pub mod totp;
+pub mod transport;
 pub mod tunif;
 
 // How to use multiple module:
@@ -13,9 +34,84 @@ pub fn run(args: parsing::Args) -> std::result::Result<(), Box<dyn std::error::E
     let ifname = args.interface.ifname;
     let ifaddr = args.interface.ifaddr;
     let netmask = args.interface.netmask;
+    let resume_grace = args.resume_grace;
+    let control = args.control;
+    let privileges = args.privileges;
+    logging::set_level(args.log_level);
     // different behaviour in case of client or server
     match args.mode {
-        parsing::Mode::Client { remote } => client::execute_client(ifname, ifaddr, netmask, remote),
-        parsing::Mode::Server { local } => server::execute_server(ifname, ifaddr, netmask, local),
+        parsing::Mode::Client {
+            remote,
+            dns,
+            identity,
+            credentials,
+            ca,
+            crl,
+            metadata,
+        } => {
+            let credentials = match credentials {
+                Some((cert, key)) => Some(certauth::Credentials::load(&cert, &key)?),
+                None => None,
+            };
+            let verifier = match ca {
+                Some(ca) => Some(certauth::CertVerifier::load(&ca, crl.as_deref())?),
+                None => None,
+            };
+            let settings = handshake::ClientSettings {
+                ifaddr,
+                netmask,
+                identity,
+                credentials,
+                verifier,
+                metadata,
+            };
+            client::execute_client(
+                ifname,
+                settings,
+                remote,
+                dns,
+                resume_grace,
+                control,
+                privileges,
+            )
+        }
+        parsing::Mode::Server {
+            listen,
+            dns,
+            clients_dir,
+            ca,
+            crl,
+            credentials,
+            hub,
+            limits,
+            metrics,
+            config,
+            accounting,
+        } => {
+            let verifier = match ca {
+                Some(ca) => Some(certauth::CertVerifier::load(&ca, crl.as_deref())?),
+                None => None,
+            };
+            let credentials = match credentials {
+                Some((cert, key)) => Some(certauth::Credentials::load(&cert, &key)?),
+                None => None,
+            };
+            let settings = handshake::ServerSettings {
+                ifaddr,
+                netmask,
+                dns: std::sync::RwLock::new(dns),
+                clients: clients_dir.map(acl::ClientDb::new),
+                verifier,
+                credentials,
+            };
+            let service = server::Service {
+                control,
+                metrics,
+                config,
+                accounting,
+                privileges,
+            };
+            server::execute_server(ifname, settings, listen, resume_grace, hub, limits, service)
+        }
     }
 }
@@ -0,0 +1,182 @@
+// Contains limits applied by the server to incoming connections:
+// concurrent sessions, handshakes in progress (overall and from each
+// source address) and connection rate of each source address (local
+// Unix socket connections have none).
+// Connections over a limit are closed right after being accepted,
+// before reading anything from them, as are connections from sources
+// banned after repeated handshake failures.
+
+use crate::ban::{BanPolicy, Bans};
+use crate::ratelimit::RateLimiter;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    // connections being served, handshake included
+    pub max_sessions: Option<u32>,
+    // connections whose handshake is in progress
+    pub max_handshakes: Option<u32>,
+    // handshakes in progress from a single source address
+    pub max_source_handshakes: Option<u32>,
+    // connections per minute from a single source address
+    pub rate: Option<u32>,
+    // ban of sources failing handshakes
+    pub ban: Option<BanPolicy>,
+}
+
+#[derive(Default)]
+struct Counters {
+    sessions: u32,
+    handshakes: u32,
+    // handshakes in progress by source
+    source_handshakes: HashMap<IpAddr, u32>,
+    // sources that connected recently
+    sources: HashMap<IpAddr, RateLimiter>,
+}
+
+impl Counters {
+    fn handshake_done(&mut self, source: Option<IpAddr>) {
+        self.handshakes -= 1;
+        let Some(source) = source else {
+            return;
+        };
+        if let Some(count) = self.source_handshakes.get_mut(&source) {
+            *count -= 1;
+            if *count == 0 {
+                self.source_handshakes.remove(&source);
+            }
+        }
+    }
+}
+
+pub struct Gate {
+    limits: Mutex<Limits>,
+    counters: Mutex<Counters>,
+    bans: Bans,
+}
+
+impl Gate {
+    pub fn new(limits: Limits) -> Gate {
+        Gate {
+            limits: Mutex::new(limits),
+            counters: Mutex::new(Counters::default()),
+            bans: Bans::new(limits.ban),
+        }
+    }
+
+    pub fn bans(&self) -> &Bans {
+        &self.bans
+    }
+
+    // New limits apply to connections accepted from now on
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.lock().unwrap() = limits;
+        self.bans.set_policy(limits.ban);
+    }
+
+    // Connection accepted from source (None for Unix sockets): return
+    // the slot it holds until dropped, Err with the reason if it must
+    // be closed
+    pub fn admit(gate: &Arc<Gate>, source: Option<IpAddr>) -> Result<Slot, String> {
+        if let Some(source) = source {
+            gate.bans.check(source)?;
+        }
+        let limits = *gate.limits.lock().unwrap();
+        let mut counters = gate.counters.lock().unwrap();
+        if let (Some(rate), Some(source)) = (limits.rate, source) {
+            // forget sources whose rate is back to zero
+            counters.sources.retain(|_, limiter| !limiter.is_idle());
+            let limiter = counters
+                .sources
+                .entry(source)
+                .or_insert_with(|| RateLimiter::per_minute(rate as u64));
+            if !limiter.allow(1) {
+                return Err(format!(
+                    "rate limit of {} connections per minute exceeded",
+                    rate
+                ));
+            }
+        }
+        if let Some(max) = limits.max_sessions {
+            if counters.sessions >= max {
+                return Err(format!("limit of {} sessions reached", max));
+            }
+        }
+        if let Some(max) = limits.max_handshakes {
+            if counters.handshakes >= max {
+                return Err(format!("limit of {} handshakes in progress reached", max));
+            }
+        }
+        if let (Some(max), Some(source)) = (limits.max_source_handshakes, source) {
+            if counters
+                .source_handshakes
+                .get(&source)
+                .copied()
+                .unwrap_or(0)
+                >= max
+            {
+                return Err(format!(
+                    "limit of {} handshakes in progress from the source reached",
+                    max
+                ));
+            }
+        }
+        counters.sessions += 1;
+        counters.handshakes += 1;
+        if let Some(source) = source {
+            *counters.source_handshakes.entry(source).or_insert(0) += 1;
+        }
+        Ok(Slot {
+            gate: gate.clone(),
+            source,
+            handshake: true,
+        })
+    }
+}
+
+// Held by a connection while it is served
+pub struct Slot {
+    gate: Arc<Gate>,
+    source: Option<IpAddr>,
+    // is the handshake in progress?
+    handshake: bool,
+}
+
+impl Slot {
+    // handshake is over, successfully or not
+    pub fn handshake_done(&mut self) {
+        if self.handshake {
+            self.handshake = false;
+            let mut counters = self.gate.counters.lock().unwrap();
+            counters.handshake_done(self.source);
+        }
+    }
+
+    // handshake failed, the source may be banned
+    pub fn handshake_failed(&mut self) {
+        self.handshake_done();
+        let Some(source) = self.source else {
+            return;
+        };
+        if let Some(duration) = self.gate.bans.failure(source) {
+            eprintln!(
+                "Source {} banned for {}s after repeated handshake failures",
+                source,
+                duration.as_secs()
+            );
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counters = self.gate.counters.lock().unwrap();
+        counters.sessions -= 1;
+        if self.handshake {
+            counters.handshake_done(self.source);
+        }
+    }
+}
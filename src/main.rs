@@ -2,10 +2,19 @@
 // https://doc.rust-lang.org/cargo/reference/build-script-examples.html#linking-to-system-libraries
 // https://docs.rust-embedded.org/book/interoperability/c-with-rust.html
 
+use rust_tcp_vpn::control;
 use rust_tcp_vpn::parsing;
 use rust_tcp_vpn::run;
 
 fn main() -> std::io::Result<()> {
+    // commands sent to a running instance
+    if std::env::args().nth(1).as_deref() == Some("ctl") {
+        if let Err(err) = control::execute_ctl(parsing::parse_ctl()) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     let args = parsing::parse_arg();
     if let Err(err) = run(args) {
         eprintln!("Execution failed for: {}", err);
@@ -0,0 +1,132 @@
+// Contains optional information the client reports to the server
+// during the handshake, so that administrators can tell which
+// machine is behind a tunnel address.
+//
+// Metadata is carried by a single handshake extension whose value
+// starts with a version byte, servers ignore versions they do not
+// know. Version 1 content is a sequence of fields:
+//      field: kind (u8), length (u8), UTF-8 value
+// Unknown field kinds are ignored.
+
+const VERSION: u8 = 1;
+
+const FIELD_HOSTNAME: u8 = 1;
+const FIELD_OS: u8 = 2;
+const FIELD_VERSION: u8 = 3;
+const FIELD_LABEL: u8 = 4;
+
+// max length of each field, longer values are truncated
+const MAX_FIELD_LEN: usize = u8::MAX as usize;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientMetadata {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub version: Option<String>,
+    // set by the user
+    pub label: Option<String>,
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    let value = std::fs::read_to_string(path).ok()?;
+    Some(value.trim().to_string())
+}
+
+// truncate value on a char boundary
+fn truncate(value: &str) -> &str {
+    let mut end = value.len().min(MAX_FIELD_LEN);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+impl ClientMetadata {
+    // metadata describing the running client
+    pub fn collect(label: Option<String>) -> ClientMetadata {
+        let os = match (
+            read_trimmed("/proc/sys/kernel/ostype"),
+            read_trimmed("/proc/sys/kernel/osrelease"),
+        ) {
+            (Some(ostype), Some(release)) => Some(format!("{} {}", ostype, release)),
+            _ => Some(std::env::consts::OS.to_string()),
+        };
+        ClientMetadata {
+            hostname: read_trimmed("/proc/sys/kernel/hostname"),
+            os,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            label,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = vec![VERSION];
+        let fields = [
+            (FIELD_HOSTNAME, &self.hostname),
+            (FIELD_OS, &self.os),
+            (FIELD_VERSION, &self.version),
+            (FIELD_LABEL, &self.label),
+        ];
+        for (kind, field) in fields {
+            if let Some(field) = field {
+                let field = truncate(field);
+                value.push(kind);
+                value.push(field.len() as u8);
+                value.extend_from_slice(field.as_bytes());
+            }
+        }
+        value
+    }
+
+    // return None if version is unknown or content is malformed
+    pub fn decode(value: &[u8]) -> Option<ClientMetadata> {
+        let (version, mut rest) = value.split_first()?;
+        if *version != VERSION {
+            return None;
+        }
+        let mut metadata = ClientMetadata::default();
+        while let [kind, len, tail @ ..] = rest {
+            let len = *len as usize;
+            if tail.len() < len {
+                return None;
+            }
+            let field = String::from_utf8(tail[..len].to_vec()).ok()?;
+            match *kind {
+                FIELD_HOSTNAME => metadata.hostname = Some(field),
+                FIELD_OS => metadata.os = Some(field),
+                FIELD_VERSION => metadata.version = Some(field),
+                FIELD_LABEL => metadata.label = Some(field),
+                _ => {}
+            }
+            rest = &tail[len..];
+        }
+        // truncated field header
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(metadata)
+    }
+}
+
+// Values are escaped because they are chosen by the client
+impl std::fmt::Display for ClientMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let fields = [
+            ("hostname", &self.hostname),
+            ("os", &self.os),
+            ("version", &self.version),
+            ("label", &self.label),
+        ];
+        let mut first = true;
+        for (name, field) in fields {
+            if let Some(field) = field {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{} {:?}", name, field)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
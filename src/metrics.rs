@@ -0,0 +1,240 @@
+// Contains the counters of the process, served in the Prometheus text
+// format by the HTTP listener of the server (--metrics). Counters are
+// updated by the flows of every connection and by the handshakes, they
+// are never reset. Received means sent by the remote endpoint, sent
+// means sent to it.
+
+use crate::codec::Frame;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// max time waited for the request of a scraper
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// longest request header line accepted
+const MAX_LINE_LEN: u64 = 8192;
+
+// names of the frame types, in the order of Counters::frames
+const FRAME_TYPES: [&str; 3] = ["data", "ack", "exit"];
+
+// name, type and help of every metric, in the order of render
+const METRICS: [(&str, &str, &str); 9] = [
+    ("vpn_packets_total", "counter", "Tunneled packets"),
+    ("vpn_bytes_total", "counter", "Bytes of tunneled packets"),
+    ("vpn_frames_total", "counter", "Protocol frames by type"),
+    ("vpn_handshakes_total", "counter", "Successful handshakes"),
+    (
+        "vpn_handshake_failures_total",
+        "counter",
+        "Failed handshakes by reason",
+    ),
+    (
+        "vpn_tun_write_errors_total",
+        "counter",
+        "Packets not written to the virtual interface",
+    ),
+    ("vpn_sessions", "gauge", "Connected clients"),
+    (
+        "vpn_parked_sessions",
+        "gauge",
+        "Sessions waiting for their client",
+    ),
+    (
+        "vpn_rtt_seconds",
+        "gauge",
+        "Round trip time of client connections estimated by the kernel",
+    ),
+];
+
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    frames: [AtomicU64; 3],
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            frames: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    fn on_packet(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn on_frame(&self, frame: &Frame) {
+        let idx = match frame {
+            Frame::Data { .. } => 0,
+            Frame::Ack { .. } => 1,
+            Frame::Exit { .. } => 2,
+        };
+        self.frames[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static RECEIVED: Counters = Counters::new();
+static SENT: Counters = Counters::new();
+static HANDSHAKES: AtomicU64 = AtomicU64::new(0);
+// failed handshakes by reason
+static FAILURES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static TUN_WRITE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// data packet received, duplicates excluded
+pub fn packet_received(len: usize) {
+    RECEIVED.on_packet(len);
+}
+
+// new data packet sent, retransmissions excluded
+pub fn packet_sent(len: usize) {
+    SENT.on_packet(len);
+}
+
+pub fn frame_received(frame: &Frame) {
+    RECEIVED.on_frame(frame);
+}
+
+pub fn frame_sent(frame: &Frame) {
+    SENT.on_frame(frame);
+}
+
+pub fn handshake_done() {
+    HANDSHAKES.fetch_add(1, Ordering::Relaxed);
+}
+
+// reason is used as label value: short, lower case, no spaces
+pub fn handshake_failed(reason: &str) {
+    *FAILURES
+        .lock()
+        .unwrap()
+        .entry(reason.to_string())
+        .or_insert(0) += 1;
+}
+
+pub fn tun_write_failed() {
+    TUN_WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Values known by the server only when scraped
+pub struct Gauges {
+    // connected clients
+    pub sessions: usize,
+    // sessions waiting for their client to reconnect
+    pub parked: usize,
+    // round trip time of the connection of each client (TCP only)
+    pub rtt: Vec<(Ipv4Addr, Duration)>,
+}
+
+// Every metric in the Prometheus text format, samples are (labels, value)
+pub fn render(gauges: &Gauges) -> String {
+    let directions = [("received", &RECEIVED), ("sent", &SENT)];
+    let by_direction = |value: &dyn Fn(&Counters) -> u64| -> Vec<(String, String)> {
+        directions
+            .iter()
+            .map(|(direction, counters)| {
+                let labels = format!("{{direction=\"{}\"}}", direction);
+                (labels, value(counters).to_string())
+            })
+            .collect()
+    };
+    let single = |value: u64| vec![(String::new(), value.to_string())];
+    let mut frames = vec![];
+    for (direction, counters) in directions {
+        for (kind, count) in FRAME_TYPES.iter().zip(counters.frames.iter()) {
+            let labels = format!("{{direction=\"{}\",type=\"{}\"}}", direction, kind);
+            frames.push((labels, count.load(Ordering::Relaxed).to_string()));
+        }
+    }
+    let failures: Vec<(String, String)> = FAILURES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(reason, count)| (format!("{{reason=\"{}\"}}", reason), count.to_string()))
+        .collect();
+    let rtt: Vec<(String, String)> = gauges
+        .rtt
+        .iter()
+        .map(|(ifaddr, rtt)| {
+            let labels = format!("{{ifaddr=\"{}\"}}", ifaddr);
+            (labels, rtt.as_secs_f64().to_string())
+        })
+        .collect();
+    let packets = by_direction(&|c| c.packets.load(Ordering::Relaxed));
+    let bytes = by_direction(&|c| c.bytes.load(Ordering::Relaxed));
+    let handshakes = single(HANDSHAKES.load(Ordering::Relaxed));
+    let tun_errors = single(TUN_WRITE_ERRORS.load(Ordering::Relaxed));
+    let sessions = single(gauges.sessions as u64);
+    let parked = single(gauges.parked as u64);
+
+    let samples = [
+        packets, bytes, frames, handshakes, failures, tun_errors, sessions, parked, rtt,
+    ];
+    let mut out = String::new();
+    for ((name, kind, help), samples) in METRICS.iter().zip(samples.iter()) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+    out
+}
+
+// Answer a single HTTP request: GET /metrics only
+fn serve_scraper(stream: TcpStream, gauges: &dyn Fn() -> Gauges) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(std::io::Read::take(&stream, MAX_LINE_LEN));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip headers until the empty line
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.get_mut().set_limit(MAX_LINE_LEN);
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&gauges())),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+// Serve metrics on listener in background, gauges is called at every
+// request. Requests are served one at a time.
+pub fn spawn(listener: TcpListener, gauges: impl Fn() -> Gauges + Send + 'static) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Failed to accept on metrics listener: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = serve_scraper(stream, &gauges) {
+                eprintln!("Metrics request: {}", err);
+            }
+        }
+    });
+}
@@ -1,10 +1,21 @@
 // https://docs.rs/crate/argparse/0.2.2
 // https://docs.rs/clap/latest/clap/
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use std::net::{IpAddr, SocketAddr};
+use crate::ban::BanPolicy;
+use crate::dns::{DnsApply, DnsConfig};
+use crate::limits::Limits;
+use crate::logging::Level;
+use crate::metadata::ClientMetadata;
+use crate::privileges::Privileges;
+use crate::routing::{self, ClientToClient, DuplicateSession};
+use crate::transport::Endpoint;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process;
 use std::str::FromStr;
+use std::time::Duration;
 
 const DEFAULT_IFNAME: &str = "tun0";
 
@@ -20,12 +31,43 @@ pub enum Mode {
     Client {
         // TCP related data
         remote: std::net::SocketAddr,
+        // how to apply DNS settings pushed by the server
+        dns: DnsApply,
+        // name presented to the server
+        identity: Option<String>,
+        // certificate and private key files
+        credentials: Option<(PathBuf, PathBuf)>,
+        // CA issuing the server certificate and its revocation list
+        ca: Option<PathBuf>,
+        crl: Option<PathBuf>,
+        // reported to the server
+        metadata: Option<ClientMetadata>,
     },
     // when acting as server require address and port to
     // bind to for incoming connections
     Server {
-        // TCP related data
-        local: std::net::SocketAddr,
+        // TCP addresses and Unix sockets to accept connections on
+        listen: Vec<Endpoint>,
+        // DNS settings pushed to clients
+        dns: DnsConfig,
+        // client database, if any
+        clients_dir: Option<PathBuf>,
+        // CA certificate used to authenticate clients
+        ca: Option<PathBuf>,
+        // certificate revocation list
+        crl: Option<PathBuf>,
+        // certificate and private key files presented to clients
+        credentials: Option<(PathBuf, PathBuf)>,
+        // client-to-client and duplicate session policies
+        hub: routing::Options,
+        // incoming connections
+        limits: Limits,
+        // address of the metrics HTTP listener, if any
+        metrics: Option<SocketAddr>,
+        // settings that can be reloaded, if any
+        config: Option<PathBuf>,
+        // state file of the traffic accounting, if any
+        accounting: Option<PathBuf>,
     },
 }
 
@@ -33,20 +75,35 @@ pub enum Mode {
 pub struct Args {
     pub interface: Interface,
     pub mode: Mode,
+    // how long a session survives a lost TCP connection
+    pub resume_grace: Duration,
+    // control socket, if any
+    pub control: Option<PathBuf>,
+    pub log_level: Level,
+    // given up after creating the interface
+    pub privileges: Privileges,
 }
 
 // clap seems better than argparse
 /// Simple TCP based L3 (TUN) point-to-point VPN server or client
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Use \"rust-tcp-vpn ctl --help\" to manage a running instance"
+)]
 struct Opts {
     // properties of local (server) or remote (client) endpoint
     /// (server) IP to accept connections on (client) remote server IP
-    #[arg(long)]
-    host: String,
+    #[arg(long, requires = "port")]
+    host: Option<String>,
     /// (server) TCP port to listen for connection (client) remote server port
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, requires = "host")]
+    port: Option<u16>,
+    /// (server) additional endpoint to accept connections on: ADDR:PORT, [ADDR6]:PORT or unix:PATH, can be repeated
+    #[arg(long)]
+    listen: Vec<Endpoint>,
 
     // properties describing virtual interface
     /// virtual interface name
@@ -62,22 +119,176 @@ struct Opts {
     /// run as server (default: client)
     #[arg(short, long)]
     server: bool,
+
+    // DNS settings
+    /// (server) DNS resolver address pushed to clients, can be repeated
+    #[arg(long)]
+    push_dns: Vec<IpAddr>,
+    /// (server) DNS search domain pushed to clients, can be repeated
+    #[arg(long)]
+    push_domain: Vec<String>,
+    /// (client) resolv.conf-style file overwritten with pushed DNS settings, requires root (no --user or --chroot)
+    #[arg(long, conflicts_with_all = ["dns_hook", "user", "chroot"])]
+    dns_file: Option<PathBuf>,
+    /// (client) executable invoked with "up"/"down" to apply pushed DNS settings, requires root (no --user or --chroot)
+    #[arg(long, conflicts_with_all = ["user", "chroot"])]
+    dns_hook: Option<PathBuf>,
+
+    // access control
+    /// (client) identity presented to the server
+    #[arg(long)]
+    identity: Option<String>,
+    /// (server) directory with one configuration file per client identity
+    #[arg(long)]
+    clients_dir: Option<PathBuf>,
+
+    // certificate authentication
+    /// PEM certificate presented to the peer
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM (PKCS#8) private key of the certificate
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// PEM certificate of the CA issuing the certificates of the peers, which must present one
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// revocation list issued by the CA, reloaded periodically
+    #[arg(long, requires = "ca")]
+    crl: Option<PathBuf>,
+
+    /// (server) client-to-client traffic: allow, deny or rules (peers in client database)
+    #[arg(long, default_value = "deny")]
+    client_to_client: ClientToClient,
+    /// (server) new session with the address or identity of a connected client: replace, reject or allow (same identity only)
+    #[arg(long, default_value = "replace")]
+    duplicate_session: DuplicateSession,
+    /// (server) bring the interface up at startup and keep it up when no client is connected
+    #[arg(long)]
+    keep_interface_up: bool,
+
+    // connection limits
+    /// (server) max connections served at once, handshakes included
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_sessions: Option<u32>,
+    /// (server) max handshakes in progress at once
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+    max_handshakes: u32,
+    /// (server) max handshakes in progress at once from a single source address
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    max_source_handshakes: u32,
+    /// (server) max connections per minute from a single source address
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_conn_rate: Option<u32>,
+    /// (server) ban a source address after this many handshake failures within 10 minutes
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    ban_after: Option<u32>,
+    /// (server) seconds of the first ban of a source, doubled at each new ban (up to one day)
+    #[arg(long, default_value_t = 60, requires = "ban_after", value_parser = clap::value_parser!(u64).range(1..))]
+    ban_time: u64,
+
+    // client metadata
+    /// (client) label reported to the server along with hostname, OS and version
+    #[arg(long, conflicts_with = "no_metadata")]
+    label: Option<String>,
+    /// (client) do not report hostname, OS and version to the server
+    #[arg(long)]
+    no_metadata: bool,
+
+    /// seconds a session can be resumed after the TCP connection is lost (0 disables)
+    #[arg(long, default_value_t = 0)]
+    resume_grace: u64,
+
+    /// (server) file with settings read again on SIGHUP: log level, connection limits, bans, pushed DNS (listening endpoints, control socket, metrics and privileges need a restart)
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// (server) serve Prometheus metrics over HTTP on PORT (localhost) or ADDR:PORT
+    #[arg(long, value_parser = parse_metrics_addr)]
+    metrics: Option<SocketAddr>,
+    /// (server) count traffic of each client identity in this state file, enforcing the quotas of the client database
+    #[arg(long, requires = "clients_dir")]
+    accounting: Option<PathBuf>,
+
+    /// Unix socket accepting commands of "rust-tcp-vpn ctl"
+    #[arg(long)]
+    control: Option<PathBuf>,
+    /// messages printed: error, info or debug (details about single packets)
+    #[arg(long, default_value = "info")]
+    log_level: Level,
+
+    // privileges
+    /// run as this user once the interface is created, keeping only the capability to bring it up and down
+    #[arg(long)]
+    user: Option<String>,
+    /// run as this group (default: primary group of --user)
+    #[arg(long, requires = "user")]
+    group: Option<String>,
+    /// chroot into this directory once the interface is created, files read later (--clients-dir, --crl, --config) are looked up inside it
+    #[arg(long)]
+    chroot: Option<PathBuf>,
+}
+
+/// Send a command to the control socket of a running client or server
+#[derive(Parser, Debug)]
+#[command(name = "rust-tcp-vpn ctl")]
+pub struct CtlOpts {
+    /// control socket of the running instance (its --control option)
+    #[arg(short = 'S', long)]
+    pub socket: PathBuf,
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List connected sessions with their traffic counters
+    Sessions,
+    /// (server) Disconnect the client using a tunnel address
+    Kick {
+        ifaddr: Ipv4Addr,
+        /// exit reason sent to the client: kicked or normal (as if the server was exiting)
+        #[arg(long, default_value = "kicked")]
+        reason: String,
+    },
+    /// Show the log level, change it if given: error, info or debug
+    LogLevel { level: Option<Level> },
+    /// (server) List source addresses banned after handshake failures
+    Bans,
+    /// (server) Lift the ban of a source address, of all of them if not given
+    Unban { source: Option<IpAddr> },
+    /// (server) Show traffic of client identities today, this month and in total
+    Usage { identity: Option<String> },
+}
+
+// a port alone is bound to localhost only
+pub fn parse_metrics_addr(s: &str) -> Result<SocketAddr, String> {
+    match u16::from_str(s) {
+        Ok(port) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        Err(_) => SocketAddr::from_str(s).map_err(|e| format!("{}: {}", s, e)),
+    }
+}
+
+// arguments following "ctl"
+pub fn parse_ctl() -> CtlOpts {
+    CtlOpts::parse_from(std::env::args().skip(1))
 }
 
 pub fn parse_arg() -> Args {
     let args = Opts::parse();
 
     // https://doc.rust-lang.org/std/str/trait.FromStr.html#tymethod.from_str
-    let host = match IpAddr::from_str(&args.host) {
-        Ok(addr) => addr,
-        Err(err) => {
+    let host = match args.host.as_deref().map(IpAddr::from_str) {
+        Some(Ok(addr)) => Some(addr),
+        Some(Err(err)) => {
             eprintln!("Error parsing address: {}", err);
             process::exit(1)
         }
+        None => None,
     };
     let ifaddr = args.ifaddr;
     // IP address to be used in network connection
-    let addr = SocketAddr::new(host, args.port);
+    let addr = host
+        .zip(args.port)
+        .map(|(host, port)| SocketAddr::new(host, port));
     Args {
         interface: Interface {
             ifname: args.ifname,
@@ -85,9 +296,84 @@ pub fn parse_arg() -> Args {
             netmask: args.netmask,
         },
         mode: if args.server {
-            Mode::Server { local: addr }
+            // may also be empty when listening sockets are passed
+            // by systemd
+            let mut listen = args.listen;
+            listen.extend(addr.map(Endpoint::Tcp));
+            let client_db = args.clients_dir.is_some();
+            Mode::Server {
+                listen,
+                dns: DnsConfig {
+                    nameservers: args.push_dns,
+                    search: args.push_domain,
+                },
+                clients_dir: args.clients_dir,
+                ca: args.ca,
+                crl: args.crl,
+                credentials: args.cert.zip(args.key),
+                hub: routing::Options {
+                    client_to_client: args.client_to_client,
+                    duplicate: args.duplicate_session,
+                    keep_up: args.keep_interface_up,
+                    client_db,
+                },
+                limits: Limits {
+                    max_sessions: args.max_sessions,
+                    max_handshakes: Some(args.max_handshakes),
+                    max_source_handshakes: Some(args.max_source_handshakes),
+                    rate: args.max_conn_rate,
+                    ban: args.ban_after.map(|failures| BanPolicy {
+                        failures,
+                        duration: Duration::from_secs(args.ban_time),
+                    }),
+                },
+                metrics: args.metrics,
+                config: args.config,
+                accounting: args.accounting,
+            }
         } else {
-            Mode::Client { remote: addr }
+            let remote = match addr {
+                Some(addr) => addr,
+                None => {
+                    eprintln!("Client requires --host and --port");
+                    process::exit(1)
+                }
+            };
+            if !args.listen.is_empty() {
+                eprintln!("--listen is a server option");
+                process::exit(1)
+            }
+            if args.metrics.is_some() || args.config.is_some() || args.accounting.is_some() {
+                eprintln!("--metrics, --config and --accounting are server options");
+                process::exit(1)
+            }
+            Mode::Client {
+                remote,
+                dns: if let Some(path) = args.dns_file {
+                    DnsApply::File(path)
+                } else if let Some(path) = args.dns_hook {
+                    DnsApply::Hook(path)
+                } else {
+                    DnsApply::Ignore
+                },
+                identity: args.identity,
+                credentials: args.cert.zip(args.key),
+                ca: args.ca,
+                crl: args.crl,
+                metadata: if args.no_metadata {
+                    None
+                } else {
+                    Some(ClientMetadata::collect(args.label))
+                },
+            }
+        },
+        resume_grace: Duration::from_secs(args.resume_grace),
+        control: args.control,
+        log_level: args.log_level,
+        privileges: Privileges {
+            user: args.user,
+            group: args.group,
+            chroot: args.chroot,
         },
     }
 }
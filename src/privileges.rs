@@ -0,0 +1,109 @@
+// Contains how the process gives up root once the virtual interface
+// has been created (--user, --group, --chroot). The interface is
+// brought up and down while running (SIOCSIFFLAGS), so CAP_NET_ADMIN
+// is the only capability kept, by the thread dropping privileges and
+// the threads it spawns afterwards. Threads spawned before lose every
+// capability.
+
+use nix::libc;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+use std::path::PathBuf;
+
+// linux/capability.h
+const CAPABILITY_VERSION_3: u32 = 0x20080522;
+const CAP_NET_ADMIN: u32 = 12;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Privileges {
+    pub user: Option<String>,
+    // primary group of user if None
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+}
+
+// CAP_NET_ADMIN only, for the calling thread
+fn keep_net_admin() -> nix::Result<()> {
+    let header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mask = 1 << CAP_NET_ADMIN;
+    // version 3 uses two sets of 32 capabilities
+    let data = [
+        CapData {
+            effective: mask,
+            permitted: mask,
+            inheritable: 0,
+        },
+        CapData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        },
+    ];
+    // safety: header and data have the layout expected by capset(2)
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+    nix::errno::Errno::result(ret).map(drop)
+}
+
+impl Privileges {
+    // Names are resolved before entering the chroot
+    fn resolve(&self) -> Result<Option<(Uid, Gid)>, String> {
+        let Some(name) = &self.user else {
+            return Ok(None);
+        };
+        let user = match User::from_name(name) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(format!("unknown user {}", name)),
+            Err(err) => return Err(format!("user {}: {}", name, err)),
+        };
+        let gid = match &self.group {
+            Some(name) => match Group::from_name(name) {
+                Ok(Some(group)) => group.gid,
+                Ok(None) => return Err(format!("unknown group {}", name)),
+                Err(err) => return Err(format!("group {}: {}", name, err)),
+            },
+            None => user.gid,
+        };
+        Ok(Some((user.uid, gid)))
+    }
+
+    // Enter the chroot, then switch to user and group keeping only
+    // CAP_NET_ADMIN
+    pub fn drop(&self) -> Result<(), String> {
+        let ids = self.resolve()?;
+        if let Some(dir) = &self.chroot {
+            unistd::chroot(dir).map_err(|e| format!("chroot {}: {}", dir.display(), e))?;
+            unistd::chdir("/").map_err(|e| format!("chdir /: {}", e))?;
+        }
+        let Some((uid, gid)) = ids else {
+            return Ok(());
+        };
+        // permitted capabilities survive setuid
+        nix::sys::prctl::set_keepcaps(true).map_err(|e| format!("keep capabilities: {}", e))?;
+        unistd::setgroups(&[gid]).map_err(|e| format!("setgroups: {}", e))?;
+        unistd::setgid(gid).map_err(|e| format!("setgid: {}", e))?;
+        unistd::setuid(uid).map_err(|e| format!("setuid: {}", e))?;
+        keep_net_admin().map_err(|e| format!("capabilities: {}", e))?;
+        nix::sys::prctl::set_keepcaps(false).map_err(|e| format!("keep capabilities: {}", e))?;
+        // root must be out of reach for good
+        if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err("root privileges can be regained".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+// Token bucket used to limit traffic rates
+
+use crate::codec;
+
+use std::time::Instant;
+
+pub struct RateLimiter {
+    // tokens added per second
+    rate: f64,
+    // maximum tokens available at once
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    // burst is equal to one second worth of tokens, at least the
+    // largest packet so that every packet can pass eventually
+    pub fn new(rate: u64) -> RateLimiter {
+        let burst = rate.max(codec::MAX_PACKET_LEN as u64) as f64;
+        RateLimiter {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // count events per minute, all of them can happen at once
+    pub fn per_minute(count: u64) -> RateLimiter {
+        let burst = count as f64;
+        RateLimiter {
+            rate: burst / 60.0,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // is the bucket full again? If so the limiter can be dropped
+    pub fn is_idle(&self) -> bool {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.burst
+    }
+
+    // return true if amount tokens are available (and consume them)
+    pub fn allow(&mut self, amount: u64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+}
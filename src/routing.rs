@@ -0,0 +1,567 @@
+// Contains the routing table of the server, independent of the
+// virtual interface: the route to each connected client and the
+// sessions parked after their connection was lost. Packets are routed
+// to the client owning their destination address, either its tunnel
+// address or a subnet behind it (the longest matching prefix wins).
+// Packets a client sends to another client are hairpinned, without
+// going through the interface, if the client-to-client policy allows
+// it.
+//
+// A client connecting with the tunnel address or identity of a
+// connected one is handled according to the duplicate session policy.
+// A tunnel address is never taken over by another identity: only the
+// same client can replace its session, any client if identities are
+// not checked against a client database.
+//
+// With accounting, the traffic of each client is added to the usage of
+// its identity when it disconnects and whenever account() is called.
+// Clients over quota are asked to exit.
+
+use crate::accounting::{Accounting, Quota};
+use crate::acl::{ClientConfig, SharedConfig, Subnet};
+use crate::codec;
+use crate::flows::Outgoing;
+use crate::metadata::ClientMetadata;
+use crate::session::{Conflict, Parked, SessionInfo, Sessions, Ticket, Traffic};
+use crate::transport::{Peer, Stream};
+use crate::{debug, info};
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Shutdown};
+use std::str::FromStr;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Can clients reach each other through the server?
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientToClient {
+    Allow,
+    Deny,
+    // only peers listed in the client database
+    Rules,
+}
+
+impl FromStr for ClientToClient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(ClientToClient::Allow),
+            "deny" => Ok(ClientToClient::Deny),
+            "rules" => Ok(ClientToClient::Rules),
+            _ => Err(format!("{}: expected allow, deny or rules", s)),
+        }
+    }
+}
+
+// What to do when a client connects with the tunnel address or the
+// identity of a connected client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateSession {
+    // disconnect the old session
+    Replace,
+    // reject the new session
+    Reject,
+    // accept the new session if only the identity is the same
+    Allow,
+}
+
+impl FromStr for DuplicateSession {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(DuplicateSession::Replace),
+            "reject" => Ok(DuplicateSession::Reject),
+            "allow" => Ok(DuplicateSession::Allow),
+            _ => Err(format!("{}: expected replace, reject or allow", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub client_to_client: ClientToClient,
+    pub duplicate: DuplicateSession,
+    // bring the interface up at startup, not when the first
+    // client connects
+    pub keep_up: bool,
+    // identities are checked against a client database
+    pub client_db: bool,
+}
+
+// Packets sent by clients to other clients
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HairpinCounters {
+    pub packets: u64,
+    pub bytes: u64,
+    // dropped by client-to-client policy
+    pub denied: u64,
+}
+
+impl HairpinCounters {
+    pub fn is_empty(&self) -> bool {
+        self.packets == 0 && self.denied == 0
+    }
+}
+
+impl std::fmt::Display for HairpinCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets ({} bytes) hairpinned, {} denied",
+            self.packets, self.bytes, self.denied
+        )
+    }
+}
+
+// Connected client
+pub struct Route {
+    pub identity: Option<String>,
+    // to close the connection if its queue is full
+    pub stream: Stream,
+    pub remote: Peer,
+    // session start, before resumptions if any
+    pub started: Instant,
+    pub traffic: Arc<Traffic>,
+    // client database settings, replaced on reload
+    pub config: Option<SharedConfig>,
+    // verified client certificate, checked again on reload
+    pub cert: Option<Vec<u8>>,
+    pub metadata: Option<ClientMetadata>,
+    pub tx: SyncSender<Outgoing>,
+    // tells connections of the same client apart, set by the table
+    id: u64,
+    // subnets behind the client
+    subnets: Vec<Subnet>,
+    // reachable clients with the rules policy
+    peers: Vec<Subnet>,
+    // packets sent by this client to other clients
+    hairpin: HairpinCounters,
+}
+
+impl Route {
+    pub fn new(
+        identity: Option<String>,
+        stream: Stream,
+        remote: Peer,
+        config: Option<SharedConfig>,
+        tx: SyncSender<Outgoing>,
+    ) -> Route {
+        let (subnets, peers) = match config.as_ref().map(SharedConfig::get) {
+            Some(config) => (config.subnets.clone(), config.peers.clone()),
+            None => (vec![], vec![]),
+        };
+        Route {
+            identity,
+            stream,
+            remote,
+            started: Instant::now(),
+            traffic: Arc::new(Traffic::default()),
+            config,
+            cert: None,
+            metadata: None,
+            tx,
+            id: 0,
+            subnets,
+            peers,
+            hairpin: HairpinCounters::default(),
+        }
+    }
+
+    // packets sent by this client to other clients
+    pub fn hairpin(&self) -> HairpinCounters {
+        self.hairpin
+    }
+
+    // ask the client to exit, close the connection if the
+    // exit packet cannot be queued
+    fn exit(&self, reason: u32) {
+        if self.tx.try_send(Outgoing::Exit(reason)).is_err() {
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // add its traffic to the usage of its identity, return the quota
+    // exceeded if any
+    fn account(&self, accounting: &Accounting) -> Option<&'static str> {
+        let identity = self.identity.as_deref()?;
+        let usage = accounting.record(identity, &self.traffic);
+        Quota::of(&self.config.as_ref()?.get()).exceeded(&usage)
+    }
+
+    fn log_hairpin(&self, ifaddr: Ipv4Addr) {
+        if !self.hairpin.is_empty() {
+            info!("Client {}: {}", ifaddr, self.hairpin);
+        }
+    }
+}
+
+// destination of an IPv4 packet
+fn destination(pkt: &[u8]) -> Option<Ipv4Addr> {
+    if pkt.len() < 20 || pkt[0] >> 4 != 4 {
+        return None;
+    }
+    Some(Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[16..20]).unwrap()))
+}
+
+// source of an IPv4 packet whose destination is known
+fn source(pkt: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[12..16]).unwrap())
+}
+
+pub struct Table {
+    options: Options,
+    // connected clients by tunnel address
+    routes: HashMap<Ipv4Addr, Route>,
+    // sessions waiting for their client, by tunnel address
+    parked: HashMap<Ipv4Addr, Parked>,
+    // server is exiting: no new client, no parked session
+    closing: bool,
+    // every packet hairpinned since the server started
+    hairpin: HairpinCounters,
+    // packets for no connected client
+    unrouted: u64,
+    // id of the next connection
+    next_id: u64,
+    // usage of each client identity, if accounted
+    accounting: Option<Arc<Accounting>>,
+}
+
+impl Table {
+    pub fn new(options: Options, accounting: Option<Arc<Accounting>>) -> Table {
+        Table {
+            options,
+            routes: HashMap::new(),
+            parked: HashMap::new(),
+            closing: false,
+            hairpin: HairpinCounters::default(),
+            unrouted: 0,
+            next_id: 0,
+            accounting,
+        }
+    }
+
+    // must the interface be up? while a client is connected or a
+    // session is parked, always if kept up (until the server exits)
+    pub fn interface_needed(&self) -> bool {
+        (self.options.keep_up && !self.closing)
+            || !self.routes.is_empty()
+            || !self.parked.is_empty()
+    }
+
+    // connected clients and parked sessions
+    pub fn clients(&self) -> (usize, usize) {
+        (self.routes.len(), self.parked.len())
+    }
+
+    // every packet hairpinned and packets for no connected client
+    pub fn dropped(&self) -> (HairpinCounters, u64) {
+        (self.hairpin, self.unrouted)
+    }
+
+    // connected clients using the tunnel address or the identity
+    fn duplicates(&self, ifaddr: Ipv4Addr, identity: Option<&str>) -> Vec<Ipv4Addr> {
+        self.routes
+            .iter()
+            .filter(|(addr, route)| {
+                **addr == ifaddr || (identity.is_some() && route.identity.as_deref() == identity)
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    // client owning an address: tunnel address first,
+    // then longest matching subnet
+    pub fn owner(&self, addr: &Ipv4Addr) -> Option<Ipv4Addr> {
+        if self.routes.contains_key(addr) {
+            return Some(*addr);
+        }
+        self.routes
+            .iter()
+            .flat_map(|(ifaddr, route)| route.subnets.iter().map(move |net| (net, ifaddr)))
+            .filter(|(net, _)| net.contains(addr))
+            .max_by_key(|(net, _)| net.prefix)
+            .map(|(_, ifaddr)| *ifaddr)
+    }
+
+    // remove the session parked by client ifaddr, if any
+    pub fn take_parked(&mut self, ifaddr: Ipv4Addr) -> Option<Parked> {
+        self.parked.remove(&ifaddr)
+    }
+
+    // Route packets for client ifaddr and the subnets behind it (if
+    // any). Return the id of the connection, None if the server is
+    // exiting or another client conflicts with it
+    pub fn connect(&mut self, ifaddr: Ipv4Addr, mut route: Route) -> Option<u64> {
+        let identity = route.identity.as_deref();
+        if self.closing || self.conflict(ifaddr, identity).is_some() {
+            return None;
+        }
+        if self.options.duplicate == DuplicateSession::Replace {
+            for addr in self.duplicates(ifaddr, identity) {
+                let old = self.routes.remove(&addr).unwrap();
+                info!("Client {} replaced by a new session", addr);
+                old.exit(codec::EXIT_DUPLICATE);
+                if let Some(accounting) = &self.accounting {
+                    old.account(accounting);
+                }
+                old.log_hairpin(addr);
+            }
+        }
+        if self.parked.remove(&ifaddr).is_some() {
+            info!("Parked session of {} replaced by new client", ifaddr);
+        }
+        route.id = self.next_id;
+        self.next_id += 1;
+        // over quota until the period is over: told when connected
+        let config = route.config.as_ref().map(SharedConfig::get);
+        let exceeded = match (&self.accounting, &route.identity, &config) {
+            (Some(accounting), Some(identity), Some(config)) => {
+                Quota::of(config).exceeded(&accounting.usage_of(identity))
+            }
+            _ => None,
+        };
+        if let Some(quota) = exceeded {
+            info!("Client {} refused: {} quota exceeded", ifaddr, quota);
+            route.exit(codec::EXIT_QUOTA);
+        }
+        let id = route.id;
+        self.routes.insert(ifaddr, route);
+        Some(id)
+    }
+
+    // Connection id of client ifaddr is over, its session is kept
+    // until parked.expires if given. Return false if the connection
+    // had been replaced by a new one.
+    pub fn disconnect(&mut self, ifaddr: Ipv4Addr, id: u64, parked: Option<Parked>) -> bool {
+        let current = self.routes.get(&ifaddr).is_some_and(|route| route.id == id);
+        if current {
+            let route = self.routes.remove(&ifaddr).unwrap();
+            if let Some(accounting) = &self.accounting {
+                route.account(accounting);
+            }
+            route.log_hairpin(ifaddr);
+            if let Some(parked) = parked {
+                if !self.closing {
+                    self.parked.insert(ifaddr, parked);
+                }
+            }
+        }
+        current
+    }
+
+    // keep a session whose resumption failed
+    pub fn park(&mut self, parked: Parked) {
+        if !self.closing && !self.routes.contains_key(&parked.ifaddr) {
+            self.parked.insert(parked.ifaddr, parked);
+        }
+    }
+
+    // connected clients, by tunnel address
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .routes
+            .iter()
+            .map(|(ifaddr, route)| SessionInfo {
+                identity: route.identity.clone(),
+                ifaddr: *ifaddr,
+                remote: route.remote,
+                uptime: route.started.elapsed(),
+                traffic: route.traffic.get(),
+                metadata: route.metadata.clone(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.ifaddr);
+        sessions
+    }
+
+    // client database settings of connected clients
+    pub fn client_configs(&self) -> Vec<(Ipv4Addr, ClientConfig)> {
+        let mut configs: Vec<(Ipv4Addr, ClientConfig)> = self
+            .routes
+            .iter()
+            .filter_map(|(ifaddr, route)| {
+                Some((*ifaddr, ClientConfig::clone(&route.config.as_ref()?.get())))
+            })
+            .collect();
+        configs.sort_by_key(|(ifaddr, _)| *ifaddr);
+        configs
+    }
+
+    // Apply new client database settings to client ifaddr: packets
+    // are routed, filtered and limited with them from now on. Return
+    // false if there is no such client or it had no settings
+    pub fn update(&mut self, ifaddr: Ipv4Addr, config: ClientConfig) -> bool {
+        let Some(route) = self.routes.get_mut(&ifaddr) else {
+            return false;
+        };
+        let Some(shared) = &route.config else {
+            return false;
+        };
+        route.subnets = config.subnets.clone();
+        route.peers = config.peers.clone();
+        shared.set(config);
+        true
+    }
+
+    // verified certificates of connected clients
+    pub fn certificates(&self) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        let mut certs: Vec<(Ipv4Addr, Vec<u8>)> = self
+            .routes
+            .iter()
+            .filter_map(|(ifaddr, route)| Some((*ifaddr, route.cert.clone()?)))
+            .collect();
+        certs.sort_by_key(|(ifaddr, _)| *ifaddr);
+        certs
+    }
+
+    // round trip time of each client connection, if known
+    pub fn round_trip_times(&self) -> Vec<(Ipv4Addr, Duration)> {
+        let mut rtts: Vec<(Ipv4Addr, Duration)> = self
+            .routes
+            .iter()
+            .filter_map(|(ifaddr, route)| Some((*ifaddr, route.stream.rtt()?)))
+            .collect();
+        rtts.sort();
+        rtts
+    }
+
+    // Add the traffic of connected clients to their usage, ask the
+    // ones over quota to exit
+    pub fn account(&self) {
+        let Some(accounting) = &self.accounting else {
+            return;
+        };
+        for (ifaddr, route) in self.routes.iter() {
+            if let Some(quota) = route.account(accounting) {
+                info!("Client {} exceeded its {} quota", ifaddr, quota);
+                route.exit(codec::EXIT_QUOTA);
+            }
+        }
+    }
+
+    // Ask client ifaddr to exit with the given reason, return false
+    // if there is no such client
+    pub fn kick(&self, ifaddr: Ipv4Addr, reason: u32) -> bool {
+        match self.routes.get(&ifaddr) {
+            Some(route) => {
+                route.exit(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // drop parked sessions expired at now, return when the next one
+    // expires
+    pub fn expire(&mut self, now: Instant) -> Option<Instant> {
+        self.parked.retain(|ifaddr, parked| {
+            let alive = parked.expires > now;
+            if !alive {
+                info!("Session of {} expired", ifaddr);
+            }
+            alive
+        });
+        self.parked.values().map(|parked| parked.expires).min()
+    }
+
+    // server is exiting: ask every client to exit, forget parked
+    // sessions
+    pub fn close(&mut self) {
+        self.closing = true;
+        self.parked.clear();
+        for route in self.routes.values() {
+            route.exit(codec::EXIT_NORMAL);
+        }
+    }
+
+    // Deliver a packet sent by client from: packets for another client
+    // are queued to it if the policy allows. Return true if the packet
+    // must be written to the interface
+    pub fn forward(&mut self, from: Ipv4Addr, pkt: &[u8]) -> bool {
+        let hairpin = destination(pkt).and_then(|dst| {
+            let to = self.owner(&dst).filter(|to| *to != from)?;
+            Some((dst, to))
+        });
+        let Some((dst, to)) = hairpin else {
+            return true;
+        };
+        // a peer rule allows traffic in both directions
+        let listed = |ifaddr: &Ipv4Addr, addr: &Ipv4Addr| match self.routes.get(ifaddr) {
+            Some(route) => route.peers.iter().any(|net| net.contains(addr)),
+            None => false,
+        };
+        let allowed = match self.options.client_to_client {
+            ClientToClient::Allow => true,
+            ClientToClient::Deny => false,
+            ClientToClient::Rules => listed(&from, &dst) || listed(&to, &source(pkt)),
+        };
+        let Some(route) = self.routes.get_mut(&from) else {
+            // client is disconnecting
+            return false;
+        };
+        for counters in [&mut route.hairpin, &mut self.hairpin] {
+            if allowed {
+                counters.packets += 1;
+                counters.bytes += pkt.len() as u64;
+            } else {
+                counters.denied += 1;
+            }
+        }
+        if allowed {
+            // dropped if queue is full, like packets from the interface
+            let _ = self.routes[&to].tx.try_send(Outgoing::Packet(pkt.to_vec()));
+        }
+        false
+    }
+
+    // Queue a packet read from the interface to its client, return
+    // false if there is no such client (counted)
+    pub fn deliver(&mut self, pkt: &[u8]) -> bool {
+        let Some(dst) = destination(pkt) else {
+            return false;
+        };
+        match self.owner(&dst) {
+            Some(to) => {
+                // dropped if queue is full
+                let _ = self.routes[&to].tx.try_send(Outgoing::Packet(pkt.to_vec()));
+                true
+            }
+            None => {
+                debug!("Packet for {} dropped: no client connected", dst);
+                self.unrouted += 1;
+                false
+            }
+        }
+    }
+
+    // packets sent by client ifaddr to other clients
+    pub fn hairpin_of(&self, ifaddr: Ipv4Addr) -> Option<HairpinCounters> {
+        self.routes.get(&ifaddr).map(Route::hairpin)
+    }
+}
+
+impl Sessions for Table {
+    fn conflict(&self, ifaddr: Ipv4Addr, identity: Option<&str>) -> Option<Conflict> {
+        let policy = self.options.duplicate;
+        if let Some(route) = self.routes.get(&ifaddr) {
+            // another identity cannot take the address over
+            let same = !self.options.client_db || route.identity.as_deref() == identity;
+            if policy != DuplicateSession::Replace || !same {
+                return Some(Conflict::Address);
+            }
+        }
+        if policy == DuplicateSession::Reject && !self.duplicates(ifaddr, identity).is_empty() {
+            return Some(Conflict::Identity);
+        }
+        None
+    }
+
+    fn parked(&self, ticket: &Ticket, ifaddr: Ipv4Addr) -> Option<u64> {
+        self.parked
+            .get(&ifaddr)
+            .and_then(|parked| parked.parked(ticket, ifaddr))
+    }
+}
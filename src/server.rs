@@ -1,52 +1,644 @@
-use crate::flows;
-use crate::handshake;
+use crate::accounting::Accounting;
+use crate::acl::SharedConfig;
+use crate::certauth::Role;
+use crate::codec;
+use crate::config::{self, Runtime};
+use crate::control::{self, ControlSocket};
+use crate::flows::{self, Outgoing};
+use crate::handshake::{self, Refusal, ServerSettings};
+use crate::hub::{self, Hub};
+use crate::info;
+use crate::limits::{Gate, Limits, Slot};
+use crate::logging;
+use crate::metrics;
+use crate::privileges::Privileges;
+use crate::routing;
+use crate::session::{self, Parked, Session};
+use crate::systemd::{self, Notifier};
+use crate::transport::{Endpoint, Listener, Peer, Stream};
 use crate::tunif;
 
-use std::net::{IpAddr, TcpListener};
+use serde_json::{json, Value};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// max time waited for clients to exit when the server exits
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+// how often traffic of connected clients is accounted and their
+// quota checked
+const ACCOUNT_INTERVAL: Duration = Duration::from_secs(10);
+// max time waited for each handshake packet, long enough for
+// users typing a one-time password
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+// max time of a whole handshake, the connection is closed after it
+const HANDSHAKE_DEADLINE: Duration = Duration::from_secs(120);
+
+// wait for incoming connections or signals until deadline (if any),
+// return (listeners with a connection available, SIGINT received,
+// SIGHUP received)
+fn wait_event(
+    listeners: &[Listener],
+    sigfile: &std::fs::File,
+    hupfile: &std::fs::File,
+    deadline: Option<Instant>,
+) -> std::result::Result<(Vec<usize>, bool, bool), Box<dyn std::error::Error>> {
+    use nix::poll::{PollFd, PollFlags, PollTimeout};
+    use std::os::fd::AsFd;
+
+    let timeout = match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX)
+        }
+        None => PollTimeout::NONE,
+    };
+    // signal pipes first
+    let mut fds = vec![
+        PollFd::new(sigfile.as_fd(), PollFlags::POLLIN),
+        PollFd::new(hupfile.as_fd(), PollFlags::POLLIN),
+    ];
+    fds.extend(
+        listeners
+            .iter()
+            .map(|listener| PollFd::new(listener.as_fd(), PollFlags::POLLIN)),
+    );
+    // interrupted by SIGHUP: its pipe is read by the next call
+    match nix::poll::poll(&mut fds, timeout) {
+        Ok(0) | Err(nix::errno::Errno::EINTR) => return Ok((vec![], false, false)),
+        Ok(_) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let signal = fds[0].any().unwrap_or(false);
+    let hangup = fds[1].any().unwrap_or(false);
+    let ready = (0..listeners.len())
+        .filter(|idx| fds[idx + 2].any().unwrap_or(false))
+        .collect();
+    Ok((ready, signal, hangup))
+}
+
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(err) => matches!(
+            err.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ),
+        None => false,
+    }
+}
+
+// Commands of the control socket specific to the server
+struct ServerControl {
+    hub: Arc<Hub>,
+    gate: Arc<Gate>,
+    accounting: Option<Arc<Accounting>>,
+}
+
+impl control::Handler for ServerControl {
+    fn handle(&self, cmd: &str, request: &Value) -> Result<Value, String> {
+        match cmd {
+            "sessions" => {
+                let sessions = self.hub.sessions();
+                let sessions: Vec<Value> = sessions.iter().map(control::session_json).collect();
+                Ok(json!({ "sessions": sessions }))
+            }
+            "kick" => {
+                let ifaddr = match control::field(request, "ifaddr")? {
+                    Some(ifaddr) => Ipv4Addr::from_str(ifaddr).map_err(|e| e.to_string())?,
+                    None => return Err("missing ifaddr".to_string()),
+                };
+                let reason = match control::field(request, "reason")? {
+                    None | Some("kicked") => codec::EXIT_KICKED,
+                    Some("normal") => codec::EXIT_NORMAL,
+                    Some(reason) => return Err(format!("{}: expected kicked or normal", reason)),
+                };
+                if !self.hub.kick(ifaddr, reason) {
+                    return Err(format!("no client connected as {}", ifaddr));
+                }
+                Ok(json!({}))
+            }
+            "bans" => {
+                let bans: Vec<Value> = self
+                    .gate
+                    .bans()
+                    .list()
+                    .iter()
+                    .map(|ban| {
+                        json!({
+                            "source": ban.source.to_string(),
+                            "remaining": ban.remaining.as_secs(),
+                            "count": ban.count,
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "bans": bans }))
+            }
+            "unban" => {
+                let source = match control::field(request, "source")? {
+                    Some(source) => Some(IpAddr::from_str(source).map_err(|e| e.to_string())?),
+                    None => None,
+                };
+                let lifted = self.gate.bans().clear(source);
+                if lifted > 0 {
+                    info!("{} bans lifted by administrator", lifted);
+                }
+                Ok(json!({ "lifted": lifted }))
+            }
+            "usage" => {
+                let Some(accounting) = &self.accounting else {
+                    return Err("traffic is not accounted (no --accounting)".to_string());
+                };
+                // up to date for connected clients
+                self.hub.account();
+                let identity = control::field(request, "identity")?;
+                let usage: serde_json::Map<String, Value> = accounting
+                    .usage()
+                    .into_iter()
+                    .filter(|(name, _)| identity.is_none_or(|identity| identity == name))
+                    .map(|(name, usage)| (name, usage.to_json()))
+                    .collect();
+                Ok(json!({ "usage": usage }))
+            }
+            _ => Err(format!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+// How the server runs as a service: interfaces for administrators
+// and monitoring, settings file and privileges, all optional
+pub struct Service {
+    // Unix socket accepting commands of "rust-tcp-vpn ctl"
+    pub control: Option<PathBuf>,
+    // HTTP listener serving metrics
+    pub metrics: Option<SocketAddr>,
+    // settings read again on SIGHUP
+    pub config: Option<PathBuf>,
+    // state file of the traffic accounting
+    pub accounting: Option<PathBuf>,
+    // given up once sockets and interface are ready
+    pub privileges: Privileges,
+}
+
+// Apply new settings of the configuration file
+fn apply_config(old: &Runtime, new: &Runtime, settings: &ServerSettings, gate: &Gate) {
+    if new.log_level != old.log_level {
+        logging::set_level(new.log_level);
+        info!("Log level set to {}", new.log_level);
+    }
+    if new.limits != old.limits {
+        gate.set_limits(new.limits);
+        info!("Connection limits changed, applied to new connections");
+    }
+    if new.dns != old.dns {
+        *settings.dns.write().unwrap() = new.dns.clone();
+        info!("Pushed DNS settings changed, sent to new sessions");
+    }
+}
+
+// SIGHUP: read the configuration file, the revocation list and the
+// client database again. Sessions are kept: client database changes
+// apply to connected clients right away, those no longer allowed to
+// connect are kicked, as are clients whose certificate was revoked.
+// Changed settings only used at startup are reported, not applied.
+fn reload(
+    path: Option<&Path>,
+    base: &Runtime,
+    current: &mut Runtime,
+    settings: &ServerSettings,
+    gate: &Gate,
+    hub: &Hub,
+) {
+    info!("Reloading configuration");
+    if let Some(path) = path {
+        match config::load(path, base) {
+            Ok(mut config) => {
+                apply_config(current, &config, settings, gate);
+                for name in config::restart_needed(current, &config) {
+                    eprintln!("WARNING: {} changed, restart the server to apply it", name);
+                }
+                // still running with the startup settings
+                config.startup = current.startup.clone();
+                *current = config;
+            }
+            // keep current settings
+            Err(err) => eprintln!("Configuration not reloaded: {}", err),
+        }
+    }
+    // connected clients whose certificate has been revoked
+    if let Some(verifier) = &settings.verifier {
+        verifier.reload_crl();
+        for (ifaddr, cert) in hub.certificates() {
+            if let Err(err) = verifier.verify(&cert, Role::Client) {
+                info!("Client {} certificate rejected: {}", ifaddr, err);
+                hub.kick(ifaddr, codec::EXIT_KICKED);
+            }
+        }
+    }
+    let Some(clients) = &settings.clients else {
+        return;
+    };
+    // new settings apply to connected clients, unless they can no
+    // longer connect
+    for (ifaddr, config) in hub.client_configs() {
+        let change = match clients.lookup(&config.identity) {
+            Ok(Some(new)) if new == config => continue,
+            Ok(Some(new)) if new.enabled && new.ifaddr == ifaddr => {
+                info!(
+                    "Client {} ({}) changed in database",
+                    config.identity, ifaddr
+                );
+                hub.update(ifaddr, new);
+                continue;
+            }
+            Ok(Some(new)) if new.enabled => "assigned another address",
+            Ok(Some(_)) => "disabled",
+            Ok(None) => "removed",
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        info!(
+            "Client {} ({}) {} in database",
+            config.identity, ifaddr, change
+        );
+        hub.kick(ifaddr, codec::EXIT_KICKED);
+    }
+}
+
+// status shown by the service manager
+fn report(hub: &Hub, notifier: &Notifier) {
+    let (connected, parked) = hub.clients();
+    notifier.status(&format!(
+        "{} clients connected, {} sessions parked",
+        connected, parked
+    ));
+}
+
+// Handle a single client, from handshake to disconnection,
+// slot is released on return
+fn serve_client(
+    hub: &Hub,
+    notifier: &Notifier,
+    settings: &ServerSettings,
+    mut stream: Stream,
+    remote: Peer,
+    resume_grace: Duration,
+    mut slot: Slot,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    // resumption is enabled if grace period is not null
+    let ticket = if resume_grace.is_zero() {
+        None
+    } else {
+        Some(session::new_ticket()?)
+    };
+    // a silent client cannot hold a handshake slot forever, nor a
+    // client sending its packets byte after byte
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let deadline = Instant::now() + HANDSHAKE_DEADLINE;
+    let watchdog = stream.try_clone()?;
+    let (done, finished) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        // woken up early when the handshake is over
+        if finished.recv_timeout(HANDSHAKE_DEADLINE) == Err(mpsc::RecvTimeoutError::Timeout) {
+            let _ = watchdog.shutdown(Shutdown::Both);
+        }
+    });
+    let result = handshake::handler_server_handshake(&mut stream, settings, ticket, hub);
+    drop(done);
+    // authentication and protocol errors and timeouts count as
+    // failures, not a connection closed by the client
+    let info = match result {
+        Ok(Ok(info)) => {
+            metrics::handshake_done();
+            info
+        }
+        Ok(Err(refusal)) => {
+            eprintln!("Failed server handshake due to protocol error");
+            // session conflicts are not the fault of the source
+            if refusal == Refusal::Failed {
+                slot.handshake_failed();
+            }
+            return Ok(());
+        }
+        Err(err) if is_timeout(err.as_ref()) || Instant::now() >= deadline => {
+            metrics::handshake_failed("timeout");
+            slot.handshake_failed();
+            return Err("Handshake timeout".into());
+        }
+        Err(err) => {
+            metrics::handshake_failed("connection_error");
+            return Err(err);
+        }
+    };
+    slot.handshake_done();
+    stream.set_read_timeout(None)?;
+    let ifaddr = info.ifaddr;
+    info!(
+        "Client {} connected as {}",
+        info.identity.as_deref().unwrap_or("(anonymous)"),
+        ifaddr
+    );
+    if let Some(metadata) = &info.metadata {
+        info!("Client metadata: {}", metadata);
+    }
+    let (ticket, session) = match info.resumed {
+        Some(received) => {
+            // parked session may have expired since the handshake:
+            // the client will start a new one when reconnecting
+            let mut parked = match hub.take_parked(ifaddr) {
+                Some(parked) => parked,
+                None => return Err(format!("Session of {} expired", ifaddr).into()),
+            };
+            if let Err(err) = flows::resume_session(&mut stream, &mut parked.session, received) {
+                eprintln!("Failed to resume session: {}", err);
+                hub.park(parked);
+                return Ok(());
+            }
+            (Some(parked.ticket), parked.session)
+        }
+        None => (ticket, Session::new(ticket.is_some())),
+    };
+    let (tx, rx) = mpsc::sync_channel(hub::QUEUE_LEN);
+    // updated by the hub when the client database is reloaded
+    let config = info.config.clone().map(SharedConfig::new);
+    // checked again: another client may have connected since the handshake
+    let id = match hub.connect(
+        &info,
+        config.clone(),
+        &session,
+        tx.clone(),
+        stream.try_clone()?,
+        remote,
+    ) {
+        Some(id) => id,
+        None => return Err(format!("{} conflicts with a connected client", ifaddr).into()),
+    };
+    report(hub, notifier);
+    let session = Arc::new(Mutex::new(session));
+    let writer = {
+        let stream = stream.try_clone()?;
+        let session = session.clone();
+        let config = config.clone();
+        std::thread::spawn(move || flows::client_writer(&stream, rx, &session, config.as_ref()))
+    };
+    let ans = flows::client_reader(
+        &stream,
+        &mut hub.port(ifaddr),
+        config.as_ref(),
+        &session,
+        &tx,
+    );
+    // writer may be already stopped
+    let _ = tx.send(Outgoing::Close);
+    let exit = writer.join().unwrap();
+    let session = match Arc::try_unwrap(session) {
+        Ok(session) => session.into_inner().unwrap(),
+        Err(_) => unreachable!("session still used by writer"),
+    };
+    // session is parked if the connection has been lost, not
+    // if it has been closed by the writer
+    let parked = match (&ans, ticket, exit) {
+        (Err(e), Some(ticket), None) if flows::is_connection_error(e.as_ref()) => Some(Parked {
+            ticket,
+            ifaddr,
+            session,
+            expires: Instant::now() + resume_grace,
+        }),
+        _ => None,
+    };
+    let is_parked = parked.is_some();
+    let current = hub.disconnect(ifaddr, id, parked);
+    report(hub, notifier);
+    if !current {
+        // already logged by the hub
+        return Ok(());
+    }
+    match (ans, exit) {
+        (Ok(()), _) => info!("Client {} exited", ifaddr),
+        (Err(_), Some(codec::EXIT_DUPLICATE)) => {}
+        (Err(_), Some(codec::EXIT_KICKED)) => info!("Client {} kicked", ifaddr),
+        // logged by the hub
+        (Err(_), Some(codec::EXIT_QUOTA)) => {}
+        (Err(_), Some(_)) => info!("Client {} disconnected, server exiting", ifaddr),
+        (Err(e), None) if is_parked => {
+            // interface is kept up while waiting for the client
+            eprintln!(
+                "Connection of {} lost: {}, session parked for {}s",
+                ifaddr,
+                e,
+                resume_grace.as_secs()
+            );
+        }
+        (Err(e), None) => return Err(e),
+    }
+    Ok(())
+}
 
 pub fn execute_server(
     ifname: String,
-    ifaddr: IpAddr,
-    netmask: u8,
-    local: std::net::SocketAddr,
+    settings: ServerSettings,
+    endpoints: Vec<Endpoint>,
+    resume_grace: Duration,
+    options: routing::Options,
+    limits: Limits,
+    service: Service,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let mut iffile = tunif::initialize_tun_interface(&ifname, ifaddr, netmask);
-    // wait for remote connection
-    let listener = match TcpListener::bind(local) {
-        Ok(l) => l,
+    // sockets passed by systemd are used along with the endpoints
+    let mut listeners = match systemd::listeners_from_env() {
+        Ok(listeners) => listeners,
         Err(err) => {
-            eprintln!("ERROR: cannot bind to address: {}", err);
+            eprintln!("ERROR: invalid socket from service manager: {}", err);
             std::process::exit(1);
         }
     };
+    // command line settings, overridden by the configuration file
+    let base = Runtime {
+        log_level: logging::level(),
+        limits,
+        dns: settings.dns.read().unwrap().clone(),
+        startup: config::Startup {
+            listen: endpoints,
+            control: service.control.clone(),
+            metrics: service.metrics,
+            privileges: service.privileges.clone(),
+        },
+    };
+    let mut runtime = match &service.config {
+        Some(path) => match config::load(path, &base) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("ERROR: invalid configuration: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => base.clone(),
+    };
+    if listeners.is_empty() && runtime.startup.listen.is_empty() {
+        eprintln!("Server requires --host and --port, or --listen");
+        std::process::exit(1);
+    }
+    logging::set_level(runtime.log_level);
+    *settings.dns.write().unwrap() = runtime.dns.clone();
+    // the server runs without notifications rather than not at all
+    let notifier = Arc::new(Notifier::from_env().unwrap_or_else(|err| {
+        eprintln!("WARNING: cannot notify systemd: {}", err);
+        Notifier::disabled()
+    }));
+    let iffile = tunif::initialize_tun_interface(&ifname, settings.ifaddr, settings.netmask);
+    // wait for remote connections on every endpoint
+    for endpoint in runtime.startup.listen.iter() {
+        match Listener::bind(endpoint) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                eprintln!("ERROR: cannot bind to {}: {}", endpoint, err);
+                std::process::exit(1);
+            }
+        }
+    }
     // spawn thread handler
     let mut sigfile = crate::signals::spawn_sig_handler();
-    // allow crashing the process if no client is connected
-    crate::signals::handle_interrupt(false);
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        // if true ok
-        let _h = match handshake::handler_server_handshake(&mut stream, &ifaddr, netmask) {
-            Ok(false) => {
-                eprintln!("Failed server handshake due to protocol error");
-                continue;
+    let mut hupfile = crate::signals::spawn_hangup_handler();
+    let accounting = match &service.accounting {
+        Some(path) => match Accounting::open(path) {
+            Ok(accounting) => Some(Arc::new(accounting)),
+            Err(err) => {
+                eprintln!("ERROR: cannot load traffic accounting: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // shared by every connection
+    let hub = Arc::new(Hub::new(iffile, ifname, options, accounting.clone()));
+    let settings = Arc::new(settings);
+    let gate = Arc::new(Gate::new(runtime.limits));
+    {
+        let hub = hub.clone();
+        std::thread::spawn(move || hub.route_packets());
+    }
+    // socket file removed when the server exits
+    let _control = match &runtime.startup.control {
+        Some(path) => {
+            let handler = Arc::new(ServerControl {
+                hub: hub.clone(),
+                gate: gate.clone(),
+                accounting: accounting.clone(),
+            });
+            match ControlSocket::spawn(path, handler) {
+                Ok(control) => Some(control),
+                Err(err) => {
+                    eprintln!(
+                        "ERROR: cannot create control socket {}: {}",
+                        path.display(),
+                        err
+                    );
+                    std::process::exit(1);
+                }
             }
-            Ok(h) => h,
+        }
+        None => None,
+    };
+    if let Some(addr) = runtime.startup.metrics {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
             Err(err) => {
-                eprintln!("Failed server handshake: {}", err);
-                continue;
+                eprintln!("ERROR: cannot bind metrics listener to {}: {}", addr, err);
+                std::process::exit(1);
             }
         };
-        // bring interface up
-        tunif::set_interface_up(&iffile, &ifname);
-        crate::signals::handle_interrupt(true);
-        let ans = flows::handle_flow(&mut stream, &mut iffile, &mut sigfile);
-        crate::signals::handle_interrupt(false);
-        tunif::set_interface_down(&iffile, &ifname);
-        match ans {
-            Ok(false) => break,
-            Err(e) => return Err(e),
-            _ => {}
+        let hub = hub.clone();
+        metrics::spawn(listener, move || {
+            let (sessions, parked) = hub.clients();
+            metrics::Gauges {
+                sessions,
+                parked,
+                rtt: hub.round_trip_times(),
+            }
+        });
+    }
+    if let Err(err) = runtime.startup.privileges.drop() {
+        eprintln!("ERROR: cannot drop privileges: {}", err);
+        std::process::exit(1);
+    }
+    notifier.ready();
+    report(&hub, &notifier);
+    let mut next_account = Instant::now() + ACCOUNT_INTERVAL;
+    loop {
+        // keep-alive sent by the main loop, so that it stops if the
+        // loop is stuck
+        notifier.watchdog();
+        if let Some(accounting) = &accounting {
+            if Instant::now() >= next_account {
+                hub.account();
+                if let Err(err) = accounting.save_if_due() {
+                    eprintln!("Failed to save traffic accounting: {}", err);
+                }
+                next_account = Instant::now() + ACCOUNT_INTERVAL;
+            }
+        }
+        // next parked session expiry, keep-alive or accounting, if any
+        let deadline = [
+            hub.expire(),
+            notifier.watchdog_deadline(),
+            accounting.as_ref().map(|_| next_account),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let (ready, signal, hangup) = wait_event(&listeners, &sigfile, &hupfile, deadline)?;
+        if signal {
+            crate::signals::consume_sigpipe(&mut sigfile);
+            break;
+        }
+        if hangup {
+            crate::signals::consume_sigpipe(&mut hupfile);
+            let path = service.config.as_deref();
+            reload(path, &base, &mut runtime, &settings, &gate, &hub);
+        }
+        for idx in ready {
+            let (stream, remote) = match listeners[idx].accept() {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Failed to accept on {}: {}", listeners[idx], err);
+                    continue;
+                }
+            };
+            // stream is closed when dropped
+            let slot = match Gate::admit(&gate, remote.ip()) {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    eprintln!("Connection from {} refused: {}", remote, reason);
+                    continue;
+                }
+            };
+            let hub = hub.clone();
+            let notifier = notifier.clone();
+            let settings = settings.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = serve_client(
+                    &hub,
+                    &notifier,
+                    &settings,
+                    stream,
+                    remote,
+                    resume_grace,
+                    slot,
+                ) {
+                    eprintln!("Client {}: {}", remote, err);
+                }
+            });
+        }
+    }
+    notifier.stopping();
+    hub.shutdown(EXIT_TIMEOUT);
+    if let Some(accounting) = &accounting {
+        // clients still connected after the timeout
+        hub.account();
+        if let Err(err) = accounting.save() {
+            eprintln!("Failed to save traffic accounting: {}", err);
         }
     }
     Ok(())
@@ -0,0 +1,239 @@
+// Contains state needed to resume a session after the TCP
+// connection has been lost: the server issues a ticket during
+// the handshake, a client reconnecting within the grace period
+// presents it along with the counter of the last data packet
+// it received. Both endpoints then retransmit data packets the
+// peer did not receive.
+
+use crate::metadata::ClientMetadata;
+use crate::transport::Peer;
+use std::collections::VecDeque;
+use std::io::Read;
+
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const TICKET_LEN: usize = 16;
+pub type Ticket = [u8; TICKET_LEN];
+
+// send an ack packet every ACK_INTERVAL data packets received
+const ACK_INTERVAL: u32 = 32;
+// max data packets kept for retransmission, oldest are dropped
+const MAX_UNACKED: usize = 1024;
+
+const RANDOM_FILE: &str = "/dev/urandom";
+
+pub fn new_ticket() -> std::io::Result<Ticket> {
+    let mut ticket: Ticket = [0; TICKET_LEN];
+    std::fs::File::open(RANDOM_FILE)?.read_exact(&mut ticket)?;
+    Ok(ticket)
+}
+
+// Compare tickets in constant time: the time taken must not tell
+// how many leading bytes of a guess are right
+pub fn same_ticket(a: &Ticket, b: &Ticket) -> bool {
+    let diff = a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+// Sent by the client when reconnecting
+pub struct Resume {
+    pub ticket: Ticket,
+    // counter of last data packet received by the client
+    pub received: u64,
+}
+
+// Data packets exchanged during a session, shared with the threads
+// reporting them
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    // bytes already added to the usage of the client identity
+    accounted_rx: AtomicU64,
+    accounted_tx: AtomicU64,
+}
+
+impl Traffic {
+    pub fn on_receive(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_send(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    // (rx bytes, tx bytes) since the previous call, callers must not
+    // run concurrently
+    pub fn take_unaccounted(&self) -> (u64, u64) {
+        let rx = self.rx_bytes.load(Ordering::Relaxed);
+        let tx = self.tx_bytes.load(Ordering::Relaxed);
+        let accounted_rx = self.accounted_rx.swap(rx, Ordering::Relaxed);
+        let accounted_tx = self.accounted_tx.swap(tx, Ordering::Relaxed);
+        (rx - accounted_rx, tx - accounted_tx)
+    }
+
+    // (rx packets, rx bytes, tx packets, tx bytes)
+    pub fn get(&self) -> (u64, u64, u64, u64) {
+        (
+            self.rx_packets.load(Ordering::Relaxed),
+            self.rx_bytes.load(Ordering::Relaxed),
+            self.tx_packets.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub struct Session {
+    // keep packets for retransmission?
+    resumable: bool,
+    // counter of last data packet sent
+    pub sent: u64,
+    // counter of last data packet received
+    pub received: u64,
+    // data packets received since last ack sent
+    not_acked: u32,
+    // data packets sent but not acknowledged yet: (counter, packet)
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    // since the session started, across resumptions
+    pub started: Instant,
+    pub traffic: Arc<Traffic>,
+}
+
+impl Session {
+    pub fn new(resumable: bool) -> Session {
+        Session {
+            resumable,
+            sent: 0,
+            received: 0,
+            not_acked: 0,
+            unacked: VecDeque::new(),
+            started: Instant::now(),
+            traffic: Arc::new(Traffic::default()),
+        }
+    }
+
+    // register data packet before sending it, return its counter
+    pub fn on_send(&mut self, pkt: &[u8]) -> u64 {
+        self.sent += 1;
+        self.traffic.on_send(pkt.len());
+        if self.resumable {
+            if self.unacked.len() == MAX_UNACKED {
+                self.unacked.pop_front();
+            }
+            self.unacked.push_back((self.sent, pkt.to_vec()));
+        }
+        self.sent
+    }
+
+    // register received data packet, return false if it
+    // is a duplicate (retransmitted after resumption)
+    pub fn on_receive(&mut self, counter: u64) -> bool {
+        if counter <= self.received {
+            return false;
+        }
+        self.received = counter;
+        self.not_acked += 1;
+        true
+    }
+
+    // should an ack packet be sent?
+    pub fn ack_needed(&self) -> bool {
+        self.resumable && self.not_acked >= ACK_INTERVAL
+    }
+
+    // ack packet has been sent
+    pub fn on_ack_sent(&mut self) {
+        self.not_acked = 0;
+    }
+
+    // peer received every data packet up to counter
+    pub fn on_ack(&mut self, counter: u64) {
+        while let Some((first, _)) = self.unacked.front() {
+            if *first > counter {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+    }
+
+    // data packets to be retransmitted after resumption
+    pub fn pending(&self) -> impl Iterator<Item = &(u64, Vec<u8>)> {
+        self.unacked.iter()
+    }
+}
+
+// Connected session, as listed to the administrator
+#[derive(Debug)]
+pub struct SessionInfo {
+    // of the client
+    pub identity: Option<String>,
+    // tunnel address of the client
+    pub ifaddr: Ipv4Addr,
+    pub remote: Peer,
+    pub uptime: Duration,
+    // (rx packets, rx bytes, tx packets, tx bytes)
+    pub traffic: (u64, u64, u64, u64),
+    // reported by the client during the handshake
+    pub metadata: Option<ClientMetadata>,
+}
+
+// Session waiting for its client to reconnect
+pub struct Parked {
+    pub ticket: Ticket,
+    // tunnel address of the client
+    pub ifaddr: Ipv4Addr,
+    pub session: Session,
+    pub expires: Instant,
+}
+
+// Why a new client cannot connect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conflict {
+    // tunnel address used by a connected client
+    Address,
+    // identity used by a connected client
+    Identity,
+}
+
+// Sessions known by the server, checked during the handshake
+pub trait Sessions {
+    // can a new client with ifaddr and identity connect?
+    fn conflict(&self, ifaddr: Ipv4Addr, identity: Option<&str>) -> Option<Conflict>;
+    // session parked by client ifaddr with the given ticket:
+    // return counter of last data packet received in it
+    fn parked(&self, ticket: &Ticket, ifaddr: Ipv4Addr) -> Option<u64>;
+}
+
+impl Sessions for Parked {
+    fn conflict(&self, _: Ipv4Addr, _: Option<&str>) -> Option<Conflict> {
+        None
+    }
+
+    fn parked(&self, ticket: &Ticket, ifaddr: Ipv4Addr) -> Option<u64> {
+        if same_ticket(ticket, &self.ticket) && ifaddr == self.ifaddr {
+            Some(self.session.received)
+        } else {
+            None
+        }
+    }
+}
+
+// No client connected nor session parked
+pub struct NoSessions;
+
+impl Sessions for NoSessions {
+    fn conflict(&self, _: Ipv4Addr, _: Option<&str>) -> Option<Conflict> {
+        None
+    }
+
+    fn parked(&self, _: &Ticket, _: Ipv4Addr) -> Option<u64> {
+        None
+    }
+}
@@ -5,7 +5,8 @@ use ctrlc;
 use nix::sys::signal::{SigSet, SigmaskHow, Signal};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::thread;
 
 const THREAD_NAME: &str = "sigthread";
@@ -16,6 +17,9 @@ static STARTED: AtomicBool = AtomicBool::new(false);
 // signal handling enabled? Or termination should be forced?
 static HANDLE_SIGNAL: AtomicBool = AtomicBool::new(false);
 
+// write end of the pipe filled on SIGHUP
+static HANGUP_FD: AtomicI32 = AtomicI32::new(-1);
+
 // should handle interrupt or let the process terminate?
 pub fn handle_interrupt(flag: bool) {
     HANDLE_SIGNAL.store(flag, Ordering::Relaxed);
@@ -78,6 +82,44 @@ pub fn spawn_sig_handler() -> std::fs::File {
     r
 }
 
+// only async-signal-safe calls here
+extern "C" fn on_hangup(_: nix::libc::c_int) {
+    let fd = HANGUP_FD.load(Ordering::Relaxed);
+    // safety: fd is the write end of the pipe, never closed. If
+    // the pipe is full a reload is pending anyway
+    unsafe {
+        nix::libc::write(fd, [1u8].as_ptr().cast(), 1);
+    }
+}
+
+/// Return a pipe filled with new data everytime SIGHUP is received,
+/// to be consumed with consume_sigpipe
+/// WARNING! Must be called at most once!
+pub fn spawn_hangup_handler() -> std::fs::File {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler};
+
+    if HANGUP_FD.load(Ordering::Relaxed) >= 0 {
+        panic!(
+            "Cannot call {} multiple times!",
+            "signals::spawn_hangup_handler"
+        );
+    }
+    let (r, w) = nix::unistd::pipe().unwrap();
+    // handler must never block
+    fcntl(w.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+    // write end is used until the process exits
+    HANGUP_FD.store(w.into_raw_fd(), Ordering::Relaxed);
+    let action = SigAction::new(
+        SigHandler::Handler(on_hangup),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // safety: the handler only calls write(2)
+    unsafe { sigaction(Signal::SIGHUP, &action) }.unwrap();
+    r.into()
+}
+
 // consume data waiting inside the
 pub fn consume_sigpipe(sigfile: &mut std::fs::File) {
     let mut buf: [u8; 8] = [0; 8];
@@ -0,0 +1,156 @@
+// Contains the integration with systemd: listening sockets passed by
+// socket activation (LISTEN_FDS) and service notifications sent over
+// NOTIFY_SOCKET (readiness, status and watchdog keep-alive). Both are
+// ignored when the server is not started by systemd.
+//
+// https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+// https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use crate::transport::Listener;
+
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+// variable intended for the current process, pid_name is the
+// variable holding the pid of the intended process (optional)
+fn env_for_me(name: &str, pid_name: &str) -> Option<String> {
+    let value = std::env::var(name).ok()?;
+    match std::env::var(pid_name) {
+        // intended for a parent process
+        Ok(pid) if pid.parse() != Ok(std::process::id()) => None,
+        _ => Some(value),
+    }
+}
+
+// Listening sockets passed by systemd, variables are removed so that
+// they are not inherited by child processes
+pub fn listeners_from_env() -> Result<Vec<Listener>, String> {
+    // LISTEN_PID is mandatory
+    let count = match (
+        std::env::var("LISTEN_PID"),
+        env_for_me("LISTEN_FDS", "LISTEN_PID"),
+    ) {
+        (Ok(_), Some(count)) => count,
+        _ => return Ok(vec![]),
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let count: RawFd = count
+        .parse()
+        .map_err(|_| format!("invalid LISTEN_FDS: {}", count))?;
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // not inherited by child processes either
+        nix::fcntl::fcntl(
+            fd,
+            nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+        )
+        .map_err(|e| format!("fd {}: {}", fd, e))?;
+        // safety: the descriptor has been passed to this process
+        // and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        listeners.push(Listener::from_fd(fd)?);
+    }
+    Ok(listeners)
+}
+
+pub struct Notifier {
+    // connected when created: still usable after chroot
+    socket: Option<UnixDatagram>,
+    // keep-alive interval requested by systemd
+    watchdog: Option<Duration>,
+    last_ping: Mutex<Instant>,
+}
+
+impl Notifier {
+    // Notifications sent to the socket at addr, "@" prefix for an
+    // abstract socket
+    pub fn new(addr: &str) -> std::io::Result<Notifier> {
+        let addr = match addr.strip_prefix('@') {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            None => SocketAddr::from_pathname(addr)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&addr)?;
+        Ok(Notifier {
+            socket: Some(socket),
+            watchdog: None,
+            last_ping: Mutex::new(Instant::now()),
+        })
+    }
+
+    // Notifications not sent anywhere
+    pub fn disabled() -> Notifier {
+        Notifier {
+            socket: None,
+            watchdog: None,
+            last_ping: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Notifier configured by systemd, disabled if not started by it
+    pub fn from_env() -> std::io::Result<Notifier> {
+        let mut notifier = match std::env::var("NOTIFY_SOCKET") {
+            Ok(addr) => Notifier::new(&addr)?,
+            Err(_) => return Ok(Notifier::disabled()),
+        };
+        // keep-alive messages are sent twice as often as required
+        notifier.watchdog = env_for_me("WATCHDOG_USEC", "WATCHDOG_PID")
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Ok(notifier)
+    }
+
+    pub fn with_watchdog(mut self, interval: Duration) -> Notifier {
+        self.watchdog = Some(interval);
+        self
+    }
+
+    fn send(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            // systemd may be restarting, not worth stopping the server
+            if let Err(err) = socket.send(state.as_bytes()) {
+                eprintln!("Failed to notify service manager: {}", err);
+            }
+        }
+    }
+
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    // one line status shown by systemctl
+    pub fn status(&self, text: &str) {
+        self.send(&format!("STATUS={}", text.replace('\n', " ")));
+    }
+
+    // time at which the next keep-alive is due, if enabled
+    pub fn watchdog_deadline(&self) -> Option<Instant> {
+        let last_ping = *self.last_ping.lock().unwrap();
+        self.watchdog.map(|interval| last_ping + interval)
+    }
+
+    // send keep-alive if it is due
+    pub fn watchdog(&self) {
+        if let Some(deadline) = self.watchdog_deadline() {
+            if Instant::now() >= deadline {
+                self.send("WATCHDOG=1");
+                *self.last_ping.lock().unwrap() = Instant::now();
+            }
+        }
+    }
+}
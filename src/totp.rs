@@ -0,0 +1,95 @@
+// Contains time-based one-time passwords (RFC 6238) used as second
+// authentication factor: HMAC-SHA1, 30 seconds steps, 6 digits.
+// Secrets are stored base32 encoded in the client database.
+
+use ring::hmac;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// accepted clock skew, in steps
+const MAX_SKEW: u64 = 1;
+
+pub fn decode_secret(value: &str) -> Result<Vec<u8>, String> {
+    let value = value.trim_end_matches('=').to_ascii_uppercase();
+    match base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &value) {
+        Some(secret) if !secret.is_empty() => Ok(secret),
+        _ => Err("bad base32 secret".to_string()),
+    }
+}
+
+// RFC 4226 HOTP value for the given counter
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    // dynamic truncation
+    let offset = (tag[tag.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes(tag[offset..offset + 4].try_into().unwrap()) & 0x7fffffff;
+    value % 10_u32.pow(digits)
+}
+
+// RFC 6238 step of a time, in seconds since the epoch
+pub fn step(unix_secs: u64) -> u64 {
+    unix_secs / STEP_SECS
+}
+
+// code expected at the given time
+pub fn code(secret: &[u8], unix_secs: u64) -> u32 {
+    hotp(secret, step(unix_secs), DIGITS)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Remember the last step used by each identity, so that
+// a code cannot be used twice
+#[derive(Default)]
+pub struct TotpVerifier {
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl TotpVerifier {
+    pub fn verify(&self, identity: &str, secret: &[u8], code: u32) -> bool {
+        self.verify_at(identity, secret, code, now())
+    }
+
+    // verify as if the current time was unix_secs
+    pub fn verify_at(&self, identity: &str, secret: &[u8], code: u32, unix_secs: u64) -> bool {
+        let now = step(unix_secs);
+        let mut used = self.used.lock().unwrap();
+        let last = used.get(identity).copied();
+        let step = (now.saturating_sub(MAX_SKEW)..=now + MAX_SKEW)
+            .filter(|step| last.is_none_or(|last| *step > last))
+            .find(|step| hotp(secret, *step, DIGITS) == code);
+        match step {
+            Some(step) => {
+                used.insert(identity.to_string(), step);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Ask the user for the current code
+pub fn prompt_code() -> std::result::Result<u32, Box<dyn std::error::Error>> {
+    print!("One-time password: ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Err("One-time password required".into());
+    }
+    let line = line.trim();
+    if line.len() != DIGITS as usize || !line.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("One-time password must be {} digits", DIGITS).into());
+    }
+    Ok(line.parse()?)
+}
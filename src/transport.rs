@@ -0,0 +1,296 @@
+// Contains the endpoints the server can listen on: TCP over IPv4 or
+// IPv6, and Unix domain sockets (for local tooling or sockets
+// forwarded by ssh). Connections accepted on any of them are served
+// the same way.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const UNIX_PREFIX: &str = "unix:";
+const BACKLOG: i32 = 128;
+
+// Where to listen: "ADDR:PORT", "[ADDR6]:PORT" or "unix:PATH"
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("{}: missing path", s)),
+            Some(path) => Ok(Endpoint::Unix(PathBuf::from(path))),
+            None => SocketAddr::from_str(s)
+                .map(Endpoint::Tcp)
+                .map_err(|e| format!("{}: {}", s, e)),
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+// IPv6 sockets only accept IPv6 connections, so that the same port
+// can also be bound by an IPv4 endpoint
+fn bind_tcp6(addr: std::net::SocketAddrV6) -> nix::Result<TcpListener> {
+    use nix::sys::socket::{self, sockopt, AddressFamily, Backlog, SockFlag, SockType};
+
+    let fd = socket::socket(
+        AddressFamily::Inet6,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // same as std::net::TcpListener::bind
+    socket::setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    socket::setsockopt(&fd, sockopt::Ipv6V6Only, &true)?;
+    socket::bind(fd.as_raw_fd(), &socket::SockaddrIn6::from(addr))?;
+    socket::listen(&fd, Backlog::new(BACKLOG)?)?;
+    Ok(TcpListener::from(fd))
+}
+
+// A socket file left by a server that did not exit cleanly is
+// removed, a socket still accepting connections is not
+pub fn remove_stale_socket(path: &PathBuf) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Ok(()),
+            Err(_) => std::fs::remove_file(path),
+        },
+        _ => Ok(()),
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // socket file removed when dropped, None if the socket has
+    // been created by someone else
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    pub fn bind(endpoint: &Endpoint) -> std::io::Result<Listener> {
+        match endpoint {
+            Endpoint::Tcp(SocketAddr::V6(addr)) => Ok(Listener::Tcp(bind_tcp6(*addr)?)),
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(
+                    UnixListener::bind(path)?,
+                    Some(path.clone()),
+                ))
+            }
+        }
+    }
+
+    // Listening socket inherited from the parent process
+    pub fn from_fd(fd: OwnedFd) -> Result<Listener, String> {
+        use nix::sys::socket::{
+            self, sockopt, AddressFamily, SockType, SockaddrLike, SockaddrStorage,
+        };
+
+        let raw_fd = fd.as_raw_fd();
+        let stream = socket::getsockopt(&fd, sockopt::SockType) == Ok(SockType::Stream);
+        let listening = socket::getsockopt(&fd, sockopt::AcceptConn) == Ok(true);
+        if !stream || !listening {
+            return Err(format!("fd {}: not a listening stream socket", raw_fd));
+        }
+        let addr = socket::getsockname::<SockaddrStorage>(raw_fd)
+            .map_err(|e| format!("fd {}: {}", raw_fd, e))?;
+        match addr.family() {
+            Some(AddressFamily::Inet) | Some(AddressFamily::Inet6) => {
+                Ok(Listener::Tcp(TcpListener::from(fd)))
+            }
+            Some(AddressFamily::Unix) => Ok(Listener::Unix(UnixListener::from(fd), None)),
+            _ => Err(format!("fd {}: unsupported address family", raw_fd)),
+        }
+    }
+
+    pub fn accept(&self) -> std::io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(addr)))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix(listener, _) => listener.as_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "(tcp socket)"),
+            },
+            Listener::Unix(listener, _) => {
+                match listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+                {
+                    Some(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+                    None => write!(f, "(unix socket)"),
+                }
+            }
+        }
+    }
+}
+
+// Remote end of an accepted connection
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    // peers of Unix sockets are usually unnamed
+    Unix,
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "(unix socket)"),
+        }
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> std::io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    // Round trip time estimated by the kernel (TCP only)
+    pub fn rtt(&self) -> Option<Duration> {
+        use nix::libc;
+
+        let Stream::Tcp(stream) = self else {
+            return None;
+        };
+        let mut info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // safety: info is large enough for len bytes
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                info.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+        // safety: zeroed, then filled by the kernel
+        let info = unsafe { info.assume_init() };
+        Some(Duration::from_micros(info.tcpi_rtt as u64))
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -0,0 +1,97 @@
+// Traffic usage per client identity, quotas and state file
+
+use rust_tcp_vpn::accounting::{self, Accounting, Bytes, Quota, Usage};
+use rust_tcp_vpn::session::Traffic;
+
+#[test]
+fn dates() {
+    assert_eq!(accounting::parse_day("1970-01-01"), Ok(0));
+    assert_eq!(accounting::parse_day("2000-03-01"), Ok(11017));
+    for day in [-1, 0, 59, 365, 11016, 11017, 20745, 100000] {
+        assert_eq!(accounting::parse_day(&accounting::format_day(day)), Ok(day));
+    }
+    assert_eq!(accounting::format_day(20745), "2026-10-19");
+    assert_eq!(accounting::format_month(20745), "2026-10");
+    for date in ["2026-02-29", "2026-13-01", "2026-10", "2026-1o-19", ""] {
+        assert!(accounting::parse_day(date).is_err(), "{}", date);
+    }
+    assert!(accounting::parse_day("2024-02-29").is_ok());
+}
+
+#[test]
+fn periods_roll_over() {
+    let oct_31 = accounting::parse_day("2026-10-31").unwrap();
+    let mut usage = Usage::default();
+    usage.add(oct_31, 100, 200);
+    usage.add(oct_31, 1, 2);
+    assert_eq!(usage.today, Bytes { rx: 101, tx: 202 });
+    // new day, same month
+    usage.add(oct_31 - 1, 10, 0);
+    assert_eq!(usage.today, Bytes { rx: 10, tx: 0 });
+    assert_eq!(usage.this_month, Bytes { rx: 111, tx: 202 });
+    // new month
+    usage.add(oct_31 + 1, 5, 5);
+    assert_eq!(usage.today.sum(), 10);
+    assert_eq!(usage.this_month.sum(), 10);
+    assert_eq!(usage.total, Bytes { rx: 116, tx: 207 });
+    let mut later = usage;
+    later.roll(oct_31 + 40);
+    assert_eq!(later.this_month, Bytes::default());
+    assert_eq!(later.total, usage.total);
+}
+
+#[test]
+fn quotas() {
+    let mut usage = Usage::default();
+    usage.add(0, 600, 300);
+    let none = Quota::default();
+    assert_eq!(none.exceeded(&usage), None);
+    let daily = Quota {
+        daily: Some(1000),
+        monthly: None,
+    };
+    assert_eq!(daily.exceeded(&usage), None);
+    usage.add(0, 0, 100);
+    assert_eq!(daily.exceeded(&usage), Some("daily"));
+    let monthly = Quota {
+        daily: None,
+        monthly: Some(1500),
+    };
+    usage.add(1, 500, 0);
+    assert_eq!(daily.exceeded(&usage), None);
+    assert_eq!(monthly.exceeded(&usage), Some("monthly"));
+}
+
+#[test]
+fn saved_and_loaded() {
+    let path = std::env::temp_dir().join(format!("vpn-accounting-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let accounting = Accounting::open(&path).unwrap();
+    // created when opened
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}\n");
+    let traffic = Traffic::default();
+    traffic.on_receive(1000);
+    traffic.on_send(400);
+    let usage = accounting.record("alice", &traffic);
+    assert_eq!(usage.today, Bytes { rx: 1000, tx: 400 });
+    // only new traffic is added
+    traffic.on_send(100);
+    accounting.record("alice", &traffic);
+    accounting.record("alice", &traffic);
+    accounting.record("bob", &Traffic::default());
+    accounting.save().unwrap();
+    drop(accounting);
+    // replaced by a temporary file
+    let tmp = path.with_file_name(format!(".vpn-accounting-{}.json.tmp", std::process::id()));
+    assert!(!tmp.exists());
+
+    let accounting = Accounting::open(&path).unwrap();
+    let usage = accounting.usage_of("alice");
+    assert_eq!(usage.total, Bytes { rx: 1000, tx: 500 });
+    assert_eq!(usage.day, accounting::today());
+    assert_eq!(accounting.usage().len(), 1);
+    assert_eq!(accounting.usage_of("carol").total, Bytes::default());
+    std::fs::write(&path, "{\"alice\": {\"day\": \"2026-10-19\"}}").unwrap();
+    assert!(Accounting::open(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
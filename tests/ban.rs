@@ -0,0 +1,61 @@
+// Bans of sources failing handshakes
+
+use rust_tcp_vpn::ban::{BanPolicy, Bans};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+fn bans(failures: u32, duration: Duration) -> Bans {
+    Bans::new(Some(BanPolicy { failures, duration }))
+}
+
+#[test]
+fn banned_after_threshold() {
+    let bans = bans(3, Duration::from_secs(60));
+    assert_eq!(bans.failure(SOURCE), None);
+    assert_eq!(bans.failure(SOURCE), None);
+    assert!(bans.check(SOURCE).is_ok());
+    assert_eq!(bans.failure(SOURCE), Some(Duration::from_secs(60)));
+    assert!(bans.check(SOURCE).is_err());
+    assert!(bans.check(OTHER).is_ok());
+    let list = bans.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].source, SOURCE);
+    assert_eq!(list[0].count, 1);
+}
+
+#[test]
+fn ban_time_escalates() {
+    let bans = bans(1, Duration::from_millis(20));
+    assert_eq!(bans.failure(SOURCE), Some(Duration::from_millis(20)));
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(bans.check(SOURCE).is_ok());
+    assert!(bans.list().is_empty());
+    assert_eq!(bans.failure(SOURCE), Some(Duration::from_millis(40)));
+    assert_eq!(bans.failure(SOURCE), Some(Duration::from_millis(80)));
+}
+
+#[test]
+fn clear_lifts_bans() {
+    let bans = bans(1, Duration::from_secs(60));
+    bans.failure(SOURCE);
+    bans.failure(OTHER);
+    assert_eq!(bans.clear(Some(SOURCE)), 1);
+    assert!(bans.check(SOURCE).is_ok());
+    assert!(bans.check(OTHER).is_err());
+    assert_eq!(bans.clear(None), 1);
+    assert!(bans.check(OTHER).is_ok());
+    // escalation starts over
+    assert_eq!(bans.failure(OTHER), Some(Duration::from_secs(60)));
+}
+
+#[test]
+fn disabled_never_bans() {
+    let bans = Bans::new(None);
+    for _ in 0..10 {
+        assert_eq!(bans.failure(SOURCE), None);
+    }
+    assert!(bans.check(SOURCE).is_ok());
+}
@@ -0,0 +1,139 @@
+// Certificate authentication of clients and servers, with the
+// certificates of tests/data (see gen.sh)
+
+use rust_tcp_vpn::certauth::{CertVerifier, Credentials, Role};
+use rust_tcp_vpn::dns::DnsConfig;
+use rust_tcp_vpn::handshake::{
+    ClientSettings, ClientStart, Failure, RejectReason, ServerSettings, ServerStart,
+};
+use rust_tcp_vpn::session::NoSessions;
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+fn data(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+fn verifier() -> CertVerifier {
+    CertVerifier::load(&data("ca.pem"), None).unwrap()
+}
+
+// "client" or "server"
+fn credentials(name: &str) -> Credentials {
+    let cert = data(&format!("{}.pem", name));
+    let key = data(&format!("{}.key", name));
+    Credentials::load(&cert, &key).unwrap()
+}
+
+fn server_settings(credentials: Option<Credentials>) -> ServerSettings {
+    ServerSettings {
+        ifaddr: IpAddr::from([10, 0, 0, 1]),
+        netmask: 24,
+        dns: RwLock::new(DnsConfig::default()),
+        clients: None,
+        verifier: Some(verifier()),
+        credentials,
+    }
+}
+
+fn client_settings(credentials: Option<Credentials>) -> ClientSettings {
+    ClientSettings {
+        ifaddr: IpAddr::from([10, 0, 0, 2]),
+        netmask: 24,
+        identity: None,
+        credentials,
+        verifier: Some(verifier()),
+        metadata: None,
+    }
+}
+
+fn expect_reject<T>(ans: Result<T, Failure>, expected: RejectReason) {
+    match ans {
+        Err(Failure::Reject(reason, _)) if reason == expected => {}
+        Err(failure) => panic!("expected reject {:?}, got {:?}", expected, failure),
+        Ok(_) => panic!("expected reject {:?}, got success", expected),
+    }
+}
+
+#[test]
+fn certificates_are_checked_for_their_role() {
+    let verifier = verifier();
+    let client = credentials("client");
+    let server = credentials("server");
+    assert_eq!(
+        verifier.verify(&client.cert, Role::Client).unwrap(),
+        "alice"
+    );
+    assert_eq!(
+        verifier.verify(&server.cert, Role::Server).unwrap(),
+        "vpn.example"
+    );
+    assert!(verifier.verify(&client.cert, Role::Server).is_err());
+    assert!(verifier.verify(&server.cert, Role::Client).is_err());
+    // signatures of one endpoint are not valid for the other
+    let challenge = [3; 32];
+    let sig = client.sign(&challenge, Role::Client).unwrap();
+    assert!(CertVerifier::verify_signature(
+        &client.cert,
+        &challenge,
+        &sig,
+        Role::Client
+    ));
+    assert!(!CertVerifier::verify_signature(
+        &client.cert,
+        &challenge,
+        &sig,
+        Role::Server
+    ));
+}
+
+#[test]
+fn ca_must_be_a_ca() {
+    assert!(CertVerifier::load(&data("client.pem"), None).is_err());
+}
+
+#[test]
+fn mutual_authentication() {
+    let server = server_settings(Some(credentials("server")));
+    let client = client_settings(Some(credentials("client")));
+    let (client_state, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+    let (server_state, hello) = ServerStart::new(&server, None, &NoSessions)
+        .receive_hello(hello)
+        .unwrap();
+    let client_state = client_state.receive_hello(hello).unwrap();
+    assert_eq!(client_state.info().identity.as_deref(), Some("vpn.example"));
+    let (_, confirm) = client_state.confirm(None).unwrap();
+    let info = server_state.receive_confirm(confirm).unwrap();
+    assert_eq!(info.identity.as_deref(), Some("alice"));
+    // checked again when the revocation list is reloaded
+    assert_eq!(info.cert, Some(credentials("client").cert));
+}
+
+#[test]
+fn client_rejects_unauthenticated_server() {
+    // no certificate, a client certificate
+    for credentials in [None, Some(credentials("client"))] {
+        let server = server_settings(credentials);
+        let client = client_settings(Some(self::credentials("client")));
+        let (client_state, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+        let (_, hello) = ServerStart::new(&server, None, &NoSessions)
+            .receive_hello(hello)
+            .unwrap();
+        expect_reject(client_state.receive_hello(hello), RejectReason::AuthFailed);
+    }
+}
+
+#[test]
+fn server_rejects_server_certificate() {
+    let server = server_settings(Some(credentials("server")));
+    let client = client_settings(Some(credentials("server")));
+    let (_, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+    expect_reject(
+        ServerStart::new(&server, None, &NoSessions).receive_hello(hello),
+        RejectReason::AuthFailed,
+    );
+}
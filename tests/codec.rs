@@ -0,0 +1,122 @@
+// Property tests for the codec: every message survives an
+// encode/decode round trip and decoding arbitrary bytes never panics
+
+use proptest::prelude::*;
+use rust_tcp_vpn::codec::{self, CodecError, Extension, Frame, HandshakeMsg};
+use std::net::Ipv4Addr;
+
+fn extension() -> impl Strategy<Value = Extension> + Clone {
+    (any::<u16>(), prop::collection::vec(any::<u8>(), 0..64))
+        .prop_map(|(kind, value)| Extension { kind, value })
+}
+
+fn handshake_msg() -> impl Strategy<Value = HandshakeMsg> {
+    let extensions = prop::collection::vec(extension(), 0..8);
+    prop_oneof![
+        (any::<u32>(), any::<u32>(), extensions.clone()).prop_map(
+            |(ifaddr, netmask, extensions)| HandshakeMsg::ClientHello {
+                ifaddr: Ipv4Addr::from(ifaddr),
+                netmask,
+                extensions,
+            }
+        ),
+        (any::<u32>(), extensions.clone()).prop_map(|(ifaddr, extensions)| {
+            HandshakeMsg::ServerHello {
+                ifaddr: Ipv4Addr::from(ifaddr),
+                extensions,
+            }
+        }),
+        (any::<u32>(), extensions)
+            .prop_map(|(status, extensions)| HandshakeMsg::ClientConfirm { status, extensions }),
+        (any::<u32>(), "\\PC{0,64}")
+            .prop_map(|(reason, text)| HandshakeMsg::Reject { reason, text }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn handshake_roundtrip(msg in handshake_msg()) {
+        let mut buf = vec![];
+        codec::encode_handshake(&msg, &mut buf).unwrap();
+        let (decoded, len) = codec::decode_handshake(&buf).unwrap();
+        prop_assert_eq!(decoded, msg);
+        prop_assert_eq!(len, buf.len());
+    }
+
+    #[test]
+    fn handshake_truncated(msg in handshake_msg(), cut in any::<prop::sample::Index>()) {
+        let mut buf = vec![];
+        codec::encode_handshake(&msg, &mut buf).unwrap();
+        let cut = cut.index(buf.len());
+        match codec::decode_handshake(&buf[..cut]) {
+            Err(CodecError::Incomplete(needed)) => prop_assert!(needed > cut),
+            ans => prop_assert!(false, "unexpected {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn data_frame_roundtrip(
+        counter in any::<u64>(),
+        packet in prop::collection::vec(any::<u8>(), 0..=codec::MAX_PACKET_LEN),
+    ) {
+        let frame = Frame::Data { counter, packet: &packet };
+        let mut buf = vec![];
+        codec::encode_frame(&frame, &mut buf).unwrap();
+        let (decoded, len) = codec::decode_frame(&buf).unwrap();
+        prop_assert_eq!(decoded, frame);
+        prop_assert_eq!(len, buf.len());
+    }
+
+    #[test]
+    fn control_frame_roundtrip(value in any::<u32>(), exit in any::<bool>()) {
+        let frame = if exit {
+            Frame::Exit { reason: value }
+        } else {
+            Frame::Ack { counter: value as u64 }
+        };
+        let mut buf = vec![];
+        codec::encode_frame(&frame, &mut buf).unwrap();
+        prop_assert_eq!(codec::decode_frame(&buf).unwrap(), (frame, buf.len()));
+    }
+
+    #[test]
+    fn decode_arbitrary(buf in prop::collection::vec(any::<u8>(), 0..256)) {
+        if let Ok((_, len)) = codec::decode_handshake(&buf) {
+            prop_assert!(len <= buf.len());
+        }
+        if let Ok((_, len)) = codec::decode_frame(&buf) {
+            prop_assert!(len <= buf.len());
+        }
+    }
+
+    #[test]
+    fn oversized_packet_rejected(len in (codec::MAX_PACKET_LEN as u32 + 1)..) {
+        let mut buf = 1_u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&len.to_be_bytes());
+        let ans = codec::decode_frame(&buf);
+        prop_assert!(matches!(ans, Err(CodecError::TooLong { .. })), "unexpected {:?}", ans);
+    }
+}
+
+#[test]
+fn magic_checked_before_packet_id() {
+    let reject = HandshakeMsg::Reject {
+        reason: 1,
+        text: String::new(),
+    };
+    let mut buf = vec![];
+    codec::encode_handshake(&reject, &mut buf).unwrap();
+    // corrupted magic equal to a packet id
+    let mut corrupted = buf.clone();
+    corrupted[..4].copy_from_slice(&4_u32.to_be_bytes());
+    assert_eq!(
+        codec::decode_handshake(&corrupted),
+        Err(CodecError::BadMagic(4))
+    );
+    // valid magic, unknown packet id
+    buf[4..8].copy_from_slice(&9_u32.to_be_bytes());
+    assert_eq!(
+        codec::decode_handshake(&buf),
+        Err(CodecError::UnknownType(9))
+    );
+}
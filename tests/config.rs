@@ -0,0 +1,139 @@
+// Settings of the configuration file reloaded on SIGHUP
+
+use rust_tcp_vpn::ban::BanPolicy;
+use rust_tcp_vpn::config::{self, Runtime, Startup};
+use rust_tcp_vpn::dns::DnsConfig;
+use rust_tcp_vpn::limits::Limits;
+use rust_tcp_vpn::logging::Level;
+use rust_tcp_vpn::privileges::Privileges;
+use rust_tcp_vpn::transport::Endpoint;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// as given on the command line
+fn base() -> Runtime {
+    Runtime {
+        log_level: Level::Info,
+        limits: Limits {
+            max_sessions: Some(10),
+            max_handshakes: Some(32),
+            max_source_handshakes: Some(4),
+            rate: None,
+            ban: None,
+        },
+        dns: DnsConfig {
+            nameservers: vec![IpAddr::from([10, 0, 0, 53])],
+            search: vec!["vpn.example".to_string()],
+        },
+        startup: Startup {
+            listen: vec![Endpoint::from_str("0.0.0.0:7000").unwrap()],
+            control: None,
+            metrics: None,
+            privileges: Privileges::default(),
+        },
+    }
+}
+
+#[test]
+fn file_overrides_command_line() {
+    let content = "
+        # comment
+        log-level debug
+        max-sessions 0      # no limit
+        max-conn-rate 20
+        ban-after 3
+        push-dns 10.0.0.54
+        push-dns 10.0.0.55
+    ";
+    let config = config::parse(content, &base()).unwrap();
+    assert_eq!(config.log_level, Level::Debug);
+    assert_eq!(config.limits.max_sessions, None);
+    assert_eq!(config.limits.max_handshakes, Some(32));
+    assert_eq!(config.limits.rate, Some(20));
+    assert_eq!(
+        config.limits.ban,
+        Some(BanPolicy {
+            failures: 3,
+            duration: Duration::from_secs(60),
+        })
+    );
+    let nameservers: Vec<IpAddr> = vec![IpAddr::from([10, 0, 0, 54]), IpAddr::from([10, 0, 0, 55])];
+    assert_eq!(config.dns.nameservers, nameservers);
+    // not in the file
+    assert_eq!(config.dns.search, base().dns.search);
+    assert_eq!(config::parse("", &base()).unwrap(), base());
+}
+
+#[test]
+fn ban_settings() {
+    let mut banning = base();
+    banning.limits.ban = Some(BanPolicy {
+        failures: 5,
+        duration: Duration::from_secs(30),
+    });
+    let config = config::parse("ban-time 120", &banning).unwrap();
+    assert_eq!(config.limits.ban.unwrap().failures, 5);
+    assert_eq!(
+        config.limits.ban.unwrap().duration,
+        Duration::from_secs(120)
+    );
+    let config = config::parse("ban-after 0", &banning).unwrap();
+    assert_eq!(config.limits.ban, None);
+}
+
+#[test]
+fn invalid_lines() {
+    for content in [
+        "log-level verbose",
+        "max-handshakes 0",
+        "max-sessions -1",
+        "ban-time 0",
+        "push-dns vpn.example",
+        "push-domain",
+        "listen 0.0.0.0:70000",
+        "metrics localhost",
+        "control",
+    ] {
+        let err = config::parse(content, &base()).unwrap_err();
+        assert!(err.starts_with("line 1: "), "{}", err);
+    }
+}
+
+#[test]
+fn startup_settings() {
+    let content = "
+        listen 0.0.0.0:7001
+        listen unix:/run/vpn.sock
+        metrics 9100
+        user nobody
+    ";
+    let config = config::parse(content, &base()).unwrap();
+    assert_eq!(
+        config.startup.listen,
+        [
+            Endpoint::from_str("0.0.0.0:7001").unwrap(),
+            Endpoint::Unix(PathBuf::from("/run/vpn.sock")),
+        ]
+    );
+    assert_eq!(
+        config.startup.metrics,
+        Some("127.0.0.1:9100".parse().unwrap())
+    );
+    assert_eq!(config.startup.privileges.user.as_deref(), Some("nobody"));
+    assert_eq!(config.startup.control, None);
+    assert_eq!(
+        config::restart_needed(&base(), &config),
+        ["listen", "metrics", "user"]
+    );
+    // settings changed while running are not reported
+    let config = config::parse("log-level debug\nmax-sessions 3", &base()).unwrap();
+    assert!(config::restart_needed(&base(), &config).is_empty());
+    let config = config::parse("chroot /var/empty\ncontrol /run/vpn.ctl", &base()).unwrap();
+    assert_eq!(
+        config::restart_needed(&base(), &config),
+        ["control", "chroot"]
+    );
+    assert!(config::parse("group nogroup", &base()).is_err());
+}
@@ -0,0 +1,92 @@
+// Requests of the control socket
+
+use rust_tcp_vpn::control::{self, ControlSocket, Handler};
+use rust_tcp_vpn::logging::{self, Level};
+use rust_tcp_vpn::metadata::ClientMetadata;
+use rust_tcp_vpn::session::SessionInfo;
+use rust_tcp_vpn::transport::Peer;
+use serde_json::{json, Value};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Stub;
+
+impl Handler for Stub {
+    fn handle(&self, cmd: &str, request: &Value) -> Result<Value, String> {
+        match cmd {
+            "echo" => Ok(json!({ "name": control::field(request, "name")? })),
+            "nothing" => Ok(Value::Null),
+            _ => Err(format!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+#[test]
+fn responses() {
+    let ans = control::respond(&Stub, r#"{"cmd": "echo", "name": "a"}"#);
+    assert_eq!(ans, json!({ "ok": true, "name": "a" }));
+    let ans = control::respond(&Stub, r#"{"cmd": "nothing"}"#);
+    assert_eq!(ans, json!({ "ok": true }));
+    let ans = control::respond(&Stub, r#"{"cmd": "echo", "name": 3}"#);
+    assert_eq!(
+        ans,
+        json!({ "ok": false, "error": "name must be a string" })
+    );
+    let ans = control::respond(&Stub, r#"{"cmd": "other"}"#);
+    assert_eq!(ans["ok"], json!(false));
+    assert_eq!(control::respond(&Stub, "{}")["ok"], json!(false));
+    assert_eq!(control::respond(&Stub, "sessions")["ok"], json!(false));
+}
+
+#[test]
+fn session_metadata() {
+    let mut session = SessionInfo {
+        identity: Some("alice".to_string()),
+        ifaddr: Ipv4Addr::new(10, 0, 0, 2),
+        remote: Peer::Tcp("192.0.2.1:40000".parse().unwrap()),
+        uptime: Duration::from_secs(5),
+        traffic: (1, 2, 3, 4),
+        metadata: None,
+    };
+    let ans = control::session_json(&session);
+    assert_eq!(ans["hostname"], Value::Null);
+    assert_eq!(ans["label"], Value::Null);
+    session.metadata = Some(ClientMetadata {
+        hostname: Some("laptop".to_string()),
+        os: Some("Linux 6.1".to_string()),
+        version: Some("0.1.0".to_string()),
+        label: None,
+    });
+    let ans = control::session_json(&session);
+    assert_eq!(ans["identity"], json!("alice"));
+    assert_eq!(ans["hostname"], json!("laptop"));
+    assert_eq!(ans["os"], json!("Linux 6.1"));
+    assert_eq!(ans["version"], json!("0.1.0"));
+    assert_eq!(ans["label"], Value::Null);
+}
+
+#[test]
+fn log_level() {
+    let ans = control::respond(&Stub, r#"{"cmd": "log-level", "level": "debug"}"#);
+    assert_eq!(ans, json!({ "ok": true, "level": "debug" }));
+    assert_eq!(logging::level(), Level::Debug);
+    let ans = control::respond(&Stub, r#"{"cmd": "log-level", "level": "verbose"}"#);
+    assert_eq!(ans["ok"], json!(false));
+    control::respond(&Stub, r#"{"cmd": "log-level", "level": "info"}"#);
+    assert_eq!(logging::level(), Level::Info);
+}
+
+#[test]
+fn socket() {
+    let path = std::env::temp_dir().join(format!("vpn-control-{}.sock", std::process::id()));
+    let socket = ControlSocket::spawn(&path, Arc::new(Stub)).unwrap();
+    // owner only, whatever the umask
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let ans = control::send_request(&path, &json!({ "cmd": "echo", "name": "b" })).unwrap();
+    assert_eq!(ans, json!({ "ok": true, "name": "b" }));
+    drop(socket);
+    assert!(!path.exists());
+}
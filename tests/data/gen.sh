@@ -0,0 +1,23 @@
+#!/bin/sh
+# Certificates used by tests/certauth.rs, valid for 100 years:
+# a CA, a client certificate (clientAuth) and a server certificate
+# (serverAuth) it issued
+set -e
+cd "$(dirname "$0")"
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1"
+}
+key ca.key
+openssl req -x509 -new -key ca.key -subj /CN=test-ca -days 36500 -out ca.pem \
+    -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign,cRLSign
+issue() {
+    key "$1.key"
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$1.csr"
+    printf 'basicConstraints=CA:FALSE\nkeyUsage=digitalSignature\nextendedKeyUsage=%s\n' "$3" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days 36500 -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+issue client alice clientAuth
+issue server vpn.example serverAuth
+rm -f ca.srl ca.key
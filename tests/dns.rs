@@ -0,0 +1,94 @@
+// DNS settings pushed by the server, applied to a temporary
+// resolv.conf and through a hook, then restored
+
+use rust_tcp_vpn::dns::{self, DnsApply, DnsConfig};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("vpn-dns-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn pushed() -> DnsConfig {
+    DnsConfig {
+        nameservers: vec![IpAddr::from([10, 8, 0, 1]), IpAddr::from([10, 8, 0, 2])],
+        search: vec!["vpn.example".to_string(), "example.org".to_string()],
+    }
+}
+
+#[test]
+fn file_restored() {
+    let dir = temp_dir("file");
+    let path = dir.join("resolv.conf");
+    std::fs::write(&path, "nameserver 192.0.2.53\n").unwrap();
+    let guard = dns::apply(&DnsApply::File(path.clone()), &pushed()).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# generated by rust-tcp-vpn\nnameserver 10.8.0.1\nnameserver 10.8.0.2\n\
+         search vpn.example example.org\n"
+    );
+    dns::restore(guard);
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "nameserver 192.0.2.53\n"
+    );
+    // missing file is removed
+    std::fs::remove_file(&path).unwrap();
+    let guard = dns::apply(&DnsApply::File(path.clone()), &pushed()).unwrap();
+    assert!(path.exists());
+    dns::restore(guard);
+    assert!(!path.exists());
+    // nothing pushed, nothing written
+    let guard = dns::apply(&DnsApply::File(path.clone()), &DnsConfig::default()).unwrap();
+    assert!(!path.exists());
+    dns::restore(guard);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_apply_leaves_nothing() {
+    let dir = temp_dir("failed");
+    let path = dir.join("missing").join("resolv.conf");
+    assert!(dns::apply(&DnsApply::File(path.clone()), &pushed()).is_err());
+    assert!(!path.exists());
+    // previous settings are still restored after a failure
+    let ok = dir.join("resolv.conf");
+    std::fs::write(&ok, "nameserver 192.0.2.53\n").unwrap();
+    let guard = dns::apply(&DnsApply::File(ok.clone()), &pushed()).unwrap();
+    assert!(dns::apply(&DnsApply::File(path), &pushed()).is_err());
+    dns::restore(guard);
+    assert_eq!(
+        std::fs::read_to_string(&ok).unwrap(),
+        "nameserver 192.0.2.53\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn hook_invoked_up_and_down() {
+    let dir = temp_dir("hook");
+    let log = dir.join("log");
+    let hook = dir.join("hook");
+    let script = format!(
+        "#!/bin/sh\necho \"$1 $VPN_DNS_SERVERS/$VPN_DNS_SEARCH\" >> {}\n",
+        log.display()
+    );
+    std::fs::write(&hook, script).unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let guard = dns::apply(&DnsApply::Hook(hook.clone()), &pushed()).unwrap();
+    dns::restore(guard);
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "up 10.8.0.1 10.8.0.2/vpn.example example.org\n\
+         down 10.8.0.1 10.8.0.2/vpn.example example.org\n"
+    );
+    // failing hook
+    std::fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+    assert!(dns::apply(&DnsApply::Hook(hook.clone()), &pushed()).is_err());
+    assert!(dns::apply(&DnsApply::Hook(dir.join("missing")), &pushed()).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,76 @@
+// Packet filter rules of the client database
+
+use rust_tcp_vpn::filter::{Direction, Filter, Rule};
+use std::str::FromStr;
+
+// IPv4 packet with a transport header starting with the ports
+fn packet(protocol: u8, src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+    let mut pkt = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, protocol, 0, 0];
+    pkt.extend_from_slice(&src);
+    pkt.extend_from_slice(&dst);
+    pkt.extend_from_slice(&sport.to_be_bytes());
+    pkt.extend_from_slice(&dport.to_be_bytes());
+    pkt.extend_from_slice(&[0; 4]);
+    pkt
+}
+
+fn filter(rules: &[&str]) -> Filter {
+    Filter {
+        rules: rules.iter().map(|r| Rule::from_str(r).unwrap()).collect(),
+    }
+}
+
+const CLIENT: [u8; 4] = [10, 0, 0, 2];
+const HOST: [u8; 4] = [10, 1, 0, 5];
+const OTHER: [u8; 4] = [10, 1, 0, 6];
+
+#[test]
+fn parse_rules() {
+    let rule = Rule::from_str("allow tcp from 10.0.0.2 to 10.1.0.0/24 port 8000-8080").unwrap();
+    assert_eq!(rule.protocol, Some(6));
+    assert_eq!(rule.to.unwrap().prefix, 24);
+    assert_eq!(rule.ports.unwrap().last, 8080);
+    assert!(Rule::from_str("deny").is_ok());
+    assert!(Rule::from_str("allow 47 to 10.1.0.5").is_ok());
+    assert!(Rule::from_str("accept tcp").is_err());
+    assert!(Rule::from_str("allow icmp port 22").is_err());
+    assert!(Rule::from_str("allow tcp port 90-80").is_err());
+    assert!(Rule::from_str("allow tcp to").is_err());
+    assert!(Rule::from_str("allow sctp").is_err());
+}
+
+#[test]
+fn no_rules_allow_everything() {
+    let filter = Filter::default();
+    assert!(filter.allows(&packet(6, CLIENT, HOST, 1000, 22), Direction::FromClient));
+    assert!(filter.allows(&[0x60; 40], Direction::ToClient));
+}
+
+#[test]
+fn first_matching_rule_applies() {
+    let filter = filter(&["deny tcp to 10.1.0.6", "allow tcp to 10.1.0.0/24 port 22"]);
+    assert!(filter.allows(&packet(6, CLIENT, HOST, 1000, 22), Direction::FromClient));
+    assert!(!filter.allows(&packet(6, CLIENT, OTHER, 1000, 22), Direction::FromClient));
+    // no matching rule
+    assert!(!filter.allows(&packet(6, CLIENT, HOST, 1000, 80), Direction::FromClient));
+    assert!(!filter.allows(&packet(17, CLIENT, HOST, 1000, 22), Direction::FromClient));
+}
+
+#[test]
+fn replies_match_swapped_rule() {
+    let filter = filter(&["allow tcp to 10.1.0.5 port 22"]);
+    assert!(filter.allows(&packet(6, HOST, CLIENT, 22, 1000), Direction::ToClient));
+    assert!(!filter.allows(&packet(6, HOST, CLIENT, 1000, 22), Direction::ToClient));
+    assert!(!filter.allows(&packet(6, OTHER, CLIENT, 22, 1000), Direction::ToClient));
+}
+
+#[test]
+fn fragments_and_other_packets() {
+    let filter = filter(&["allow tcp port 22", "allow icmp"]);
+    let mut fragment = packet(6, CLIENT, HOST, 1000, 22);
+    // fragment offset not null: no ports
+    fragment[7] = 1;
+    assert!(!filter.allows(&fragment, Direction::FromClient));
+    assert!(filter.allows(&packet(1, CLIENT, HOST, 0, 0), Direction::FromClient));
+    assert!(!filter.allows(&[0x60; 40], Direction::FromClient));
+}
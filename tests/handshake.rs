@@ -0,0 +1,610 @@
+// Handshake state machines driven by scripted peers, no socket
+// or interface is needed
+
+use rust_tcp_vpn::acl::ClientDb;
+use rust_tcp_vpn::codec::{self, Extension, HandshakeMsg};
+use rust_tcp_vpn::dns::DnsConfig;
+use rust_tcp_vpn::handshake::{
+    self, ClientSettings, ClientStart, Failure, Refusal, RejectReason, ServerSettings, ServerStart,
+};
+use rust_tcp_vpn::session::{Conflict, NoSessions, Parked, Resume, Session, Sessions, Ticket};
+use rust_tcp_vpn::totp;
+
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// extension kinds, see handshake
+const EXT_IDENTITY: u16 = 3;
+const EXT_TICKET: u16 = 4;
+const EXT_RESUME: u16 = 5;
+const EXT_RESUMED: u16 = 6;
+const EXT_TOTP_CHALLENGE: u16 = 11;
+
+const TICKET: Ticket = [7; 16];
+
+fn server_settings(clients: Option<ClientDb>) -> ServerSettings {
+    ServerSettings {
+        ifaddr: IpAddr::from([10, 0, 0, 1]),
+        netmask: 24,
+        dns: RwLock::new(DnsConfig {
+            nameservers: vec![IpAddr::from([10, 0, 0, 53])],
+            search: vec!["vpn.example".to_string()],
+        }),
+        clients,
+        verifier: None,
+        credentials: None,
+    }
+}
+
+fn client_settings(ifaddr: [u8; 4], netmask: u8, identity: Option<&str>) -> ClientSettings {
+    ClientSettings {
+        ifaddr: IpAddr::from(ifaddr),
+        netmask,
+        identity: identity.map(|s| s.to_string()),
+        credentials: None,
+        verifier: None,
+        metadata: None,
+    }
+}
+
+// client database in a fresh temporary directory
+fn client_db(name: &str, files: &[(&str, &str)]) -> ClientDb {
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("rust-tcp-vpn-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (identity, content) in files {
+        std::fs::write(dir.join(identity), content).unwrap();
+    }
+    ClientDb::new(dir)
+}
+
+fn client_hello(settings: &ClientSettings, resume: Option<&Resume>) -> HandshakeMsg {
+    ClientStart::new(settings, resume).send_hello().unwrap().1
+}
+
+fn server_hello(ifaddr: [u8; 4], extensions: Vec<Extension>) -> HandshakeMsg {
+    HandshakeMsg::ServerHello {
+        ifaddr: Ipv4Addr::from(ifaddr),
+        extensions,
+    }
+}
+
+fn confirm() -> HandshakeMsg {
+    HandshakeMsg::ClientConfirm {
+        status: 0,
+        extensions: vec![],
+    }
+}
+
+fn expect_reject<T>(ans: Result<T, Failure>, expected: RejectReason) {
+    match ans {
+        Err(Failure::Reject(reason, _)) if reason == expected => {}
+        Err(failure) => panic!("expected reject {:?}, got {:?}", expected, failure),
+        Ok(_) => panic!("expected reject {:?}, got success", expected),
+    }
+}
+
+fn server_reject(settings: &ServerSettings, hello: HandshakeMsg, expected: RejectReason) {
+    expect_reject(
+        ServerStart::new(settings, None, &NoSessions).receive_hello(hello),
+        expected,
+    );
+}
+
+fn client_reject(hello: HandshakeMsg, expected: RejectReason) {
+    let settings = client_settings([10, 0, 0, 2], 24, None);
+    let (state, _) = ClientStart::new(&settings, None).send_hello().unwrap();
+    expect_reject(state.receive_hello(hello), expected);
+}
+
+#[test]
+fn steps_succeed() {
+    let server = server_settings(None);
+    let client = client_settings([10, 0, 0, 2], 24, Some("alice"));
+    let (client_state, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+    let (server_state, hello) = ServerStart::new(&server, Some(TICKET), &NoSessions)
+        .receive_hello(hello)
+        .unwrap();
+    let client_state = client_state.receive_hello(hello).unwrap();
+    assert!(!client_state.totp_required());
+    let (server_info, confirm) = client_state.confirm(None).unwrap();
+    let client_info = server_state.receive_confirm(confirm).unwrap();
+    assert_eq!(server_info.ifaddr, Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!(server_info.dns, *server.dns.read().unwrap());
+    assert_eq!(server_info.ticket, Some(TICKET));
+    assert_eq!(server_info.resumed, None);
+    assert_eq!(client_info.ifaddr, Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(client_info.identity.as_deref(), Some("alice"));
+    assert_eq!(client_info.resumed, None);
+}
+
+#[test]
+fn server_rejects_unexpected_packet() {
+    server_reject(
+        &server_settings(None),
+        confirm(),
+        RejectReason::UnexpectedPacket,
+    );
+}
+
+#[test]
+fn server_rejects_netmask_mismatch() {
+    let client = client_settings([10, 0, 0, 2], 16, None);
+    server_reject(
+        &server_settings(None),
+        client_hello(&client, None),
+        RejectReason::NetmaskMismatch,
+    );
+}
+
+#[test]
+fn server_rejects_bad_address() {
+    for ifaddr in [[10, 0, 0, 1], [10, 0, 1, 2]] {
+        let client = client_settings(ifaddr, 24, None);
+        server_reject(
+            &server_settings(None),
+            client_hello(&client, None),
+            RejectReason::BadAddress,
+        );
+    }
+}
+
+// a single client "alice" connected as 10.0.0.2, duplicate
+// sessions are rejected
+struct Connected;
+
+impl Sessions for Connected {
+    fn conflict(&self, ifaddr: Ipv4Addr, identity: Option<&str>) -> Option<Conflict> {
+        if ifaddr == Ipv4Addr::new(10, 0, 0, 2) {
+            Some(Conflict::Address)
+        } else if identity == Some("alice") {
+            Some(Conflict::Identity)
+        } else {
+            None
+        }
+    }
+
+    fn parked(&self, _: &Ticket, _: Ipv4Addr) -> Option<u64> {
+        None
+    }
+}
+
+#[test]
+fn server_rejects_duplicate_session() {
+    let server = server_settings(None);
+    for (ifaddr, identity, expected) in [
+        ([10, 0, 0, 2], None, RejectReason::BadAddress),
+        ([10, 0, 0, 3], Some("alice"), RejectReason::DuplicateSession),
+    ] {
+        let client = client_settings(ifaddr, 24, identity);
+        let hello = client_hello(&client, None);
+        match ServerStart::new(&server, None, &Connected).receive_hello(hello) {
+            Err(Failure::Conflict(reason, _)) if reason == expected => {}
+            Err(failure) => panic!("expected conflict {:?}, got {:?}", expected, failure),
+            Ok(_) => panic!("expected conflict {:?}, got success", expected),
+        }
+    }
+    let client = client_settings([10, 0, 0, 3], 24, Some("bob"));
+    assert!(ServerStart::new(&server, None, &Connected)
+        .receive_hello(client_hello(&client, None))
+        .is_ok());
+}
+
+#[test]
+fn server_rejects_malformed_extensions() {
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    for (kind, value) in [(EXT_IDENTITY, vec![0xff]), (EXT_RESUME, vec![0; 3])] {
+        let mut hello = client_hello(&client, None);
+        if let HandshakeMsg::ClientHello { extensions, .. } = &mut hello {
+            extensions.push(Extension { kind, value });
+        }
+        server_reject(&server_settings(None), hello, RejectReason::MalformedPacket);
+    }
+}
+
+#[test]
+fn server_reports_peer_reject() {
+    let hello = HandshakeMsg::Reject {
+        reason: RejectReason::AccessDenied as u32,
+        text: "bye".to_string(),
+    };
+    let server = server_settings(None);
+    match ServerStart::new(&server, None, &NoSessions).receive_hello(hello) {
+        Err(Failure::Rejected(RejectReason::AccessDenied, text)) => assert_eq!(text, "bye"),
+        _ => panic!("expected peer reject"),
+    }
+}
+
+#[test]
+fn server_checks_client_database() {
+    let db = client_db(
+        "db",
+        &[
+            ("alice", "ifaddr 10.0.0.2\n"),
+            ("bob", "ifaddr 10.0.0.3\nenabled false\n"),
+        ],
+    );
+    let server = server_settings(Some(db));
+    let cases = [
+        (None, [10, 0, 0, 2], RejectReason::UnknownIdentity),
+        (Some("carol"), [10, 0, 0, 2], RejectReason::UnknownIdentity),
+        (
+            Some("../alice"),
+            [10, 0, 0, 2],
+            RejectReason::UnknownIdentity,
+        ),
+        (Some("bob"), [10, 0, 0, 3], RejectReason::AccessDenied),
+        (Some("alice"), [10, 0, 0, 4], RejectReason::BadAddress),
+    ];
+    for (identity, ifaddr, expected) in cases {
+        let client = client_settings(ifaddr, 24, identity);
+        server_reject(&server, client_hello(&client, None), expected);
+    }
+    let client = client_settings([10, 0, 0, 2], 24, Some("alice"));
+    assert!(ServerStart::new(&server, None, &NoSessions)
+        .receive_hello(client_hello(&client, None))
+        .is_ok());
+}
+
+#[test]
+fn server_checks_confirm() {
+    let server = server_settings(None);
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let start = || {
+        ServerStart::new(&server, None, &NoSessions)
+            .receive_hello(client_hello(&client, None))
+            .unwrap()
+            .0
+    };
+    let status = HandshakeMsg::ClientConfirm {
+        status: 1,
+        extensions: vec![],
+    };
+    assert!(matches!(
+        start().receive_confirm(status),
+        Err(Failure::Aborted(_))
+    ));
+    let reject = HandshakeMsg::Reject {
+        reason: RejectReason::BadAddress as u32,
+        text: String::new(),
+    };
+    assert!(matches!(
+        start().receive_confirm(reject),
+        Err(Failure::Rejected(RejectReason::BadAddress, _))
+    ));
+    expect_reject(
+        start().receive_confirm(client_hello(&client, None)),
+        RejectReason::UnexpectedPacket,
+    );
+}
+
+#[test]
+fn server_requires_totp() {
+    let db = client_db(
+        "totp",
+        &[("alice", "ifaddr 10.0.0.2\ntotp JBSWY3DPEHPK3PXP\n")],
+    );
+    let server = server_settings(Some(db));
+    let client = client_settings([10, 0, 0, 2], 24, Some("alice"));
+    let (client_state, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+    let (server_state, hello) = ServerStart::new(&server, None, &NoSessions)
+        .receive_hello(hello)
+        .unwrap();
+    let client_state = client_state.receive_hello(hello).unwrap();
+    assert!(client_state.totp_required());
+    // missing code
+    let state = ServerStart::new(&server, None, &NoSessions)
+        .receive_hello(client_hello(&client, None))
+        .unwrap()
+        .0;
+    expect_reject(state.receive_confirm(confirm()), RejectReason::AuthFailed);
+    // wrong code: a valid one is 6 digits
+    let (_, confirm) = client_state.confirm(Some(1_000_000)).unwrap();
+    expect_reject(
+        server_state.receive_confirm(confirm),
+        RejectReason::AuthFailed,
+    );
+    // current code
+    let secret = totp::decode_secret("JBSWY3DPEHPK3PXP").unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let code = totp::code(&secret, now.as_secs());
+    let (client_state, hello) = ClientStart::new(&client, None).send_hello().unwrap();
+    let (server_state, hello) = ServerStart::new(&server, None, &NoSessions)
+        .receive_hello(hello)
+        .unwrap();
+    let client_state = client_state.receive_hello(hello).unwrap();
+    let (_, confirm) = client_state.confirm(Some(code)).unwrap();
+    let info = server_state.receive_confirm(confirm).unwrap();
+    assert_eq!(info.identity.as_deref(), Some("alice"));
+}
+
+#[test]
+fn server_resumes_parked_session() {
+    let server = server_settings(None);
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let mut session = Session::new(true);
+    session.on_receive(5);
+    let parked = Parked {
+        ticket: TICKET,
+        ifaddr: Ipv4Addr::new(10, 0, 0, 2),
+        session,
+        expires: Instant::now(),
+    };
+    let resume = |ticket| Resume {
+        ticket,
+        received: 3,
+    };
+    // matching ticket
+    let (state, hello) = ServerStart::new(&server, Some([1; 16]), &parked)
+        .receive_hello(client_hello(&client, Some(&resume(TICKET))))
+        .unwrap();
+    let (client_state, _) = ClientStart::new(&client, None).send_hello().unwrap();
+    let client_state = client_state.receive_hello(hello).unwrap();
+    assert_eq!(client_state.info().resumed, Some(5));
+    assert_eq!(client_state.info().ticket, Some(TICKET));
+    let info = state.receive_confirm(confirm()).unwrap();
+    assert_eq!(info.resumed, Some(3));
+    // other ticket
+    let (state, _) = ServerStart::new(&server, Some([1; 16]), &parked)
+        .receive_hello(client_hello(&client, Some(&resume([2; 16]))))
+        .unwrap();
+    assert_eq!(state.receive_confirm(confirm()).unwrap().resumed, None);
+}
+
+#[test]
+fn client_rejects_unexpected_packet() {
+    client_reject(confirm(), RejectReason::UnexpectedPacket);
+}
+
+#[test]
+fn client_rejects_bad_address() {
+    client_reject(
+        server_hello([10, 0, 0, 2], vec![]),
+        RejectReason::BadAddress,
+    );
+    client_reject(
+        server_hello([10, 0, 1, 1], vec![]),
+        RejectReason::BadAddress,
+    );
+}
+
+#[test]
+fn client_rejects_malformed_extensions() {
+    let cases = [
+        (1, vec![1, 2, 3]),
+        (2, vec![0xff]),
+        (EXT_TICKET, vec![0; 3]),
+        (EXT_RESUMED, vec![0; 3]),
+    ];
+    for (kind, value) in cases {
+        client_reject(
+            server_hello([10, 0, 0, 1], vec![Extension { kind, value }]),
+            RejectReason::MalformedPacket,
+        );
+    }
+}
+
+#[test]
+fn client_reports_peer_reject() {
+    let settings = client_settings([10, 0, 0, 2], 24, None);
+    let (state, _) = ClientStart::new(&settings, None).send_hello().unwrap();
+    let hello = HandshakeMsg::Reject {
+        reason: RejectReason::NetmaskMismatch as u32,
+        text: String::new(),
+    };
+    assert!(matches!(
+        state.receive_hello(hello),
+        Err(Failure::Rejected(RejectReason::NetmaskMismatch, _))
+    ));
+}
+
+#[test]
+fn client_needs_totp_code() {
+    let settings = client_settings([10, 0, 0, 2], 24, None);
+    let (state, _) = ClientStart::new(&settings, None).send_hello().unwrap();
+    let ext = Extension {
+        kind: EXT_TOTP_CHALLENGE,
+        value: vec![],
+    };
+    let state = state
+        .receive_hello(server_hello([10, 0, 0, 1], vec![ext]))
+        .unwrap();
+    assert!(matches!(state.confirm(None), Err(Failure::Error(_))));
+}
+
+// Peer replaying recorded bytes
+struct Scripted {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Scripted {
+    fn new(input: &[u8]) -> Scripted {
+        Scripted {
+            input: Cursor::new(input.to_vec()),
+            output: vec![],
+        }
+    }
+
+    fn from_msgs(msgs: &[HandshakeMsg]) -> Scripted {
+        let mut input = vec![];
+        for msg in msgs {
+            codec::encode_handshake(msg, &mut input).unwrap();
+        }
+        Scripted::new(&input)
+    }
+
+    // messages sent to the peer
+    fn sent(&self) -> Vec<HandshakeMsg> {
+        let mut msgs = vec![];
+        let mut rest = &self.output[..];
+        while !rest.is_empty() {
+            let (msg, len) = codec::decode_handshake(rest).unwrap();
+            msgs.push(msg);
+            rest = &rest[len..];
+        }
+        msgs
+    }
+}
+
+impl Read for Scripted {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Scripted {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn sent_reject(peer: &Scripted) -> RejectReason {
+    match peer.sent().last() {
+        Some(HandshakeMsg::Reject { reason, .. }) => RejectReason::from_code(*reason),
+        msg => panic!("expected reject, got {:?}", msg),
+    }
+}
+
+#[test]
+fn driver_rejects_bad_magic() {
+    let mut peer = Scripted::new(b"GET / HTTP/1.1\r\n\r\n");
+    let ans =
+        handshake::handler_server_handshake(&mut peer, &server_settings(None), None, &NoSessions);
+    assert!(matches!(ans, Ok(Err(Refusal::Failed))));
+    assert_eq!(sent_reject(&peer), RejectReason::BadMagic);
+}
+
+#[test]
+fn driver_rejects_malformed_packet() {
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let mut input = vec![];
+    codec::encode_handshake(&client_hello(&client, None), &mut input).unwrap();
+    // extension longer than its block
+    let len = input.len();
+    input[len - 4..].copy_from_slice(&4_u32.to_be_bytes());
+    input.extend_from_slice(&[0, 1, 0, 9, 0, 0, 0, 0]);
+    let mut peer = Scripted::new(&input);
+    let ans =
+        handshake::handler_server_handshake(&mut peer, &server_settings(None), None, &NoSessions);
+    assert!(matches!(ans, Ok(Err(Refusal::Failed))));
+    assert_eq!(sent_reject(&peer), RejectReason::MalformedPacket);
+}
+
+#[test]
+fn driver_does_not_count_conflicts_as_failures() {
+    let settings = server_settings(None);
+    for ifaddr in [[10, 0, 0, 2], [10, 0, 0, 3]] {
+        let client = client_settings(ifaddr, 24, Some("alice"));
+        let mut input = vec![];
+        codec::encode_handshake(&client_hello(&client, None), &mut input).unwrap();
+        let mut peer = Scripted::new(&input);
+        let ans = handshake::handler_server_handshake(&mut peer, &settings, None, &Connected);
+        assert!(matches!(ans, Ok(Err(Refusal::Declined))));
+    }
+    // netmask mismatch is a misconfiguration
+    let client = client_settings([10, 0, 0, 4], 16, None);
+    let mut input = vec![];
+    codec::encode_handshake(&client_hello(&client, None), &mut input).unwrap();
+    let mut peer = Scripted::new(&input);
+    let ans = handshake::handler_server_handshake(&mut peer, &settings, None, &Connected);
+    assert!(matches!(ans, Ok(Err(Refusal::Declined))));
+    assert_eq!(sent_reject(&peer), RejectReason::NetmaskMismatch);
+}
+
+#[test]
+fn driver_reports_connection_closed() {
+    let mut peer = Scripted::new(&[0x12, 0x34]);
+    let ans =
+        handshake::handler_server_handshake(&mut peer, &server_settings(None), None, &NoSessions);
+    let err = ans.err().expect("I/O error expected");
+    assert!(err.downcast_ref::<std::io::Error>().is_some());
+    assert!(peer.sent().is_empty());
+}
+
+#[test]
+fn driver_reports_server_reject() {
+    let reject = HandshakeMsg::Reject {
+        reason: RejectReason::AccessDenied as u32,
+        text: "client disabled".to_string(),
+    };
+    let mut peer = Scripted::from_msgs(&[reject]);
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let err = handshake::handler_client_handshake(&mut peer, &client, None)
+        .err()
+        .expect("server reject expected");
+    assert_eq!(
+        err.to_string(),
+        "server rejected: access denied (client disabled)"
+    );
+    assert!(matches!(
+        peer.sent()[..],
+        [HandshakeMsg::ClientHello { .. }]
+    ));
+}
+
+#[test]
+fn driver_never_prompts_when_resuming() {
+    // session expired on the server, which asks for a code
+    let ext = Extension {
+        kind: EXT_TOTP_CHALLENGE,
+        value: vec![],
+    };
+    let mut peer = Scripted::from_msgs(&[server_hello([10, 0, 0, 1], vec![ext])]);
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let resume = Resume {
+        ticket: TICKET,
+        received: 0,
+    };
+    let err = handshake::handler_client_handshake(&mut peer, &client, Some(&resume))
+        .err()
+        .expect("error expected");
+    assert!(err.to_string().contains("one-time password required"));
+}
+
+#[test]
+fn driver_leaves_flow_data_unread() {
+    // client confirm is immediately followed by a data frame
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let mut input = vec![];
+    codec::encode_handshake(&client_hello(&client, None), &mut input).unwrap();
+    codec::encode_handshake(&confirm(), &mut input).unwrap();
+    let frame = codec::Frame::Data {
+        counter: 1,
+        packet: &[1, 2, 3],
+    };
+    codec::encode_frame(&frame, &mut input).unwrap();
+    let mut peer = Scripted::new(&input);
+    let info =
+        handshake::handler_server_handshake(&mut peer, &server_settings(None), None, &NoSessions)
+            .unwrap();
+    assert!(info.is_ok());
+    let mut buf = vec![];
+    assert_eq!(codec::read_frame(&mut peer, &mut buf).unwrap(), frame);
+}
+
+#[test]
+fn drivers_complete_handshake() {
+    let (mut server_end, mut client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || {
+        let settings = server_settings(None);
+        handshake::handler_server_handshake(&mut server_end, &settings, Some(TICKET), &NoSessions)
+            .unwrap()
+            .ok()
+            .map(|info| info.ifaddr)
+    });
+    let client = client_settings([10, 0, 0, 2], 24, None);
+    let info = handshake::handler_client_handshake(&mut client_end, &client, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(info.ticket, Some(TICKET));
+    assert_eq!(server.join().unwrap(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+}
@@ -0,0 +1,143 @@
+// Limits of incoming connections: sessions and handshakes in progress,
+// connection rate of each source
+
+use rust_tcp_vpn::ban::BanPolicy;
+use rust_tcp_vpn::limits::{Gate, Limits};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+fn gate(limits: Limits) -> Arc<Gate> {
+    Arc::new(Gate::new(limits))
+}
+
+#[test]
+fn sessions_released_when_dropped() {
+    let gate = gate(Limits {
+        max_sessions: Some(2),
+        ..Limits::default()
+    });
+    let mut first = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    let second = Gate::admit(&gate, Some(OTHER)).unwrap();
+    // a session holds its slot after the handshake
+    first.handshake_done();
+    assert_eq!(
+        Gate::admit(&gate, None).err().unwrap(),
+        "limit of 2 sessions reached"
+    );
+    drop(second);
+    let third = Gate::admit(&gate, None).unwrap();
+    drop(first);
+    drop(third);
+    assert!(Gate::admit(&gate, None).is_ok());
+}
+
+#[test]
+fn handshakes_limited() {
+    let gate = gate(Limits {
+        max_handshakes: Some(2),
+        ..Limits::default()
+    });
+    let mut first = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    let _second = Gate::admit(&gate, Some(OTHER)).unwrap();
+    assert_eq!(
+        Gate::admit(&gate, None).err().unwrap(),
+        "limit of 2 handshakes in progress reached"
+    );
+    // released once, whatever the outcome
+    first.handshake_done();
+    first.handshake_failed();
+    let _third = Gate::admit(&gate, None).unwrap();
+    assert!(Gate::admit(&gate, None).is_err());
+}
+
+#[test]
+fn handshakes_limited_per_source() {
+    let gate = gate(Limits {
+        max_handshakes: Some(10),
+        max_source_handshakes: Some(2),
+        ..Limits::default()
+    });
+    let mut first = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    let second = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    assert_eq!(
+        Gate::admit(&gate, Some(SOURCE)).err().unwrap(),
+        "limit of 2 handshakes in progress from the source reached"
+    );
+    // other sources and Unix sockets are not affected
+    let _other = Gate::admit(&gate, Some(OTHER)).unwrap();
+    let _local = Gate::admit(&gate, None).unwrap();
+    let _local = Gate::admit(&gate, None).unwrap();
+    first.handshake_done();
+    let _third = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    assert!(Gate::admit(&gate, Some(SOURCE)).is_err());
+    drop(second);
+    assert!(Gate::admit(&gate, Some(SOURCE)).is_ok());
+}
+
+#[test]
+fn connection_rate_limited_per_source() {
+    let gate = gate(Limits {
+        rate: Some(2),
+        ..Limits::default()
+    });
+    assert!(Gate::admit(&gate, Some(SOURCE)).is_ok());
+    assert!(Gate::admit(&gate, Some(SOURCE)).is_ok());
+    // closed connections still count
+    assert_eq!(
+        Gate::admit(&gate, Some(SOURCE)).err().unwrap(),
+        "rate limit of 2 connections per minute exceeded"
+    );
+    assert!(Gate::admit(&gate, Some(OTHER)).is_ok());
+    for _ in 0..5 {
+        assert!(Gate::admit(&gate, None).is_ok());
+    }
+}
+
+#[test]
+fn refused_connections_hold_no_slot() {
+    let gate = gate(Limits {
+        max_sessions: Some(1),
+        max_handshakes: Some(1),
+        ..Limits::default()
+    });
+    let slot = Gate::admit(&gate, None).unwrap();
+    assert!(Gate::admit(&gate, None).is_err());
+    assert!(Gate::admit(&gate, None).is_err());
+    drop(slot);
+    assert!(Gate::admit(&gate, None).is_ok());
+}
+
+#[test]
+fn new_limits_apply_to_new_connections() {
+    let gate = gate(Limits::default());
+    let _first = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    let _second = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    gate.set_limits(Limits {
+        max_sessions: Some(2),
+        ..Limits::default()
+    });
+    assert!(Gate::admit(&gate, Some(OTHER)).is_err());
+}
+
+#[test]
+fn banned_source_refused() {
+    let gate = gate(Limits {
+        ban: Some(BanPolicy {
+            failures: 1,
+            duration: Duration::from_secs(60),
+        }),
+        ..Limits::default()
+    });
+    let mut slot = Gate::admit(&gate, Some(SOURCE)).unwrap();
+    slot.handshake_failed();
+    assert!(Gate::admit(&gate, Some(SOURCE)).is_err());
+    assert!(Gate::admit(&gate, Some(OTHER)).is_ok());
+    // Unix sockets are never banned
+    let mut slot = Gate::admit(&gate, None).unwrap();
+    slot.handshake_failed();
+    assert!(Gate::admit(&gate, None).is_ok());
+}
@@ -0,0 +1,68 @@
+// Encoding of the client metadata extension, decoded by the server
+// from untrusted input
+
+use proptest::prelude::*;
+use rust_tcp_vpn::metadata::ClientMetadata;
+
+fn field() -> impl Strategy<Value = Option<String>> {
+    // at most 255 bytes once encoded
+    prop::option::of("\\PC{0,60}")
+}
+
+proptest! {
+    #[test]
+    fn roundtrip(hostname in field(), os in field(), version in field(), label in field()) {
+        let metadata = ClientMetadata { hostname, os, version, label };
+        prop_assert_eq!(ClientMetadata::decode(&metadata.encode()), Some(metadata));
+    }
+
+    #[test]
+    fn decode_never_panics(value in prop::collection::vec(any::<u8>(), 0..300)) {
+        let _ = ClientMetadata::decode(&value);
+    }
+}
+
+#[test]
+fn unknown_version_ignored() {
+    let mut value = ClientMetadata::collect(None).encode();
+    value[0] = 2;
+    assert_eq!(ClientMetadata::decode(&value), None);
+    assert_eq!(ClientMetadata::decode(&[]), None);
+}
+
+#[test]
+fn unknown_fields_skipped() {
+    // version, unknown field 9, hostname
+    let value = [1, 9, 3, b'a', b'b', b'c', 1, 2, b'h', b'1'];
+    let metadata = ClientMetadata::decode(&value).unwrap();
+    assert_eq!(metadata.hostname.as_deref(), Some("h1"));
+    assert_eq!(metadata.os, None);
+}
+
+#[test]
+fn truncated_content_rejected() {
+    // field header cut after its kind
+    assert_eq!(ClientMetadata::decode(&[1, 1]), None);
+    // value shorter than its length
+    assert_eq!(ClientMetadata::decode(&[1, 1, 3, b'h', b'1']), None);
+    // not UTF-8
+    assert_eq!(ClientMetadata::decode(&[1, 1, 1, 0xff]), None);
+    // no field at all
+    assert_eq!(
+        ClientMetadata::decode(&[1]),
+        Some(ClientMetadata::default())
+    );
+}
+
+#[test]
+fn long_values_truncated_on_char_boundary() {
+    // 2 bytes per char: 255 bytes would split the last one
+    let metadata = ClientMetadata {
+        label: Some("é".repeat(200)),
+        ..ClientMetadata::default()
+    };
+    let value = metadata.encode();
+    assert_eq!(value[2], 254);
+    let decoded = ClientMetadata::decode(&value).unwrap();
+    assert_eq!(decoded.label, Some("é".repeat(127)));
+}
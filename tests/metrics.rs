@@ -0,0 +1,46 @@
+// Prometheus text format of the counters
+
+use rust_tcp_vpn::codec::Frame;
+use rust_tcp_vpn::metrics::{self, Gauges};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+// counters are global: a single test updates them
+#[test]
+fn render_counters_and_gauges() {
+    metrics::packet_received(100);
+    metrics::packet_received(50);
+    metrics::packet_sent(40);
+    metrics::frame_received(&Frame::Ack { counter: 1 });
+    metrics::frame_sent(&Frame::Exit { reason: 0 });
+    metrics::handshake_done();
+    metrics::handshake_failed("bad_magic");
+    metrics::handshake_failed("bad_magic");
+    metrics::tun_write_failed();
+    let text = metrics::render(&Gauges {
+        sessions: 2,
+        parked: 1,
+        rtt: vec![(Ipv4Addr::new(10, 0, 0, 2), Duration::from_millis(15))],
+    });
+    let lines: Vec<&str> = text.lines().collect();
+    for line in [
+        "vpn_packets_total{direction=\"received\"} 2",
+        "vpn_packets_total{direction=\"sent\"} 1",
+        "vpn_bytes_total{direction=\"received\"} 150",
+        "vpn_frames_total{direction=\"received\",type=\"ack\"} 1",
+        "vpn_frames_total{direction=\"sent\",type=\"exit\"} 1",
+        "vpn_frames_total{direction=\"sent\",type=\"data\"} 0",
+        "vpn_handshakes_total 1",
+        "vpn_handshake_failures_total{reason=\"bad_magic\"} 2",
+        "vpn_tun_write_errors_total 1",
+        "vpn_sessions 2",
+        "vpn_parked_sessions 1",
+        "vpn_rtt_seconds{ifaddr=\"10.0.0.2\"} 0.015",
+        "# TYPE vpn_sessions gauge",
+    ] {
+        assert!(lines.contains(&line), "missing {}", line);
+    }
+    // each metric is described once
+    let types = lines.iter().filter(|l| l.starts_with("# TYPE")).count();
+    assert_eq!(types, 9);
+}
@@ -0,0 +1,33 @@
+// Rate limit of the traffic sent by clients
+
+use rust_tcp_vpn::acl::ClientDb;
+use rust_tcp_vpn::codec;
+use rust_tcp_vpn::ratelimit::RateLimiter;
+
+#[test]
+fn burst_fits_largest_packet() {
+    // less than a packet per second
+    let mut limiter = RateLimiter::new(1000);
+    assert!(limiter.allow(1400));
+    assert!(limiter.allow(1400));
+    assert!(!limiter.allow(1400));
+    let mut limiter = RateLimiter::new(10);
+    assert!(limiter.allow(codec::MAX_PACKET_LEN as u64));
+    // one second worth of tokens above the largest packet
+    let mut limiter = RateLimiter::new(100_000);
+    assert!(limiter.allow(100_000));
+    assert!(!limiter.allow(100));
+}
+
+#[test]
+fn zero_rate_rejected() {
+    let dir = std::env::temp_dir().join(format!("rust-tcp-vpn-{}-rate", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("alice"), "ifaddr 10.0.0.2\nrate 0\n").unwrap();
+    std::fs::write(dir.join("bob"), "ifaddr 10.0.0.3\nrate 1\n").unwrap();
+    let db = ClientDb::new(dir.clone());
+    assert!(db.lookup("alice").is_err());
+    assert_eq!(db.lookup("bob").unwrap().unwrap().rate, Some(1));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
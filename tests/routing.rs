@@ -0,0 +1,367 @@
+// Routing table driven without a virtual interface: owner of each
+// address, client-to-client policies and duplicate sessions
+
+use rust_tcp_vpn::acl::{ClientConfig, SharedConfig};
+use rust_tcp_vpn::codec;
+use rust_tcp_vpn::filter::Filter;
+use rust_tcp_vpn::flows::Outgoing;
+use rust_tcp_vpn::routing::{
+    ClientToClient, DuplicateSession, HairpinCounters, Options, Route, Table,
+};
+use rust_tcp_vpn::session::{self, Conflict, Parked, Session, Sessions};
+use rust_tcp_vpn::transport::{Peer, Stream};
+use std::net::Ipv4Addr;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+fn options(client_to_client: ClientToClient, duplicate: DuplicateSession) -> Options {
+    Options {
+        client_to_client,
+        duplicate,
+        keep_up: false,
+        client_db: true,
+    }
+}
+
+fn addr(s: &str) -> Ipv4Addr {
+    s.parse().unwrap()
+}
+
+fn config(identity: &str, ifaddr: &str, subnets: &[&str], peers: &[&str]) -> ClientConfig {
+    ClientConfig {
+        identity: identity.to_string(),
+        ifaddr: addr(ifaddr),
+        subnets: subnets.iter().map(|net| net.parse().unwrap()).collect(),
+        peers: peers.iter().map(|net| net.parse().unwrap()).collect(),
+        rate: None,
+        enabled: true,
+        totp: None,
+        filter: Filter::default(),
+        quota_day: None,
+        quota_month: None,
+    }
+}
+
+// connect a client, return the id of its connection and its queue
+fn connect(table: &mut Table, config: ClientConfig) -> (Option<u64>, Receiver<Outgoing>) {
+    let (tx, rx) = mpsc::sync_channel(16);
+    let (stream, _) = UnixStream::pair().unwrap();
+    let ifaddr = config.ifaddr;
+    let identity = Some(config.identity.clone());
+    let route = Route::new(
+        identity,
+        Stream::Unix(stream),
+        Peer::Unix,
+        Some(SharedConfig::new(config)),
+        tx,
+    );
+    (table.connect(ifaddr, route), rx)
+}
+
+// IPv4 header with the given source and destination
+fn packet(src: &str, dst: &str) -> Vec<u8> {
+    let mut pkt = vec![0; 20];
+    pkt[0] = 0x45;
+    pkt[12..16].copy_from_slice(&addr(src).octets());
+    pkt[16..20].copy_from_slice(&addr(dst).octets());
+    pkt
+}
+
+fn packets(rx: &Receiver<Outgoing>) -> usize {
+    rx.try_iter()
+        .filter(|out| matches!(out, Outgoing::Packet(_)))
+        .count()
+}
+
+fn exit_reason(rx: &Receiver<Outgoing>) -> Option<u32> {
+    rx.try_iter().find_map(|out| match out {
+        Outgoing::Exit(reason) => Some(reason),
+        _ => None,
+    })
+}
+
+#[test]
+fn longest_prefix_owns_address() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    connect(
+        &mut table,
+        config("a", "10.9.0.2", &["192.168.0.0/16"], &[]),
+    );
+    connect(
+        &mut table,
+        config("b", "10.9.0.3", &["192.168.1.0/24"], &[]),
+    );
+    assert_eq!(table.owner(&addr("10.9.0.2")), Some(addr("10.9.0.2")));
+    assert_eq!(table.owner(&addr("192.168.2.1")), Some(addr("10.9.0.2")));
+    assert_eq!(table.owner(&addr("192.168.1.1")), Some(addr("10.9.0.3")));
+    assert_eq!(table.owner(&addr("10.9.0.4")), None);
+    // a tunnel address wins over any subnet
+    connect(&mut table, config("c", "192.168.1.7", &[], &[]));
+    assert_eq!(table.owner(&addr("192.168.1.7")), Some(addr("192.168.1.7")));
+}
+
+#[test]
+fn packets_from_interface_delivered_to_owner() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    let (_, rx) = connect(
+        &mut table,
+        config("a", "10.9.0.2", &["192.168.0.0/16"], &[]),
+    );
+    assert!(table.deliver(&packet("10.0.0.1", "192.168.3.4")));
+    assert!(!table.deliver(&packet("10.0.0.1", "10.9.0.9")));
+    // not IPv4
+    assert!(!table.deliver(&[0x60; 40]));
+    assert_eq!(packets(&rx), 1);
+    assert_eq!(table.dropped().1, 1);
+}
+
+#[test]
+fn hairpin_allowed() {
+    let mut table = Table::new(
+        options(ClientToClient::Allow, DuplicateSession::Replace),
+        None,
+    );
+    let (_, rx_a) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    let (_, rx_b) = connect(
+        &mut table,
+        config("b", "10.9.0.3", &["192.168.1.0/24"], &[]),
+    );
+    assert!(!table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.3")));
+    assert!(!table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "192.168.1.1")));
+    // to the interface
+    assert!(table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "8.8.8.8")));
+    // to itself
+    assert!(table.forward(addr("10.9.0.3"), &packet("10.9.0.3", "192.168.1.1")));
+    assert_eq!(packets(&rx_b), 2);
+    assert_eq!(packets(&rx_a), 0);
+    let expected = HairpinCounters {
+        packets: 2,
+        bytes: 40,
+        denied: 0,
+    };
+    assert_eq!(table.hairpin_of(addr("10.9.0.2")), Some(expected));
+    assert_eq!(
+        table.hairpin_of(addr("10.9.0.3")),
+        Some(HairpinCounters::default())
+    );
+    assert_eq!(table.dropped().0, expected);
+}
+
+#[test]
+fn hairpin_denied() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    let (_, rx_b) = connect(&mut table, config("b", "10.9.0.3", &[], &[]));
+    // dropped, not sent to the interface either
+    assert!(!table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.3")));
+    assert_eq!(packets(&rx_b), 0);
+    let expected = HairpinCounters {
+        packets: 0,
+        bytes: 0,
+        denied: 1,
+    };
+    assert_eq!(table.hairpin_of(addr("10.9.0.2")), Some(expected));
+    assert_eq!(table.dropped().0, expected);
+}
+
+#[test]
+fn hairpin_by_rules() {
+    let mut table = Table::new(
+        options(ClientToClient::Rules, DuplicateSession::Replace),
+        None,
+    );
+    let (_, rx_a) = connect(&mut table, config("a", "10.9.0.2", &[], &["10.9.0.3/32"]));
+    let (_, rx_b) = connect(&mut table, config("b", "10.9.0.3", &[], &[]));
+    let (_, rx_c) = connect(&mut table, config("c", "10.9.0.4", &[], &[]));
+    // listed by either end, in both directions
+    assert!(!table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.3")));
+    assert!(!table.forward(addr("10.9.0.3"), &packet("10.9.0.3", "10.9.0.2")));
+    // listed by neither
+    assert!(!table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.4")));
+    assert!(!table.forward(addr("10.9.0.4"), &packet("10.9.0.4", "10.9.0.3")));
+    assert_eq!(packets(&rx_a), 1);
+    assert_eq!(packets(&rx_b), 1);
+    assert_eq!(packets(&rx_c), 0);
+    let totals = table.dropped().0;
+    assert_eq!((totals.packets, totals.denied), (2, 2));
+    assert_eq!(table.hairpin_of(addr("10.9.0.4")).unwrap().denied, 1);
+}
+
+#[test]
+fn peers_updated_on_reload() {
+    let mut table = Table::new(
+        options(ClientToClient::Rules, DuplicateSession::Replace),
+        None,
+    );
+    connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    let (_, rx_b) = connect(&mut table, config("b", "10.9.0.3", &[], &[]));
+    table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.3"));
+    assert!(table.update(
+        addr("10.9.0.2"),
+        config("a", "10.9.0.2", &[], &["10.9.0.0/24"])
+    ));
+    table.forward(addr("10.9.0.2"), &packet("10.9.0.2", "10.9.0.3"));
+    assert_eq!(packets(&rx_b), 1);
+    assert!(!table.update(addr("10.9.0.9"), config("x", "10.9.0.9", &[], &[])));
+}
+
+#[test]
+fn duplicate_replaced() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    let (first, rx) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_eq!(table.conflict(addr("10.9.0.2"), Some("a")), None);
+    let (second, _) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_ne!(first, second);
+    assert_eq!(exit_reason(&rx), Some(codec::EXIT_DUPLICATE));
+    // the old connection is over, not the new one
+    assert!(!table.disconnect(addr("10.9.0.2"), first.unwrap(), None));
+    assert_eq!(table.clients(), (1, 0));
+    // same identity with another address
+    let (_, rx) = connect(&mut table, config("a", "10.9.0.5", &[], &[]));
+    assert_eq!(table.clients(), (1, 0));
+    assert_eq!(exit_reason(&rx), None);
+    assert_eq!(table.owner(&addr("10.9.0.5")), Some(addr("10.9.0.5")));
+}
+
+#[test]
+fn address_not_taken_by_other_identity() {
+    for duplicate in [
+        DuplicateSession::Replace,
+        DuplicateSession::Reject,
+        DuplicateSession::Allow,
+    ] {
+        let mut table = Table::new(options(ClientToClient::Deny, duplicate), None);
+        let (_, rx) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+        assert_eq!(
+            table.conflict(addr("10.9.0.2"), Some("b")),
+            Some(Conflict::Address)
+        );
+        let (id, _) = connect(&mut table, config("b", "10.9.0.2", &[], &[]));
+        assert_eq!(id, None);
+        assert_eq!(exit_reason(&rx), None);
+    }
+    // any client can replace a session without client database
+    let mut options = options(ClientToClient::Deny, DuplicateSession::Replace);
+    options.client_db = false;
+    let mut table = Table::new(options, None);
+    connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_eq!(table.conflict(addr("10.9.0.2"), Some("b")), None);
+}
+
+#[test]
+fn duplicate_rejected() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Reject),
+        None,
+    );
+    let (_, rx) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_eq!(
+        table.conflict(addr("10.9.0.2"), Some("a")),
+        Some(Conflict::Address)
+    );
+    assert_eq!(
+        table.conflict(addr("10.9.0.5"), Some("a")),
+        Some(Conflict::Identity)
+    );
+    assert_eq!(
+        connect(&mut table, config("a", "10.9.0.5", &[], &[])).0,
+        None
+    );
+    assert_eq!(exit_reason(&rx), None);
+    assert_eq!(table.conflict(addr("10.9.0.5"), Some("b")), None);
+}
+
+#[test]
+fn duplicate_allowed() {
+    let mut table = Table::new(options(ClientToClient::Deny, DuplicateSession::Allow), None);
+    let (_, rx) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_eq!(
+        table.conflict(addr("10.9.0.2"), Some("a")),
+        Some(Conflict::Address)
+    );
+    assert!(connect(&mut table, config("a", "10.9.0.5", &[], &[]))
+        .0
+        .is_some());
+    assert_eq!(exit_reason(&rx), None);
+    assert_eq!(table.clients(), (2, 0));
+}
+
+fn parked(ifaddr: &str, expires: Instant) -> Parked {
+    Parked {
+        ticket: session::new_ticket().unwrap(),
+        ifaddr: addr(ifaddr),
+        session: Session::new(true),
+        expires,
+    }
+}
+
+#[test]
+fn interface_kept_up() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    assert!(!table.interface_needed());
+    let (id, _) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert!(table.interface_needed());
+    // needed while the session is parked
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(10);
+    assert!(table.disconnect(
+        addr("10.9.0.2"),
+        id.unwrap(),
+        Some(parked("10.9.0.2", expires))
+    ));
+    assert!(table.interface_needed());
+    assert_eq!(table.expire(now), Some(expires));
+    assert_eq!(table.expire(expires), None);
+    assert!(!table.interface_needed());
+
+    let mut options = options(ClientToClient::Deny, DuplicateSession::Replace);
+    options.keep_up = true;
+    let mut table = Table::new(options, None);
+    assert!(table.interface_needed());
+    let (id, rx) = connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    table.disconnect(addr("10.9.0.2"), id.unwrap(), None);
+    assert!(table.interface_needed());
+    assert_eq!(exit_reason(&rx), None);
+    // until the server exits and its clients are gone
+    let (id, rx) = connect(&mut table, config("b", "10.9.0.3", &[], &[]));
+    table.close();
+    assert_eq!(exit_reason(&rx), Some(codec::EXIT_NORMAL));
+    assert_eq!(
+        connect(&mut table, config("c", "10.9.0.4", &[], &[])).0,
+        None
+    );
+    assert!(table.interface_needed());
+    table.disconnect(addr("10.9.0.3"), id.unwrap(), None);
+    assert!(!table.interface_needed());
+}
+
+#[test]
+fn parked_session_replaced_by_new_client() {
+    let mut table = Table::new(
+        options(ClientToClient::Deny, DuplicateSession::Replace),
+        None,
+    );
+    let parked = parked("10.9.0.2", Instant::now() + Duration::from_secs(10));
+    let ticket = parked.ticket;
+    table.park(parked);
+    assert_eq!(table.parked(&ticket, addr("10.9.0.2")), Some(0));
+    connect(&mut table, config("a", "10.9.0.2", &[], &[]));
+    assert_eq!(table.parked(&ticket, addr("10.9.0.2")), None);
+    assert_eq!(table.clients(), (1, 0));
+}
@@ -0,0 +1,122 @@
+// Sessions driven directly: duplicates after a resumption, packets
+// kept until acknowledged and replayed when resuming
+
+use rust_tcp_vpn::codec::{self, Frame};
+use rust_tcp_vpn::flows;
+use rust_tcp_vpn::session::{self, Parked, Session, Sessions, Ticket};
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+fn pending(session: &Session) -> Vec<u64> {
+    session.pending().map(|(counter, _)| *counter).collect()
+}
+
+#[test]
+fn duplicates_suppressed() {
+    let mut session = Session::new(true);
+    assert!(session.on_receive(1));
+    assert!(session.on_receive(2));
+    // retransmitted by the peer after resuming
+    assert!(!session.on_receive(1));
+    assert!(!session.on_receive(2));
+    assert!(session.on_receive(3));
+    assert_eq!(session.received, 3);
+}
+
+#[test]
+fn acks_requested_periodically() {
+    let mut session = Session::new(true);
+    for counter in 1..32 {
+        session.on_receive(counter);
+        assert!(!session.ack_needed());
+    }
+    session.on_receive(32);
+    assert!(session.ack_needed());
+    session.on_ack_sent();
+    assert!(!session.ack_needed());
+    // duplicates are not counted
+    session.on_receive(1);
+    assert!(!session.ack_needed());
+    // nothing to acknowledge without resumption
+    let mut session = Session::new(false);
+    for counter in 1..100 {
+        session.on_receive(counter);
+    }
+    assert!(!session.ack_needed());
+}
+
+#[test]
+fn acked_packets_trimmed() {
+    let mut session = Session::new(true);
+    for i in 1..=5 {
+        assert_eq!(session.on_send(&[i; 3]), i as u64);
+    }
+    assert_eq!(pending(&session), [1, 2, 3, 4, 5]);
+    session.on_ack(3);
+    assert_eq!(pending(&session), [4, 5]);
+    // older ack
+    session.on_ack(2);
+    assert_eq!(pending(&session), [4, 5]);
+    session.on_ack(5);
+    assert!(pending(&session).is_empty());
+    // not kept without resumption
+    let mut session = Session::new(false);
+    session.on_send(&[1]);
+    assert!(pending(&session).is_empty());
+}
+
+#[test]
+fn oldest_packets_dropped() {
+    let mut session = Session::new(true);
+    for _ in 0..1100 {
+        session.on_send(&[0]);
+    }
+    let kept = pending(&session);
+    assert_eq!(kept.len(), 1024);
+    assert_eq!(kept[0], 1100 - 1023);
+}
+
+#[test]
+fn unacked_packets_replayed() {
+    let mut session = Session::new(true);
+    for i in 1..=4 {
+        session.on_send(&[i, i]);
+    }
+    // peer received the first two before the connection was lost
+    let mut out = vec![];
+    flows::resume_session(&mut out, &mut session, 2).unwrap();
+    let mut replayed = vec![];
+    let mut pos = 0;
+    while pos < out.len() {
+        let (frame, len) = codec::decode_frame(&out[pos..]).unwrap();
+        match frame {
+            Frame::Data { counter, packet } => replayed.push((counter, packet.to_vec())),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        pos += len;
+    }
+    assert_eq!(replayed, [(3, vec![3, 3]), (4, vec![4, 4])]);
+    // counters continue after the replayed packets
+    assert_eq!(session.on_send(&[5]), 5);
+}
+
+#[test]
+fn parked_session_requires_ticket_and_address() {
+    let ticket: Ticket = session::new_ticket().unwrap();
+    let ifaddr = Ipv4Addr::new(10, 0, 0, 2);
+    let mut parked_session = Session::new(true);
+    parked_session.on_receive(7);
+    let parked = Parked {
+        ticket,
+        ifaddr,
+        session: parked_session,
+        expires: Instant::now(),
+    };
+    assert_eq!(parked.parked(&ticket, ifaddr), Some(7));
+    assert_eq!(parked.parked(&ticket, Ipv4Addr::new(10, 0, 0, 3)), None);
+    let mut other = ticket;
+    other[15] ^= 1;
+    assert_eq!(parked.parked(&other, ifaddr), None);
+    assert!(session::same_ticket(&ticket, &ticket));
+    assert!(!session::same_ticket(&ticket, &other));
+}
@@ -0,0 +1,77 @@
+// Service notifications received by a stand-in for the systemd
+// notification socket
+
+use rust_tcp_vpn::systemd::Notifier;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+fn recv(socket: &UnixDatagram) -> String {
+    let mut buf = [0; 256];
+    let len = socket.recv(&mut buf).unwrap();
+    String::from_utf8(buf[..len].to_vec()).unwrap()
+}
+
+fn stand_in(name: &str) -> (UnixDatagram, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    (socket, path)
+}
+
+#[test]
+fn notifier_sends_state() {
+    let (socket, path) = stand_in("notify-state");
+    let notifier = Notifier::new(path.to_str().unwrap()).unwrap();
+    notifier.ready();
+    notifier.status("2 clients connected\n0 sessions parked");
+    notifier.stopping();
+    assert_eq!(recv(&socket), "READY=1");
+    assert_eq!(
+        recv(&socket),
+        "STATUS=2 clients connected 0 sessions parked"
+    );
+    assert_eq!(recv(&socket), "STOPPING=1");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn notifier_abstract_socket() {
+    use std::os::linux::net::SocketAddrExt;
+
+    let name = format!("notify-abstract-{}", std::process::id());
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+    let socket = UnixDatagram::bind_addr(&addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let notifier = Notifier::new(&format!("@{}", name)).unwrap();
+    notifier.ready();
+    assert_eq!(recv(&socket), "READY=1");
+}
+
+#[test]
+fn notifier_watchdog_when_due() {
+    let (socket, path) = stand_in("notify-watchdog");
+    let notifier = Notifier::new(path.to_str().unwrap())
+        .unwrap()
+        .with_watchdog(Duration::from_millis(50));
+    // not due yet
+    notifier.watchdog();
+    std::thread::sleep(Duration::from_millis(60));
+    notifier.watchdog();
+    notifier.ready();
+    assert_eq!(recv(&socket), "WATCHDOG=1");
+    assert_eq!(recv(&socket), "READY=1");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn disabled_notifier() {
+    let notifier = Notifier::disabled();
+    assert!(notifier.watchdog_deadline().is_none());
+    notifier.ready();
+    notifier.watchdog();
+}
@@ -0,0 +1,70 @@
+// One-time passwords: known answers of the RFCs and verification
+// of the codes typed by users
+
+use rust_tcp_vpn::totp::{self, TotpVerifier};
+
+// secret of the test vectors of both RFCs (SHA-1)
+const SECRET: &[u8] = b"12345678901234567890";
+
+#[test]
+fn rfc4226_hotp_vectors() {
+    // Appendix D
+    let expected = [
+        755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+    for (counter, code) in expected.iter().enumerate() {
+        assert_eq!(totp::hotp(SECRET, counter as u64, 6), *code, "{}", counter);
+    }
+}
+
+#[test]
+fn rfc6238_totp_vectors() {
+    // Appendix B, SHA-1 mode, 8 digits
+    let expected = [
+        (59, 94287082),
+        (1111111109, 7081804),
+        (1111111111, 14050471),
+        (1234567890, 89005924),
+        (2000000000, 69279037),
+        (20000000000, 65353130),
+    ];
+    for (time, code) in expected {
+        assert_eq!(totp::hotp(SECRET, totp::step(time), 8), code, "{}", time);
+    }
+    // 6 digits codes are the last ones of the 8 digits codes
+    assert_eq!(totp::code(SECRET, 59), 287082);
+}
+
+#[test]
+fn valid_code_accepted_with_skew() {
+    let now = 1_700_000_000;
+    let code = totp::code(SECRET, now);
+    // clock of the user one step ahead or behind
+    for time in [now - 30, now, now + 30] {
+        let verifier = TotpVerifier::default();
+        assert!(verifier.verify_at("alice", SECRET, code, time), "{}", time);
+    }
+    for time in [now - 60, now + 60] {
+        let verifier = TotpVerifier::default();
+        assert!(!verifier.verify_at("alice", SECRET, code, time), "{}", time);
+    }
+    assert!(!TotpVerifier::default().verify_at("alice", b"other secret", code, now));
+}
+
+#[test]
+fn code_cannot_be_reused() {
+    let now = 1_700_000_000;
+    let verifier = TotpVerifier::default();
+    let code = totp::code(SECRET, now);
+    assert!(verifier.verify_at("alice", SECRET, code, now));
+    assert!(!verifier.verify_at("alice", SECRET, code, now));
+    assert!(!verifier.verify_at("alice", SECRET, code, now + 10));
+    // steps older than the last one used are refused too
+    let previous = totp::code(SECRET, now - 30);
+    assert!(!verifier.verify_at("alice", SECRET, previous, now));
+    // each identity has its own codes
+    assert!(verifier.verify_at("bob", SECRET, code, now));
+    // next step
+    let next = totp::code(SECRET, now + 30);
+    assert!(verifier.verify_at("alice", SECRET, next, now + 30));
+}
@@ -0,0 +1,60 @@
+// Endpoints given to --listen
+
+use rust_tcp_vpn::transport::Endpoint;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+fn tcp(s: &str) -> Endpoint {
+    Endpoint::Tcp(SocketAddr::from_str(s).unwrap())
+}
+
+#[test]
+fn tcp_endpoints_parsed() {
+    assert_eq!(Endpoint::from_str("0.0.0.0:7000"), Ok(tcp("0.0.0.0:7000")));
+    assert_eq!(Endpoint::from_str("[::]:7000"), Ok(tcp("[::]:7000")));
+    assert_eq!(
+        Endpoint::from_str("[fe80::1%2]:443"),
+        Ok(tcp("[fe80::1%2]:443"))
+    );
+}
+
+#[test]
+fn unix_endpoints_parsed() {
+    assert_eq!(
+        Endpoint::from_str("unix:/run/vpn.sock"),
+        Ok(Endpoint::Unix(PathBuf::from("/run/vpn.sock")))
+    );
+    assert_eq!(
+        Endpoint::from_str("unix:"),
+        Err("unix:: missing path".to_string())
+    );
+}
+
+#[test]
+fn bad_endpoints_rejected() {
+    for s in [
+        // bad port
+        "0.0.0.0:70000",
+        "0.0.0.0:",
+        "0.0.0.0",
+        // unknown scheme
+        "tcp:0.0.0.0:7000",
+        "udp://0.0.0.0:7000",
+        // IPv6 without brackets
+        "::1:7000",
+        "[::1]",
+        "[::1:7000",
+        "",
+    ] {
+        let err = Endpoint::from_str(s).unwrap_err();
+        assert!(err.starts_with(&format!("{}: ", s)), "{}", err);
+    }
+}
+
+#[test]
+fn endpoints_displayed_as_parsed() {
+    for s in ["127.0.0.1:7000", "[::1]:7000", "unix:/run/vpn.sock"] {
+        assert_eq!(Endpoint::from_str(s).unwrap().to_string(), s);
+    }
+}